@@ -1,5 +1,7 @@
 use nextcloud_appinfo::get_appinfo;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::time::{SystemTime, UNIX_EPOCH};
 
 fn main() {
     println!("cargo:rerun-if-changed=appinfo/info.xml");
@@ -8,4 +10,41 @@ fn main() {
     let appinfo = get_appinfo(&appinfo_path).expect("Failed to load appinfo");
     println!("cargo:rustc-env=NOTIFY_PUSH_VERSION={}", appinfo.version());
     println!("cargo:rustc-env=CARGO_PKG_VERSION={}", appinfo.version());
+
+    if Path::new(".git/HEAD").exists() {
+        println!("cargo:rerun-if-changed=.git/HEAD");
+        // HEAD only changes on a branch switch, new commits move the ref it points to
+        if let Ok(head) = std::fs::read_to_string(".git/HEAD") {
+            if let Some(reference) = head.trim().strip_prefix("ref: ") {
+                if Path::new(".git").join(reference).exists() {
+                    println!("cargo:rerun-if-changed=.git/{}", reference);
+                }
+            }
+        }
+        if Path::new(".git/packed-refs").exists() {
+            println!("cargo:rerun-if-changed=.git/packed-refs");
+        }
+    }
+    let commit = Command::new("git")
+        .args(["rev-parse", "--short", "HEAD"])
+        .output()
+        .ok()
+        .filter(|output| output.status.success())
+        .and_then(|output| String::from_utf8(output.stdout).ok())
+        .map(|commit| commit.trim().to_string())
+        .unwrap_or_else(|| "unknown".to_string());
+    println!("cargo:rustc-env=NOTIFY_PUSH_COMMIT={}", commit);
+
+    // respect SOURCE_DATE_EPOCH for reproducible builds
+    println!("cargo:rerun-if-env-changed=SOURCE_DATE_EPOCH");
+    let build_time = std::env::var("SOURCE_DATE_EPOCH")
+        .ok()
+        .and_then(|epoch| epoch.parse().ok())
+        .unwrap_or_else(|| {
+            SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|time| time.as_secs())
+                .unwrap_or_default()
+        });
+    println!("cargo:rustc-env=NOTIFY_PUSH_BUILD_TIME={}", build_time);
 }
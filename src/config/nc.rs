@@ -7,6 +7,7 @@ pub(super) fn parse_config_file(
     path: impl AsRef<Path>,
     glob: bool,
 ) -> Result<PartialConfig, ConfigError> {
+    let path = path.as_ref();
     let config = if glob { parse_glob(path) } else { parse(path) }?;
 
     Ok(PartialConfig {
@@ -14,6 +15,162 @@ pub(super) fn parse_config_file(
         database_prefix: Some(config.database_prefix),
         nextcloud_url: Some(config.nextcloud_url),
         redis: config.redis.into_vec(),
+        instance_id: parse_instance_id(path),
         ..PartialConfig::default()
     })
 }
+
+/// Read the `instanceid` from the main config file, which isn't exposed by the config parser
+fn parse_instance_id(path: &Path) -> Option<String> {
+    let content = std::fs::read_to_string(path).ok()?;
+    content.lines().find_map(|line| {
+        let (key, value) = line.split_once("=>")?;
+        if key.trim().trim_matches(|c| c == '\'' || c == '"') != "instanceid" {
+            return None;
+        }
+        let value = value.trim().trim_end_matches(',').trim();
+        Some(value.trim_matches(|c| c == '\'' || c == '"').to_string())
+    })
+}
+
+#[cfg(test)]
+const TEST_DATABASE: &str = "\
+    'dbtype' => 'mysql',
+    'dbname' => 'nextcloud',
+    'dbhost' => '127.0.0.1',
+    'dbtableprefix' => 'oc_',
+    'dbuser' => 'nextcloud',
+    'dbpassword' => 'secret',";
+
+#[cfg(test)]
+fn parse_test_config(name: &str, database: &str, redis: &str) -> PartialConfig {
+    let path = std::env::temp_dir().join(format!(
+        "notify_push_{}_{}.config.php",
+        name,
+        std::process::id()
+    ));
+    std::fs::write(
+        &path,
+        format!(
+            "<?php\n$CONFIG = [\n'overwrite.cli.url' => 'https://cloud.example.com',\n{}\n{}\n];",
+            database, redis
+        ),
+    )
+    .unwrap();
+    let config = parse_config_file(&path, false).unwrap();
+    std::fs::remove_file(path).ok();
+    config
+}
+
+#[test]
+fn test_redis_tcp() {
+    use redis::ConnectionAddr;
+
+    let config = parse_test_config(
+        "redis_tcp",
+        TEST_DATABASE,
+        "'redis' => ['host' => 'redis.example.com', 'port' => 6380, 'password' => 'pass', 'dbindex' => 2],",
+    );
+    assert_eq!(1, config.redis.len());
+    let redis = &config.redis[0];
+    assert_eq!(
+        ConnectionAddr::Tcp("redis.example.com".into(), 6380),
+        redis.addr
+    );
+    assert_eq!(Some("pass"), redis.redis.password.as_deref());
+    assert_eq!(2, redis.redis.db);
+}
+
+#[test]
+fn test_redis_socket() {
+    use redis::ConnectionAddr;
+
+    let config = parse_test_config(
+        "redis_socket",
+        TEST_DATABASE,
+        "'redis' => ['host' => '/var/run/redis/redis.sock', 'port' => 0],",
+    );
+    assert_eq!(1, config.redis.len());
+    assert_eq!(
+        ConnectionAddr::Unix("/var/run/redis/redis.sock".into()),
+        config.redis[0].addr
+    );
+}
+
+#[test]
+fn test_redis_cluster() {
+    let config = parse_test_config(
+        "redis_cluster",
+        TEST_DATABASE,
+        "'redis.cluster' => ['seeds' => ['redis1:7000', 'redis2:7001'], 'password' => 'pass'],",
+    );
+    let mut addresses = config
+        .redis
+        .iter()
+        .map(|redis| redis.addr.to_string())
+        .collect::<Vec<_>>();
+    addresses.sort();
+    assert_eq!(vec!["redis1:7000", "redis2:7001"], addresses);
+    assert!(config
+        .redis
+        .iter()
+        .all(|redis| redis.redis.password.as_deref() == Some("pass")));
+}
+
+#[test]
+fn test_database_pgsql_socket() {
+    use sqlx::any::AnyKind;
+
+    let config = parse_test_config(
+        "pgsql_socket",
+        "'dbtype' => 'pgsql',
+        'dbname' => 'nextcloud',
+        'dbhost' => 'localhost:/var/run/postgresql',
+        'dbuser' => 'nextcloud',
+        'dbpassword' => 'secret',",
+        "",
+    );
+    let database = config.database.unwrap();
+    assert_eq!(AnyKind::Postgres, database.kind());
+    let options = format!("{:?}", database);
+    assert!(options.contains(r#"socket: Some("/var/run/postgresql")"#));
+    assert!(options.contains("port: 5432"));
+    assert_eq!(
+        Some("nextcloud"),
+        database.as_postgres().unwrap().get_database()
+    );
+}
+
+#[test]
+fn test_database_mysql_tcp() {
+    use sqlx::any::AnyKind;
+
+    let config = parse_test_config(
+        "mysql_tcp",
+        "'dbtype' => 'mysql',
+        'dbname' => 'nextcloud',
+        'dbhost' => 'db.example.com',
+        'dbuser' => 'nextcloud',
+        'dbpassword' => 'secret',",
+        "",
+    );
+    let database = config.database.unwrap();
+    assert_eq!(AnyKind::MySql, database.kind());
+    let options = format!("{:?}", database);
+    assert!(options.contains(r#"host: "db.example.com""#));
+    assert!(options.contains("port: 3306"));
+    assert!(options.contains("socket: None"));
+}
+
+#[test]
+fn test_instance_id() {
+    let config = parse_test_config(
+        "instance_id",
+        TEST_DATABASE,
+        "'instanceid' => 'oc0123456789',",
+    );
+    assert_eq!(Some("oc0123456789"), config.instance_id.as_deref());
+
+    let config = parse_test_config("no_instance_id", TEST_DATABASE, "");
+    assert_eq!(None, config.instance_id);
+}
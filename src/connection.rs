@@ -1,56 +1,349 @@
 use crate::error::{AuthenticationError, WebSocketError};
-use crate::message::{PushMessage, SendQueue};
+use crate::event::EventId;
+use crate::message::{ProtocolVersion, PushMessage, SendQueue};
 use crate::metrics::METRICS;
 use crate::passthru_hasher::PassthruHasher;
+use crate::reconnect::RESUME_PREFIX;
+use crate::replay::SINCE_PREFIX;
+use crate::storage_mapping::is_path_within;
 use crate::Result;
 use crate::{App, UserId};
+use ahash::RandomState;
 use dashmap::mapref::entry::Entry;
 use dashmap::DashMap;
 use futures::{future::select, pin_mut, SinkExt, StreamExt};
 use rand::{Rng, SeedableRng};
+use serde::Serialize;
+use serde_json::json;
+use std::convert::Infallible;
 use std::net::IpAddr;
 use std::num::NonZeroUsize;
-use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
 use std::sync::Arc;
-use std::time::{Duration, Instant};
-use tokio::sync::broadcast;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tokio::sync::broadcast::error::{RecvError, TryRecvError};
+use tokio::sync::{broadcast, mpsc, oneshot};
 use tokio::time::timeout;
+use tokio_stream::wrappers::ReceiverStream;
+use warp::filters::sse;
 use warp::filters::ws::{Message, WebSocket};
 
 const USER_CONNECTION_LIMIT: usize = 64;
 const PING_INTERVAL: Duration = Duration::from_secs(30);
+const MAX_CLIENT_LENGTH: usize = 64;
+/// Maximum size of messages received from clients
+pub const MAX_MESSAGE_SIZE: usize = 64 * 1024;
+pub const DEFAULT_SEND_QUEUE_SIZE: usize = 64;
+const MAX_SUBSCRIPTIONS: usize = 64;
 
-#[derive(Default)]
-pub struct ActiveConnections(DashMap<UserId, broadcast::Sender<PushMessage>, PassthruHasher>);
+/// Default time in seconds a client has to authenticate after connecting
+pub const DEFAULT_AUTH_TIMEOUT: usize = 15;
+/// Upper limit for the authentication timeout, to prevent unauthenticated connections from being held open
+pub const MAX_AUTH_TIMEOUT: usize = 60;
+/// Default maximum length of the username and password messages
+pub const DEFAULT_MAX_AUTH_MESSAGE_LENGTH: usize = 4 * 1024;
+
+pub type ConnectionId = u64;
+
+pub struct ActiveConnections {
+    users: DashMap<UserId, broadcast::Sender<QueuedMessage>, PassthruHasher>,
+    connections: DashMap<ConnectionId, ConnectionEntry, RandomState>,
+    next_id: AtomicU64,
+    count: AtomicUsize,
+    queue_size: usize,
+}
+
+impl Default for ActiveConnections {
+    fn default() -> Self {
+        Self::with_queue_size(DEFAULT_SEND_QUEUE_SIZE)
+    }
+}
+
+/// A message queued for a user
+#[derive(Debug, Clone, PartialEq)]
+pub struct QueuedMessage {
+    pub message: PushMessage,
+    /// Path of the change that triggered the message, if known
+    pub path: Option<Arc<str>>,
+    /// The event that triggered the message
+    pub event: EventId,
+}
+
+impl QueuedMessage {
+    pub fn new(message: PushMessage, event: EventId) -> Self {
+        QueuedMessage {
+            message,
+            path: None,
+            event,
+        }
+    }
+
+    pub fn with_path(mut self, path: Arc<str>) -> Self {
+        self.path = Some(path);
+        self
+    }
+}
+
+impl From<PushMessage> for QueuedMessage {
+    fn from(message: PushMessage) -> Self {
+        QueuedMessage::new(message, EventId::new())
+    }
+}
+
+/// Outcome of queueing a message for a user
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Delivery {
+    /// Number of connections the message was queued for
+    pub connections: usize,
+    /// Whether the queue was full, causing the slowest connection to miss messages
+    pub overflowed: bool,
+}
+
+struct ConnectionEntry {
+    user: UserId,
+    close: Option<oneshot::Sender<()>>,
+    /// Client type and version as reported by the client during the handshake
+    client: Option<String>,
+    /// Paths the client wants to receive updates for, empty to receive all updates
+    subscriptions: Vec<String>,
+    connected: SystemTime,
+    protocol: ProtocolVersion,
+}
+
+/// Details of an active connection, for debugging
+#[derive(Debug, Serialize)]
+pub struct ConnectionInfo {
+    pub id: ConnectionId,
+    /// Unix timestamp of when the connection was opened
+    pub connected: u64,
+    pub protocol: String,
+    pub client: Option<String>,
+    pub subscriptions: Vec<String>,
+}
+
+impl Drop for ConnectionEntry {
+    fn drop(&mut self) {
+        if let Some(client) = &self.client {
+            METRICS.remove_client_connection(client);
+        }
+    }
+}
+
+/// A single connection registered with the active connections
+///
+/// The connection is removed from the active connections when dropped
+pub struct UserConnection<'a> {
+    pub id: ConnectionId,
+    pub messages: broadcast::Receiver<QueuedMessage>,
+    /// Resolves when the connection is closed from outside
+    pub close: oneshot::Receiver<()>,
+    connections: &'a ActiveConnections,
+    user: UserId,
+    started: Instant,
+}
+
+impl Drop for UserConnection<'_> {
+    fn drop(&mut self) {
+        METRICS.connection_duration.observe(self.started.elapsed());
+        self.connections.remove(&self.user, self.id);
+    }
+}
 
 impl ActiveConnections {
-    pub fn add(&self, user: UserId) -> Result<broadcast::Receiver<PushMessage>> {
-        match self.0.entry(user) {
+    /// Create the active connections with the number of messages that can be queued for every user
+    pub fn with_queue_size(queue_size: usize) -> Self {
+        ActiveConnections {
+            users: Default::default(),
+            connections: Default::default(),
+            next_id: Default::default(),
+            count: Default::default(),
+            queue_size: queue_size.max(1),
+        }
+    }
+
+    pub fn add(&self, user: UserId) -> Result<UserConnection<'_>> {
+        let messages = match self.users.entry(user.clone()) {
             Entry::Occupied(entry) => {
                 let sender = entry.get();
                 if sender.receiver_count() > USER_CONNECTION_LIMIT {
-                    Err(AuthenticationError::LimitExceeded.into())
-                } else {
-                    Ok(sender.subscribe())
+                    return Err(AuthenticationError::LimitExceeded.into());
                 }
+                sender.subscribe()
             }
             Entry::Vacant(entry) => {
                 METRICS.add_user();
-                let (tx, rx) = broadcast::channel(4);
+                let (tx, rx) = broadcast::channel(self.queue_size);
                 entry.insert(tx);
-                Ok(rx)
+                rx
+            }
+        };
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let (close_tx, close) = oneshot::channel();
+        self.connections.insert(
+            id,
+            ConnectionEntry {
+                user: user.clone(),
+                close: Some(close_tx),
+                client: None,
+                subscriptions: Vec::new(),
+                connected: SystemTime::now(),
+                protocol: ProtocolVersion::default(),
+            },
+        );
+        self.count.fetch_add(1, Ordering::Relaxed);
+        METRICS.add_connection();
+        Ok(UserConnection {
+            id,
+            messages,
+            close,
+            connections: self,
+            user,
+            started: Instant::now(),
+        })
+    }
+
+    /// Number of open connections
+    pub fn connection_count(&self) -> usize {
+        self.count.load(Ordering::Relaxed)
+    }
+
+    /// Queue a message for all connections of a user without waiting for the connections to send it
+    pub fn send_to_user(&self, user: &UserId, msg: impl Into<QueuedMessage>) -> Delivery {
+        let tx = match self.users.get(user) {
+            Some(tx) => tx,
+            None => return Delivery::default(),
+        };
+        // once the queue is full, sending pushes out the oldest message for the slowest connection,
+        // which will be closed when it notices it missed messages
+        let overflowed = tx.len() >= self.queue_size;
+        match tx.send(msg.into()) {
+            Ok(connections) => {
+                if overflowed {
+                    METRICS.add_failed_delivery();
+                }
+                Delivery {
+                    connections,
+                    overflowed,
+                }
             }
+            // the last connection of a user removes the sender before dropping its receiver,
+            // so there is never a sender without receivers to send to
+            Err(_) => Delivery::default(),
         }
     }
 
-    pub fn send_to_user(&self, user: &UserId, msg: PushMessage) {
-        if let Some(tx) = self.0.get(user) {
-            tx.send(msg).ok();
+    pub fn send_to_all(&self, msg: &str) {
+        let msg = QueuedMessage::from(PushMessage::Custom(msg.to_string(), Box::default()));
+        for tx in self.users.iter() {
+            tx.send(msg.clone()).ok();
         }
     }
 
-    pub fn remove(&self, user: &UserId) {
-        if let Entry::Occupied(e) = self.0.entry(user.clone()) {
+    /// Close a single connection of a user, returns false if no such connection exists
+    pub fn close_connection(&self, user: &UserId, id: ConnectionId) -> bool {
+        match self
+            .connections
+            .remove_if(&id, |_, entry| entry.user == *user)
+        {
+            Some((_, mut entry)) => {
+                log::debug!("Closing connection {} for {}", id, user);
+                if let Some(close) = entry.close.take() {
+                    close.send(()).ok();
+                }
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Close all connections of a user, returns the number of closed connections
+    pub fn disconnect_user(&self, user: &UserId) -> usize {
+        let ids: Vec<ConnectionId> = self
+            .connections
+            .iter()
+            .filter(|connection| connection.value().user == *user)
+            .map(|connection| *connection.key())
+            .collect();
+        ids.into_iter()
+            .filter(|id| self.close_connection(user, *id))
+            .count()
+    }
+
+    /// Set the client type of a connection, the client type can only be set once
+    pub fn set_client(&self, id: ConnectionId, client: &str) -> bool {
+        let client = sanitize_client(client);
+        match self.connections.get_mut(&id) {
+            Some(mut entry) if entry.client.is_none() && !client.is_empty() => {
+                METRICS.add_client_connection(&client);
+                entry.client = Some(client);
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Set the protocol version negotiated for a connection
+    pub fn set_protocol(&self, id: ConnectionId, protocol: ProtocolVersion) {
+        if let Some(mut entry) = self.connections.get_mut(&id) {
+            entry.protocol = protocol;
+        }
+    }
+
+    /// Get the details of all open connections of a user, ordered by connection id
+    pub fn user_connections(&self, user: &UserId) -> Vec<ConnectionInfo> {
+        let mut connections: Vec<ConnectionInfo> = self
+            .connections
+            .iter()
+            .filter(|connection| connection.user == *user)
+            .map(|connection| ConnectionInfo {
+                id: *connection.key(),
+                connected: connection
+                    .connected
+                    .duration_since(UNIX_EPOCH)
+                    .unwrap_or_default()
+                    .as_secs(),
+                protocol: connection.protocol.to_string(),
+                client: connection.client.clone(),
+                subscriptions: connection.subscriptions.clone(),
+            })
+            .collect();
+        connections.sort_by_key(|connection| connection.id);
+        connections
+    }
+
+    /// Only send updates for paths below the subscribed path to the connection
+    pub fn subscribe(&self, id: ConnectionId, path: &str) -> bool {
+        let path = path.trim().trim_end_matches('*').trim_end_matches('/');
+        match self.connections.get_mut(&id) {
+            Some(mut entry) if entry.subscriptions.len() < MAX_SUBSCRIPTIONS => {
+                entry.subscriptions.push(path.to_string());
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Check if a connection should receive an update for the path
+    ///
+    /// Connections without subscriptions receive all updates, as do updates without a known path
+    pub fn is_subscribed(&self, id: ConnectionId, path: Option<&str>) -> bool {
+        match (self.connections.get(&id), path) {
+            (Some(entry), Some(path)) => {
+                entry.subscriptions.is_empty()
+                    || entry
+                        .subscriptions
+                        .iter()
+                        .any(|subscription| is_path_within(path, subscription))
+            }
+            _ => true,
+        }
+    }
+
+    fn remove(&self, user: &UserId, id: ConnectionId) {
+        self.connections.remove(&id);
+        self.count.fetch_sub(1, Ordering::Relaxed);
+        METRICS.remove_connection();
+        if let Entry::Occupied(e) = self.users.entry(user.clone()) {
             if e.get().receiver_count() == 1 {
                 log::debug!("Removing {} from active connections", user);
                 METRICS.remove_user();
@@ -65,6 +358,7 @@ pub struct ConnectionOptions {
     pub listen_file_id: AtomicBool,
     pub max_debounce_time: usize,
     pub max_connection_time: Duration,
+    pub protocol: ProtocolVersion,
 }
 
 impl ConnectionOptions {
@@ -75,6 +369,12 @@ impl ConnectionOptions {
             ..ConnectionOptions::default()
         }
     }
+
+    /// Set the message format negotiated with the client
+    pub fn with_protocol(mut self, protocol: ProtocolVersion) -> Self {
+        self.protocol = protocol;
+        self
+    }
 }
 
 pub async fn handle_user_socket(
@@ -83,40 +383,77 @@ pub async fn handle_user_socket(
     forwarded_for: Vec<IpAddr>,
     opts: ConnectionOptions,
 ) {
-    let user_id = match timeout(
-        Duration::from_secs(15),
-        socket_auth(&mut ws, forwarded_for, &app),
+    let auth = timeout(
+        app.auth_timeout,
+        socket_auth(&mut ws, forwarded_for, &app, opts.protocol),
     )
     .await
-    {
-        Ok(Ok(user_id)) => user_id,
-        Ok(Err(e)) => {
-            log::warn!("{}", e);
-            ws.send(Message::text(format!("err: {}", e))).await.ok();
+    .unwrap_or(Err(AuthenticationError::Timeout));
+    let (user_id, user_name) = match auth {
+        Ok(user) => {
+            METRICS.add_authentication_success();
+            user
+        }
+        Err(AuthenticationError::Timeout) => {
+            let e = AuthenticationError::Timeout;
+            METRICS.add_authentication_failure(e.reason());
+            ws.send(opts.protocol.error_message(&e)).await.ok();
+            ws.send(Message::close_with(e.close_code(), e.to_string()))
+                .await
+                .ok();
             return;
         }
-        Err(_) => {
-            ws.send(Message::text("Authentication timeout".to_string()))
+        Err(e) => {
+            METRICS.add_authentication_failure(e.reason());
+            log::warn!("{}", e);
+            ws.send(opts.protocol.error_message(&e)).await.ok();
+            ws.send(Message::close_with(e.close_code(), e.to_string()))
                 .await
                 .ok();
             return;
         }
     };
 
-    log::info!("new websocket authenticated as {}", user_id);
-    ws.send(Message::text("authenticated")).await.ok();
-
-    let mut rx = match app.connections.add(user_id.clone()) {
-        Ok(rx) => rx,
+    let mut connection = match app.connections.add(user_id.clone()) {
+        Ok(connection) => connection,
         Err(e) => {
-            ws.send(Message::text(e.to_string())).await.ok();
+            ws.send(
+                opts.protocol
+                    .error_message(&AuthenticationError::LimitExceeded),
+            )
+            .await
+            .ok();
+            ws.send(Message::close_with(
+                AuthenticationError::LimitExceeded.close_code(),
+                e.to_string(),
+            ))
+            .await
+            .ok();
             return;
         }
     };
+    app.connections.set_protocol(connection.id, opts.protocol);
+    let reconnect_token = app.reconnect_tokens.issue(&user_name);
+    ws.send(
+        opts.protocol
+            .authenticated_message(&user_name, &reconnect_token),
+    )
+    .await
+    .ok();
+    if app.replay.is_enabled() {
+        ws.send(opts.protocol.seq_message(app.replay.latest()))
+            .await
+            .ok();
+    }
 
-    let (mut user_ws_tx, mut user_ws_rx) = ws.split();
+    log::info!(
+        "new websocket authenticated as {} (connection {})",
+        user_id,
+        connection.id
+    );
 
-    METRICS.add_connection();
+    let connection_id = connection.id;
+    let (mut user_ws_tx, mut user_ws_rx) = ws.split();
 
     // Every time we send a ping, we set this to a random non-zero value
     // when a pong is returned, we check it against the expected value and reset this to 0
@@ -125,29 +462,42 @@ pub async fn handle_user_socket(
     let expect_pong = AtomicUsize::default();
     let expect_pong = &expect_pong;
 
+    // Time of the last frame received from the client, in milliseconds since the connection started
+    let connection_start_time = Instant::now();
+    let last_received = AtomicU64::default();
+    let last_received = &last_received;
+
+    // Sequence numbers the client asked to catch up from
+    let (replay_tx, mut replay_rx) = mpsc::channel::<u64>(4);
+
     let transmit = async {
         // Use faster random generator for generating ping messages, they dont need to be
         // cryptographically secure. It is also OK to use same sequence for every connection.
         let mut rng = rand::rngs::SmallRng::seed_from_u64(0);
 
-        let mut send_queue = SendQueue::default();
+        let mut send_queue = SendQueue::with_mode(app.debounce_mode);
 
         let mut reset = app.reset_rx();
 
-        let connection_start_time = Instant::now();
         let mut last_send = connection_start_time - PING_INTERVAL;
+        let mut last_heartbeat = connection_start_time;
 
         'tx_loop: loop {
             tokio::select! {
-                msg = timeout(Duration::from_millis(500), rx.recv()) => {
+                msg = timeout(Duration::from_millis(500), connection.messages.recv()) => {
                     let now = Instant::now();
+                    let subscribed = |msg: &QueuedMessage| opts.protocol.can_send(&msg.message) && app.connections.is_subscribed(connection_id, msg.path.as_deref());
+                    let msg = msg.map(|msg| msg.and_then(|msg| take_pending(msg, &mut connection.messages, subscribed)));
                     match msg {
-                        Ok(Ok(msg)) => {
-                            if let Some(msg) = send_queue.push(msg, now) {
-                                log::debug!(target: "notify_push::send", "Sending {} to {}", msg, user_id);
-                                METRICS.add_message();
-                                last_send = now;
-                                user_ws_tx.send(msg.into_message(&opts)).await.ok();
+                        Ok(Ok(pending)) => {
+                            for QueuedMessage { message, event, .. } in pending {
+                                log::debug!(target: "notify_push::send", "Queued {} for {} (event {})", message, user_id, event);
+                                if let Some(msg) = send_queue.push(message, now, METRICS.active_connection_count() + 50000, opts.max_debounce_time) {
+                                    log::debug!(target: "notify_push::send", "Sending {} to {} (event {})", msg, user_id, event);
+                                    METRICS.add_message();
+                                    last_send = now;
+                                    user_ws_tx.send(msg.into_message(&opts)).await.ok();
+                                }
                             }
                         }
                         Err(_timout) => {
@@ -157,6 +507,13 @@ pub async fn handle_user_socket(
                                 break 'tx_loop;
                             }
 
+                            let last_received_time = connection_start_time + Duration::from_millis(last_received.load(Ordering::Relaxed));
+                            if app.idle_timeout != Duration::ZERO && now.duration_since(last_received_time) > app.idle_timeout {
+                                log::info!("{} has been idle for too long, closing", user_id);
+                                user_ws_tx.send(Message::close_with(1000u16, "Idle timeout")).await.ok();
+                                break 'tx_loop;
+                            }
+
                             for msg in send_queue.drain(now, METRICS.active_connection_count() + 50000, opts.max_debounce_time) {
                                 last_send = now;
                                 METRICS.add_message();
@@ -164,6 +521,13 @@ pub async fn handle_user_socket(
                                 user_ws_tx.feed(msg.into_message(&opts)).await.ok();
                             }
 
+                            if app.heartbeat_interval != Duration::ZERO && now.duration_since(last_heartbeat) >= app.heartbeat_interval {
+                                log::debug!(target: "notify_push::send", "Sending heartbeat to {}", user_id);
+                                last_heartbeat = now;
+                                last_send = now;
+                                user_ws_tx.feed(opts.protocol.heartbeat_message()).await.ok();
+                            }
+
                             if now.duration_since(last_send) > PING_INTERVAL {
                                 let data = rng.gen::<NonZeroUsize>().into();
                                 let last_ping = expect_pong.swap(data, Ordering::SeqCst);
@@ -180,16 +544,36 @@ pub async fn handle_user_socket(
                             }
                             user_ws_tx.flush().await.ok();
                         }
-                        Ok(Err(_)) => {
-                            // we dont care about dropped messages
+                        Ok(Err(RecvError::Lagged(skipped))) => {
+                            log::info!("{} isn't keeping up with messages, skipped {} messages, closing", user_id, skipped);
+                            user_ws_tx.send(Message::close_with(1013u16, "Send queue full")).await.ok();
+                            break 'tx_loop;
                         }
+                        Ok(Err(RecvError::Closed)) => {}
                     }
                 },
+                Some(seq) = replay_rx.recv() => {
+                    let (messages, latest) = app.replay.since(&user_id, seq);
+                    for QueuedMessage { message, path, .. } in messages {
+                        if opts.protocol.can_send(&message) && app.connections.is_subscribed(connection_id, path.as_deref()) {
+                            log::debug!(target: "notify_push::send", "Replaying {} to {}", message, user_id);
+                            METRICS.add_message();
+                            user_ws_tx.feed(message.into_message(&opts)).await.ok();
+                        }
+                    }
+                    last_send = Instant::now();
+                    user_ws_tx.send(opts.protocol.seq_message(latest)).await.ok();
+                },
                 _ = reset.recv() => {
                     user_ws_tx.close().await.ok();
                     log::debug!("Connection closed by reset request");
                     break 'tx_loop;
                 },
+                _ = &mut connection.close => {
+                    user_ws_tx.close().await.ok();
+                    log::debug!("Connection {} closed by request", connection.id);
+                    break 'tx_loop;
+                },
             };
         }
     };
@@ -197,6 +581,10 @@ pub async fn handle_user_socket(
     let receive = async {
         // handle messages until the client closes the connection
         while let Some(result) = user_ws_rx.next().await {
+            if result.is_ok() {
+                let elapsed = connection_start_time.elapsed().as_millis() as u64;
+                last_received.store(elapsed, Ordering::Relaxed);
+            }
             match result {
                 Ok(msg) if msg.is_pong() => {
                     let expected = expect_pong.swap(0, Ordering::SeqCst);
@@ -209,6 +597,20 @@ pub async fn handle_user_socket(
                     let text = msg.to_str().unwrap_or_default();
                     if text == "listen notify_file_id" {
                         opts.listen_file_id.store(true, Ordering::Relaxed);
+                    } else if let Some(path) = text.strip_prefix("subscribe ") {
+                        app.connections.subscribe(connection_id, path);
+                    } else if let Some(feature) = text.strip_prefix("listen ") {
+                        log::debug!("unknown listen option {}", feature);
+                    } else if let Some(client) = text.strip_prefix("client ") {
+                        app.connections.set_client(connection_id, client);
+                    } else if let Some(seq) = text.strip_prefix(SINCE_PREFIX) {
+                        match seq.parse() {
+                            Ok(seq) if app.replay.is_enabled() => {
+                                replay_tx.try_send(seq).ok();
+                            }
+                            Ok(_) => {}
+                            Err(_) => log::debug!("invalid replay sequence number {}", seq),
+                        }
                     }
                 }
                 Ok(_) => {}
@@ -232,16 +634,52 @@ pub async fn handle_user_socket(
     pin_mut!(receive);
 
     select(transmit, receive).await;
+}
+
+/// Take all messages that are already queued for the connection and match the filter,
+/// skipping messages identical to one already taken
+fn take_pending(
+    first: QueuedMessage,
+    messages: &mut broadcast::Receiver<QueuedMessage>,
+    filter: impl Fn(&QueuedMessage) -> bool,
+) -> Result<Vec<QueuedMessage>, RecvError> {
+    let mut pending: Vec<QueuedMessage> = Vec::new();
+    let mut next = Some(first);
+    loop {
+        if let Some(msg) = next.take().filter(&filter) {
+            if !pending.iter().any(|queued| queued.message == msg.message) {
+                pending.push(msg);
+            }
+        }
+        match messages.try_recv() {
+            Ok(msg) => next = Some(msg),
+            Err(TryRecvError::Lagged(skipped)) => return Err(RecvError::Lagged(skipped)),
+            Err(_) => return Ok(pending),
+        }
+    }
+}
 
-    METRICS.remove_connection();
-    app.connections.remove(&user_id);
+/// Limit the client type to a short, metrics safe, label
+fn sanitize_client(client: &str) -> String {
+    client
+        .trim()
+        .chars()
+        .filter(|c| c.is_ascii_alphanumeric() || matches!(c, '.' | '-' | '_' | '/' | ' '))
+        .take(MAX_CLIENT_LENGTH)
+        .collect()
 }
 
-async fn read_socket_auth_message(rx: &mut WebSocket) -> Result<Message, WebSocketError> {
+async fn read_socket_auth_message(
+    rx: &mut WebSocket,
+    max_length: usize,
+) -> Result<Message, AuthenticationError> {
     match rx.next().await {
+        Some(Ok(msg)) if msg.as_bytes().len() > max_length => {
+            Err(AuthenticationError::MessageTooLarge)
+        }
         Some(Ok(msg)) => Ok(msg),
-        Some(Err(e)) => Err(e.into()),
-        None => Err(WebSocketError::Disconnected),
+        Some(Err(e)) => Err(WebSocketError::from(e).into()),
+        None => Err(WebSocketError::Disconnected.into()),
     }
 }
 
@@ -249,16 +687,42 @@ async fn socket_auth(
     rx: &mut WebSocket,
     forwarded_for: Vec<IpAddr>,
     app: &App,
-) -> Result<UserId, AuthenticationError> {
-    let username_msg = read_socket_auth_message(rx).await?;
+    protocol: ProtocolVersion,
+) -> Result<(UserId, String), AuthenticationError> {
+    let mut username_msg = read_socket_auth_message(rx, app.max_auth_message_length).await?;
+    if let Some(token) = username_msg
+        .to_str()
+        .ok()
+        .and_then(|msg| msg.strip_prefix(RESUME_PREFIX))
+    {
+        if let Some(user) = app.reconnect_tokens.verify(token) {
+            log::debug!("Resumed socket for {} using reconnect token", user);
+            return Ok((app.user_id(&UserId::from(user.as_str())), user));
+        }
+        log::debug!("Invalid reconnect token, falling back to credentials");
+        rx.send(protocol.resume_failed_message())
+            .await
+            .map_err(WebSocketError::from)?;
+        username_msg = read_socket_auth_message(rx, app.max_auth_message_length).await?;
+    }
     let username = username_msg
         .to_str()
         .map_err(|_| AuthenticationError::InvalidMessage)?;
-    let password_msg = read_socket_auth_message(rx).await?;
+    let password_msg = read_socket_auth_message(rx, app.max_auth_message_length).await?;
     let password = password_msg
         .to_str()
         .map_err(|_| AuthenticationError::InvalidMessage)?;
 
+    authenticate(app, username, password, forwarded_for).await
+}
+
+/// Verify a username and password or pre-authenticated token
+async fn authenticate(
+    app: &App,
+    username: &str,
+    password: &str,
+    forwarded_for: Vec<IpAddr>,
+) -> Result<(UserId, String), AuthenticationError> {
     // cleanup all pre_auth tokens older than 15s
     let cutoff = Instant::now() - Duration::from_secs(15);
     app.pre_auth.retain(|_, (time, _)| *time > cutoff);
@@ -268,15 +732,387 @@ async fn socket_auth(
             "Authenticated socket for {} using pre authenticated token",
             user
         );
-        return Ok(user);
+        return Ok((app.user_id(&UserId::from(user.as_str())), user));
+    }
+
+    let client_ip = forwarded_for.first().copied();
+    if let Some(client_ip) = &client_ip {
+        if app.auth_rate_limiter.is_limited(client_ip) {
+            log::info!("Rejecting authentication from {}, rate limited", client_ip);
+            return Err(AuthenticationError::RateLimited);
+        }
     }
 
-    if !username.is_empty() {
-        Ok(app
-            .nc_client
+    let result = if !username.is_empty() {
+        let start = Instant::now();
+        let result = app
+            .authenticator
             .verify_credentials(username, password, forwarded_for)
-            .await?)
+            .await;
+        METRICS.auth_duration.observe(start.elapsed());
+        result
     } else {
         Err(AuthenticationError::Invalid)
+    };
+
+    if let (Err(AuthenticationError::Invalid), Some(client_ip)) = (&result, client_ip) {
+        app.auth_rate_limiter.add_failure(client_ip);
     }
+
+    result.map(|user| (app.user_id(&UserId::from(user.as_str())), user))
+}
+
+/// Authenticate a server-sent events request and stream the messages for the user
+///
+/// Messages are sent in the same json format as for `notify_push.v2` websocket clients
+pub async fn user_event_stream(
+    app: Arc<App>,
+    username: String,
+    password: String,
+    forwarded_for: Vec<IpAddr>,
+    opts: ConnectionOptions,
+) -> Result<ReceiverStream<Result<sse::Event, Infallible>>, AuthenticationError> {
+    let auth = timeout(
+        app.auth_timeout,
+        authenticate(&app, &username, &password, forwarded_for),
+    )
+    .await
+    .unwrap_or(Err(AuthenticationError::Timeout));
+    let (user_id, user_name) = match auth {
+        Ok(user) => {
+            METRICS.add_authentication_success();
+            user
+        }
+        Err(e) => {
+            METRICS.add_authentication_failure(e.reason());
+            return Err(e);
+        }
+    };
+
+    let (tx, rx) = mpsc::channel(16);
+    let (registered_tx, registered) = oneshot::channel();
+    tokio::spawn(async move {
+        let mut connection = match app.connections.add(user_id.clone()) {
+            Ok(connection) => connection,
+            Err(_) => {
+                registered_tx
+                    .send(Err(AuthenticationError::LimitExceeded))
+                    .ok();
+                return;
+            }
+        };
+        app.connections
+            .set_protocol(connection.id, ProtocolVersion::V2);
+        registered_tx.send(Ok(())).ok();
+        log::info!(
+            "new event stream authenticated as {} (connection {})",
+            user_id,
+            connection.id
+        );
+
+        let authenticated = json!({"type": "authenticated", "user": user_name});
+        if tx
+            .send(Ok(sse::Event::default().data(authenticated.to_string())))
+            .await
+            .is_err()
+        {
+            return;
+        }
+
+        let mut send_queue = SendQueue::with_mode(app.debounce_mode);
+        let mut reset = app.reset_rx();
+        let connection_start_time = Instant::now();
+
+        'stream: loop {
+            let messages: Vec<PushMessage> = tokio::select! {
+                msg = timeout(Duration::from_millis(500), connection.messages.recv()) => {
+                    let now = Instant::now();
+                    match msg {
+                        Ok(Ok(msg)) => {
+                            if ProtocolVersion::V2.can_send(&msg.message) && app.connections.is_subscribed(connection.id, msg.path.as_deref()) {
+                                send_queue.push(msg.message, now, METRICS.active_connection_count() + 50000, opts.max_debounce_time).into_iter().collect()
+                            } else {
+                                Vec::new()
+                            }
+                        }
+                        Ok(Err(RecvError::Lagged(skipped))) => {
+                            log::info!("{} isn't keeping up with messages, skipped {} messages, closing", user_id, skipped);
+                            break 'stream;
+                        }
+                        Ok(Err(RecvError::Closed)) => Vec::new(),
+                        Err(_timeout) => {
+                            if opts.max_connection_time != Duration::ZERO && now - connection_start_time > opts.max_connection_time {
+                                log::debug!("Event stream closed by exceeding maximum connection time");
+                                break 'stream;
+                            }
+                            send_queue.drain(now, METRICS.active_connection_count() + 50000, opts.max_debounce_time).collect()
+                        }
+                    }
+                },
+                _ = tx.closed() => break 'stream,
+                _ = reset.recv() => break 'stream,
+                _ = &mut connection.close => break 'stream,
+            };
+
+            for message in messages {
+                log::debug!(target: "notify_push::send", "Sending {} to {} over event stream", message, user_id);
+                METRICS.add_message();
+                let event = sse::Event::default().data(message.into_json(false).to_string());
+                if tx.send(Ok(event)).await.is_err() {
+                    break 'stream;
+                }
+            }
+        }
+        log::debug!("Event stream {} closed", connection.id);
+    });
+
+    registered
+        .await
+        .unwrap_or(Err(AuthenticationError::LimitExceeded))?;
+    Ok(ReceiverStream::new(rx))
+}
+
+#[test]
+fn test_close_connection() {
+    let connections = ActiveConnections::default();
+    let user = UserId::from("foo");
+    let mut first = connections.add(user.clone()).unwrap();
+    let mut second = connections.add(user.clone()).unwrap();
+
+    assert!(!connections.close_connection(&UserId::from("bar"), first.id));
+    assert!(connections.close_connection(&user, first.id));
+    assert!(!connections.close_connection(&user, first.id));
+    assert!(first.close.try_recv().is_ok());
+    assert!(second.close.try_recv().is_err());
+
+    drop(first);
+    connections.send_to_user(&user, PushMessage::Activity);
+    assert_eq!(
+        PushMessage::Activity,
+        second.messages.try_recv().unwrap().message
+    );
+}
+
+#[test]
+fn test_connection_count() {
+    let connections = ActiveConnections::default();
+    let first = connections.add(UserId::from("foo")).unwrap();
+    let second = connections.add(UserId::from("foo")).unwrap();
+    let other = connections.add(UserId::from("bar")).unwrap();
+    assert_eq!(3, connections.connection_count());
+
+    drop(first);
+    assert_eq!(2, connections.connection_count());
+    connections.close_connection(&UserId::from("foo"), second.id);
+    drop(second);
+    drop(other);
+    assert_eq!(0, connections.connection_count());
+    assert!(connections.users.is_empty());
+}
+
+#[test]
+fn test_client_connection_count() {
+    let connections = ActiveConnections::default();
+    let client = "test-client/1.0";
+    let first = connections.add(UserId::from("foo")).unwrap();
+    let second = connections.add(UserId::from("foo")).unwrap();
+    let _without_client = connections.add(UserId::from("bar")).unwrap();
+
+    assert!(connections.set_client(first.id, client));
+    assert!(!connections.set_client(first.id, "other"));
+    assert!(connections.set_client(second.id, &format!(" {}\n", client)));
+    assert_eq!(2, METRICS.client_connection_count(client));
+
+    connections.close_connection(&UserId::from("foo"), second.id);
+    assert_eq!(1, METRICS.client_connection_count(client));
+    drop(second);
+    drop(first);
+    assert_eq!(0, METRICS.client_connection_count(client));
+}
+
+#[test]
+fn test_sanitize_client() {
+    assert_eq!(
+        "Nextcloud-android/3.26.0",
+        sanitize_client("Nextcloud-android/3.26.0")
+    );
+    assert_eq!("foobar", sanitize_client("foo\"}bar"));
+    assert_eq!(MAX_CLIENT_LENGTH, sanitize_client(&"a".repeat(100)).len());
+}
+
+#[test]
+fn test_send_queue_overflow() {
+    let connections = ActiveConnections::with_queue_size(2);
+    let user = UserId::from("foo");
+    let mut stalled = connections.add(user.clone()).unwrap();
+
+    connections.send_to_user(&user, PushMessage::Activity);
+    connections.send_to_user(&user, PushMessage::Notification);
+    assert_eq!(
+        PushMessage::Activity,
+        stalled.messages.try_recv().unwrap().message
+    );
+
+    connections.send_to_user(&user, PushMessage::Activity);
+    connections.send_to_user(&user, PushMessage::Notification);
+    connections.send_to_user(&user, PushMessage::Activity);
+    assert_eq!(Err(TryRecvError::Lagged(2)), stalled.messages.try_recv());
+}
+
+#[test]
+fn test_send_delivery() {
+    let connections = ActiveConnections::with_queue_size(2);
+    let user = UserId::from("foo");
+    let _stalled = connections.add(user.clone()).unwrap();
+    let _other = connections.add(user.clone()).unwrap();
+    let failed = METRICS.failed_deliveries();
+
+    let delivered = Delivery {
+        connections: 2,
+        overflowed: false,
+    };
+    assert_eq!(
+        delivered,
+        connections.send_to_user(&user, PushMessage::Activity)
+    );
+    assert_eq!(
+        delivered,
+        connections.send_to_user(&user, PushMessage::Activity)
+    );
+    // the queue is full, sending doesn't wait for the stalled connections
+    assert_eq!(
+        Delivery {
+            connections: 2,
+            overflowed: true,
+        },
+        connections.send_to_user(&user, PushMessage::Activity)
+    );
+    // other tests count failed deliveries concurrently
+    assert!(METRICS.failed_deliveries() > failed);
+
+    assert_eq!(
+        Delivery::default(),
+        connections.send_to_user(&UserId::from("bar"), PushMessage::Activity)
+    );
+}
+
+#[test]
+fn test_take_pending_dedup() {
+    use crate::message::UpdatedFiles;
+
+    let connections = ActiveConnections::default();
+    let user = UserId::from("foo");
+    let mut connection = connections.add(user.clone()).unwrap();
+
+    let update = PushMessage::File(UpdatedFiles::Known(vec![1].into()));
+    connections.send_to_user(&user, update.clone());
+    connections.send_to_user(&user, update.clone());
+    connections.send_to_user(&user, PushMessage::Activity);
+    connections.send_to_user(&user, update.clone());
+
+    let first = connection.messages.try_recv().unwrap();
+    assert_eq!(
+        vec![update, PushMessage::Activity],
+        take_pending(first, &mut connection.messages, |_| true)
+            .unwrap()
+            .into_iter()
+            .map(|msg| msg.message)
+            .collect::<Vec<_>>()
+    );
+    assert!(connection.messages.try_recv().is_err());
+}
+
+#[test]
+fn test_path_subscriptions() {
+    use crate::message::UpdatedFiles;
+
+    let connections = ActiveConnections::default();
+    let user = UserId::from("foo");
+    let mut subscribed = connections.add(user.clone()).unwrap();
+    let mut unsubscribed = connections.add(user.clone()).unwrap();
+    assert!(connections.subscribe(subscribed.id, "files/Photos/*"));
+
+    let update = PushMessage::File(UpdatedFiles::Known(vec![1].into()));
+    let other = PushMessage::File(UpdatedFiles::Known(vec![2].into()));
+    connections.send_to_user(
+        &user,
+        QueuedMessage::from(update.clone()).with_path("files/Photos/a.jpg".into()),
+    );
+    connections.send_to_user(
+        &user,
+        QueuedMessage::from(other.clone()).with_path("files/Documents/a.txt".into()),
+    );
+    connections.send_to_user(&user, PushMessage::Activity);
+
+    let filter =
+        |msg: &QueuedMessage| connections.is_subscribed(subscribed.id, msg.path.as_deref());
+    let first = subscribed.messages.try_recv().unwrap();
+    assert_eq!(
+        vec![update.clone(), PushMessage::Activity],
+        take_pending(first, &mut subscribed.messages, filter)
+            .unwrap()
+            .into_iter()
+            .map(|msg| msg.message)
+            .collect::<Vec<_>>()
+    );
+
+    let filter =
+        |msg: &QueuedMessage| connections.is_subscribed(unsubscribed.id, msg.path.as_deref());
+    let first = unsubscribed.messages.try_recv().unwrap();
+    assert_eq!(
+        vec![update, other, PushMessage::Activity],
+        take_pending(first, &mut unsubscribed.messages, filter)
+            .unwrap()
+            .into_iter()
+            .map(|msg| msg.message)
+            .collect::<Vec<_>>()
+    );
+}
+
+#[test]
+fn test_event_id_carried() {
+    let connections = ActiveConnections::default();
+    let user = UserId::from("foo");
+    let mut connection = connections.add(user.clone()).unwrap();
+
+    let event = EventId::new();
+    connections.send_to_user(&user, QueuedMessage::new(PushMessage::Activity, event));
+    connections.send_to_user(
+        &user,
+        QueuedMessage::new(PushMessage::Activity, EventId::new()),
+    );
+
+    let first = connection.messages.try_recv().unwrap();
+    let pending = take_pending(first, &mut connection.messages, |_| true).unwrap();
+    assert_eq!(1, pending.len());
+    assert_eq!(event, pending[0].event);
+}
+
+#[test]
+fn test_connection_duration() {
+    let connections = ActiveConnections::default();
+    let before = METRICS.connection_duration.bucket_count(1000).unwrap();
+    let connection = connections.add(UserId::from("foo")).unwrap();
+    drop(connection);
+    assert!(METRICS.connection_duration.bucket_count(1000).unwrap() > before);
+}
+
+#[test]
+fn test_user_connections() {
+    let connections = ActiveConnections::default();
+    let first = connections.add(UserId::from("foo")).unwrap();
+    let second = connections.add(UserId::from("foo")).unwrap();
+    let _other = connections.add(UserId::from("bar")).unwrap();
+    connections.set_protocol(second.id, ProtocolVersion::V2);
+    connections.set_client(second.id, "desktop");
+
+    let info = connections.user_connections(&UserId::from("foo"));
+    assert_eq!(2, info.len());
+    assert_eq!(first.id, info[0].id);
+    assert_eq!("notify_push.v1", info[0].protocol);
+    assert_eq!(None, info[0].client);
+    assert_eq!(second.id, info[1].id);
+    assert_eq!("notify_push.v2", info[1].protocol);
+    assert_eq!(Some("desktop"), info[1].client.as_deref());
+    assert!(info[1].connected > 0);
 }
@@ -26,6 +26,10 @@ pub enum Error {
     Authentication(#[from] AuthenticationError),
     #[error("Error while communicating with Nextcloud")]
     NextCloud(#[from] NextCloudError),
+    #[error("Failed to setup webhook client")]
+    Webhook(#[source] reqwest::Error),
+    #[error("Timed out after {1}s while connecting to the {0}")]
+    StartupTimeout(&'static str, u64),
 }
 
 #[derive(Debug, Error, Diagnostic)]
@@ -66,6 +70,12 @@ pub enum SelfTestError {
     Redis(#[from] RedisError),
     #[error("Error while communicating with nextcloud instance")]
     NextcloudCommunication(#[from] NextCloudError),
+    #[error("Timeout while waiting for the redis subscription")]
+    SubscribeTimeout,
+    #[error(
+        "Test cookie {expected} wasn't received through redis, last received cookie is {received}"
+    )]
+    CookieMismatch { expected: u32, received: u32 },
 }
 
 #[derive(Debug, Error, Diagnostic)]
@@ -74,6 +84,12 @@ pub enum SocketError {
     Bind(#[source] std::io::Error, String),
     #[error("Failed to set socket permissions")]
     SocketPermissions(#[source] std::io::Error),
+    #[error("Failed to set socket ownership")]
+    SocketOwner(#[source] std::io::Error),
+    #[error("Socket {0} is already in use by another process")]
+    InUse(String),
+    #[error("{0} already exists and is not a socket")]
+    NotASocket(String),
 }
 
 #[derive(Debug, Error, Diagnostic)]
@@ -84,6 +100,8 @@ pub enum ConfigError {
     NoNextcloud,
     #[error("No database server is configured")]
     NoDatabase,
+    #[error("Validating events requires the instance id to be configured")]
+    NoInstanceId,
     #[error("Error while parsing nextcloud config.php")]
     #[diagnostic(transparent)]
     Parse(#[from] nextcloud_config_parser::Error),
@@ -92,10 +110,36 @@ pub enum ConfigError {
         &'static str,
         #[source] Box<dyn std::error::Error + Send + Sync>,
     ),
+    #[error(
+        "Invalid {0} environment variable, expected a boolean value like true or false, got {1}"
+    )]
+    InvalidBool(&'static str, String),
     #[error("socket permissions should be provided in the octal form `0xxx`, got {0}")]
     SocketPermissions(String, Option<ParseIntError>),
+    #[error("Unknown socket owner {0}")]
+    UnknownUser(String),
+    #[error("Unknown socket group {0}")]
+    UnknownGroup(String),
     #[error("Failed to parse log level")]
     LogLevel(#[from] FlexiLoggerError),
+    #[error("Both {0} and {1} are set")]
+    DuplicateSecret(&'static str, String),
+    #[error("Failed to read {0} from {1}")]
+    SecretFile(String, String, #[source] std::io::Error),
+    #[error("Invalid bind configuration: {0}")]
+    InvalidBind(String),
+    #[error("Cache jitter should be between 0 and 1, got {0}")]
+    CacheJitter(f64),
+    #[error("Authentication timeout should be between 1 and 60 seconds, got {0}")]
+    AuthTimeout(usize),
+    #[error("Invalid allowed origin {0}, origins should be in the form scheme://host[:port]")]
+    InvalidOrigin(String),
+    #[error("Invalid instance prefix {0}, expected instance_id=prefix")]
+    InvalidInstancePrefix(String),
+    #[error("Unknown event type {0}, expected one of {}", crate::event::EVENT_TYPES.join(", "))]
+    InvalidEventType(String),
+    #[error("Failed to read TLS {0} at {1}")]
+    Tls(&'static str, String, #[source] std::io::Error),
 }
 
 #[derive(Debug, Error, Diagnostic)]
@@ -118,4 +162,103 @@ pub enum AuthenticationError {
     Invalid,
     #[error("Connection limit exceeded for user")]
     LimitExceeded,
+    #[error("rate limited")]
+    RateLimited,
+    #[error("Authentication timeout")]
+    Timeout,
+    #[error("message too large")]
+    MessageTooLarge,
+}
+
+/// Metric labels for the reasons an authentication can fail
+pub const AUTH_FAILURE_REASONS: [&str; 8] = [
+    "socket",
+    "invalid_message",
+    "nextcloud",
+    "invalid_credentials",
+    "limit_exceeded",
+    "rate_limited",
+    "timeout",
+    "message_too_large",
+];
+
+impl AuthenticationError {
+    /// The websocket close code to send to the client when authentication fails
+    pub fn close_code(&self) -> u16 {
+        match self {
+            // protocol error
+            AuthenticationError::Socket(_) => 1002,
+            // policy violation
+            AuthenticationError::InvalidMessage
+            | AuthenticationError::Invalid
+            | AuthenticationError::Timeout => 1008,
+            // internal error
+            AuthenticationError::Nextcloud(_) => 1011,
+            // message too big
+            AuthenticationError::MessageTooLarge => 1009,
+            // try again later
+            AuthenticationError::LimitExceeded | AuthenticationError::RateLimited => 1013,
+        }
+    }
+
+    /// The http status to respond with when authenticating a http request fails
+    pub fn status_code(&self) -> StatusCode {
+        match self {
+            AuthenticationError::Socket(_) | AuthenticationError::InvalidMessage => {
+                StatusCode::BAD_REQUEST
+            }
+            AuthenticationError::Invalid => StatusCode::UNAUTHORIZED,
+            AuthenticationError::Nextcloud(_) => StatusCode::BAD_GATEWAY,
+            AuthenticationError::LimitExceeded | AuthenticationError::RateLimited => {
+                StatusCode::TOO_MANY_REQUESTS
+            }
+            AuthenticationError::Timeout => StatusCode::REQUEST_TIMEOUT,
+            AuthenticationError::MessageTooLarge => StatusCode::PAYLOAD_TOO_LARGE,
+        }
+    }
+
+    /// The label used for the failure in the authentication metrics
+    pub fn reason(&self) -> &'static str {
+        match self {
+            AuthenticationError::Socket(_) => AUTH_FAILURE_REASONS[0],
+            AuthenticationError::InvalidMessage => AUTH_FAILURE_REASONS[1],
+            AuthenticationError::Nextcloud(_) => AUTH_FAILURE_REASONS[2],
+            AuthenticationError::Invalid => AUTH_FAILURE_REASONS[3],
+            AuthenticationError::LimitExceeded => AUTH_FAILURE_REASONS[4],
+            AuthenticationError::RateLimited => AUTH_FAILURE_REASONS[5],
+            AuthenticationError::Timeout => AUTH_FAILURE_REASONS[6],
+            AuthenticationError::MessageTooLarge => AUTH_FAILURE_REASONS[7],
+        }
+    }
+}
+
+#[test]
+fn test_authentication_error_reason() {
+    use std::collections::HashSet;
+
+    let errors = [
+        AuthenticationError::Socket(WebSocketError::Disconnected),
+        AuthenticationError::InvalidMessage,
+        AuthenticationError::Nextcloud(NextCloudError::NotATrustedDomain("example.com".into())),
+        AuthenticationError::Invalid,
+        AuthenticationError::LimitExceeded,
+        AuthenticationError::RateLimited,
+        AuthenticationError::Timeout,
+        AuthenticationError::MessageTooLarge,
+    ];
+    let reasons: HashSet<_> = errors.iter().map(AuthenticationError::reason).collect();
+    assert_eq!(AUTH_FAILURE_REASONS.len(), reasons.len());
+
+    for error in &errors {
+        let expected = match error {
+            AuthenticationError::Socket(_) => 1002,
+            AuthenticationError::Nextcloud(_) => 1011,
+            AuthenticationError::MessageTooLarge => 1009,
+            AuthenticationError::LimitExceeded | AuthenticationError::RateLimited => 1013,
+            AuthenticationError::InvalidMessage
+            | AuthenticationError::Invalid
+            | AuthenticationError::Timeout => 1008,
+        };
+        assert_eq!(expected, error.close_code());
+    }
 }
@@ -1,24 +1,81 @@
 use crate::metrics::METRICS;
 use crate::{Redis, Result, UserId};
+use futures::stream::BoxStream;
 use parse_display::Display;
-use redis::Msg;
-use serde::Deserialize;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Deserializer};
 use serde_json::Value;
-use std::convert::TryFrom;
+use std::collections::VecDeque;
+use std::fmt;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
 use thiserror::Error;
-use tokio_stream::{Stream, StreamExt};
+use tokio::sync::Notify;
+use tokio_stream::StreamExt;
+
+/// Short random id to correlate the log lines for an event from receiving it to sending it to the clients
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Display)]
+#[display("{0:08x}")]
+pub struct EventId(u32);
+
+impl EventId {
+    pub fn new() -> Self {
+        EventId(rand::random())
+    }
+}
+
+impl Default for EventId {
+    fn default() -> Self {
+        Self::new()
+    }
+}
 
 #[derive(Debug, Deserialize)]
 pub struct StorageUpdate {
     pub storage: u32,
     pub path: String,
     pub file_id: u64,
+    /// Instance id of the Nextcloud server the update happened on
+    #[serde(default)]
+    pub instance: Option<String>,
+}
+
+/// Updates to many paths in a storage at once, sent for bulk operations
+#[derive(Debug, Deserialize)]
+pub struct StorageUpdateBatch {
+    pub storage: u32,
+    pub paths: Vec<String>,
+    #[serde(default)]
+    pub instance: Option<String>,
+}
+
+impl fmt::Display for StorageUpdateBatch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "storage update notification for storage {} and {} paths",
+            self.storage,
+            self.paths.len()
+        )
+    }
+}
+
+/// A file or folder moved within a storage
+#[derive(Debug, Deserialize)]
+pub struct StorageUpdateMove {
+    pub storage: u32,
+    pub from: String,
+    pub to: String,
+    #[serde(default)]
+    pub instance: Option<String>,
 }
 
 #[derive(Debug, Deserialize)]
 pub struct GroupUpdate {
     pub user: UserId,
     pub group: String,
+    #[serde(default)]
+    pub instance: Option<String>,
 }
 
 #[derive(Debug, Deserialize)]
@@ -34,11 +91,35 @@ pub struct Activity {
 #[derive(Debug, Deserialize)]
 pub struct Notification {
     pub user: UserId,
+    /// The object the notification is about, if included by the notifications app
+    #[serde(default, deserialize_with = "deserialize_lenient")]
+    pub object: Option<NotificationObject>,
 }
 
 #[derive(Debug, Deserialize)]
-pub struct PreAuth {
+pub struct NotificationObject {
+    #[serde(rename = "type")]
+    pub ty: String,
+    pub id: String,
+}
+
+/// Deserialize an optional field, ignoring values that don't match the expected format
+fn deserialize_lenient<'de, D, T>(deserializer: D) -> Result<Option<T>, D::Error>
+where
+    D: Deserializer<'de>,
+    T: DeserializeOwned,
+{
+    Ok(T::deserialize(Value::deserialize(deserializer)?).ok())
+}
+
+#[derive(Debug, Deserialize)]
+pub struct CredentialInvalidation {
     pub user: UserId,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct PreAuth {
+    pub user: String,
     pub token: String,
 }
 
@@ -73,6 +154,10 @@ pub enum Signal {
 pub enum Event {
     #[display("storage update notification for storage {0.storage} and path {0.path}")]
     StorageUpdate(StorageUpdate),
+    #[display("{0}")]
+    StorageUpdateBatch(StorageUpdateBatch),
+    #[display("move notification for storage {0.storage} from {0.from} to {0.to}")]
+    StorageUpdateMove(StorageUpdateMove),
     #[display("group update notification for user {0.user}")]
     GroupUpdate(GroupUpdate),
     #[display("share create notification for user {0.user}")]
@@ -85,6 +170,8 @@ pub enum Event {
     Notification(Notification),
     #[display("pre_auth user {0.user}")]
     PreAuth(PreAuth),
+    #[display("credential invalidation for user {0.user}")]
+    CredentialInvalidation(CredentialInvalidation),
     #[display("custom notification {0.message} for user {0.user}")]
     Custom(Custom),
     #[display("config update")]
@@ -95,80 +182,486 @@ pub enum Event {
     Signal(Signal),
 }
 
+/// All redis channels the push server listens to
+pub const CHANNELS: [&str; 14] = [
+    "notify_storage_update",
+    "notify_storage_update_batch",
+    "notify_storage_update_move",
+    "notify_group_membership_update",
+    "notify_user_share_created",
+    "notify_test_cookie",
+    "notify_activity",
+    "notify_notification",
+    "notify_pre_auth",
+    "notify_credential_invalidation",
+    "notify_custom",
+    "notify_config",
+    "notify_query",
+    "notify_signal",
+];
+
+/// Names of the event types, as used for disabling event types
+pub const EVENT_TYPES: [&str; 14] = [
+    "storage_update",
+    "storage_update_batch",
+    "storage_update_move",
+    "group_update",
+    "share_create",
+    "test_cookie",
+    "activity",
+    "notification",
+    "pre_auth",
+    "credential_invalidation",
+    "custom",
+    "config",
+    "query",
+    "signal",
+];
+
 #[derive(Debug, Error)]
 pub enum MessageDecodeError {
     #[error("unsupported event type")]
     UnsupportedEventType,
+    #[error("event from another instance")]
+    ForeignInstance,
     #[error("json deserialization error: {0}")]
     Json(#[from] serde_json::Error),
 }
 
-impl TryFrom<Msg> for Event {
-    type Error = MessageDecodeError;
-
-    fn try_from(msg: Msg) -> Result<Self, Self::Error> {
-        match msg.get_channel_name() {
-            "notify_storage_update" => Ok(Event::StorageUpdate(serde_json::from_slice(
-                msg.get_payload_bytes(),
-            )?)),
-            "notify_group_membership_update" => Ok(Event::GroupUpdate(serde_json::from_slice(
-                msg.get_payload_bytes(),
-            )?)),
-            "notify_user_share_created" => Ok(Event::ShareCreate(serde_json::from_slice(
-                msg.get_payload_bytes(),
-            )?)),
-            "notify_test_cookie" => Ok(Event::TestCookie(serde_json::from_slice(
-                msg.get_payload_bytes(),
-            )?)),
-            "notify_activity" => Ok(Event::Activity(serde_json::from_slice(
-                msg.get_payload_bytes(),
-            )?)),
-            "notify_notification" => Ok(Event::Notification(serde_json::from_slice(
-                msg.get_payload_bytes(),
-            )?)),
-            "notify_pre_auth" => Ok(Event::PreAuth(serde_json::from_slice(
-                msg.get_payload_bytes(),
-            )?)),
-            "notify_custom" => Ok(Event::Custom(serde_json::from_slice(
-                msg.get_payload_bytes(),
-            )?)),
-            "notify_config" => Ok(Event::Config(serde_json::from_slice(
-                msg.get_payload_bytes(),
-            )?)),
-            "notify_query" => Ok(Event::Query(serde_json::from_slice(
-                msg.get_payload_bytes(),
-            )?)),
-            "notify_signal" => Ok(Event::Signal(serde_json::from_slice(
-                msg.get_payload_bytes(),
-            )?)),
+impl Event {
+    /// Name of the event type, one of [`EVENT_TYPES`]
+    pub fn event_type(&self) -> &'static str {
+        match self {
+            Event::StorageUpdate(_) => "storage_update",
+            Event::StorageUpdateBatch(_) => "storage_update_batch",
+            Event::StorageUpdateMove(_) => "storage_update_move",
+            Event::GroupUpdate(_) => "group_update",
+            Event::ShareCreate(_) => "share_create",
+            Event::TestCookie(_) => "test_cookie",
+            Event::Activity(_) => "activity",
+            Event::Notification(_) => "notification",
+            Event::PreAuth(_) => "pre_auth",
+            Event::CredentialInvalidation(_) => "credential_invalidation",
+            Event::Custom(_) => "custom",
+            Event::Config(_) => "config",
+            Event::Query(_) => "query",
+            Event::Signal(_) => "signal",
+        }
+    }
+
+    /// Decode an event from the payload received on the channel (without prefix)
+    pub fn decode(channel: &str, payload: &[u8]) -> Result<Self, MessageDecodeError> {
+        match channel {
+            "notify_storage_update" => Ok(Event::StorageUpdate(serde_json::from_slice(payload)?)),
+            "notify_storage_update_batch" => {
+                Ok(Event::StorageUpdateBatch(serde_json::from_slice(payload)?))
+            }
+            "notify_storage_update_move" => {
+                Ok(Event::StorageUpdateMove(serde_json::from_slice(payload)?))
+            }
+            "notify_group_membership_update" => {
+                Ok(Event::GroupUpdate(serde_json::from_slice(payload)?))
+            }
+            "notify_user_share_created" => Ok(Event::ShareCreate(serde_json::from_slice(payload)?)),
+            "notify_test_cookie" => Ok(Event::TestCookie(serde_json::from_slice(payload)?)),
+            "notify_activity" => Ok(Event::Activity(serde_json::from_slice(payload)?)),
+            "notify_notification" => Ok(Event::Notification(serde_json::from_slice(payload)?)),
+            "notify_pre_auth" => Ok(Event::PreAuth(serde_json::from_slice(payload)?)),
+            "notify_credential_invalidation" => Ok(Event::CredentialInvalidation(
+                serde_json::from_slice(payload)?,
+            )),
+            "notify_custom" => Ok(Event::Custom(serde_json::from_slice(payload)?)),
+            "notify_config" => Ok(Event::Config(serde_json::from_slice(payload)?)),
+            "notify_query" => Ok(Event::Query(serde_json::from_slice(payload)?)),
+            "notify_signal" => Ok(Event::Signal(serde_json::from_slice(payload)?)),
             _ => Err(MessageDecodeError::UnsupportedEventType),
         }
     }
 }
 
+/// Check that the event carries the instance id of the Nextcloud server we're serving
+fn is_from_instance(payload: &[u8], instance_id: &str) -> bool {
+    #[derive(Deserialize)]
+    struct Instance {
+        instance: Option<String>,
+    }
+
+    serde_json::from_slice::<Instance>(payload)
+        .ok()
+        .and_then(|event| event.instance)
+        .as_deref()
+        == Some(instance_id)
+}
+
+/// How events are received from redis
+#[derive(
+    Debug, Clone, Copy, Default, PartialEq, Eq, parse_display::Display, parse_display::FromStr,
+)]
+#[display(style = "kebab-case")]
+pub enum RedisMode {
+    /// Subscribe to the channels the events are published on
+    #[default]
+    Channel,
+    /// Subscribe to keyspace notifications for the keys the events are written to
+    Keyspace,
+}
+
 pub async fn subscribe(
     client: &Redis,
-) -> Result<impl Stream<Item = Result<Event, MessageDecodeError>>> {
+    prefix: &str,
+    instance_id: Option<String>,
+    mode: RedisMode,
+) -> Result<BoxStream<'static, Result<Event, MessageDecodeError>>> {
+    match mode {
+        RedisMode::Channel => subscribe_channels(client, prefix, instance_id).await,
+        RedisMode::Keyspace => subscribe_keyspace(client, prefix, instance_id).await,
+    }
+}
+
+async fn subscribe_channels(
+    client: &Redis,
+    prefix: &str,
+    instance_id: Option<String>,
+) -> Result<BoxStream<'static, Result<Event, MessageDecodeError>>> {
     let mut pubsub = client.pubsub().await?;
-    let channels = [
-        "notify_storage_update",
-        "notify_group_membership_update",
-        "notify_user_share_created",
-        "notify_test_cookie",
-        "notify_activity",
-        "notify_notification",
-        "notify_pre_auth",
-        "notify_custom",
-        "notify_config",
-        "notify_query",
-        "notify_signal",
-    ];
+    let channels: Vec<String> = CHANNELS
+        .iter()
+        .map(|channel| format!("{}{}", prefix, channel))
+        .collect();
     for channel in channels.iter() {
-        pubsub.subscribe(*channel).await?;
+        pubsub.subscribe(channel).await?;
+    }
+    log::info!("Subscribed to redis channels {}", channels.join(", "));
+
+    let prefix = prefix.to_string();
+    Ok(Box::pin(pubsub.into_on_message().map(move |event| {
+        decode_message(
+            event.get_channel_name(),
+            &prefix,
+            event.get_payload_bytes(),
+            instance_id.as_deref(),
+        )
+    })))
+}
+
+/// Subscribe to the keyspace notifications for the event keys and read the event payload from the key
+///
+/// Requires keyspace notifications to be enabled on the redis server (`notify-keyspace-events K$`)
+async fn subscribe_keyspace(
+    client: &Redis,
+    prefix: &str,
+    instance_id: Option<String>,
+) -> Result<BoxStream<'static, Result<Event, MessageDecodeError>>> {
+    let mut pubsub = client.pubsub().await?;
+    let patterns: Vec<String> = CHANNELS
+        .iter()
+        .map(|channel| format!("__keyspace@*__:{}{}", prefix, channel))
+        .collect();
+    for pattern in patterns.iter() {
+        pubsub.psubscribe(pattern).await?;
     }
+    let connection = client.connect().await?;
+    log::info!(
+        "Subscribed to redis keyspace notifications {}",
+        patterns.join(", ")
+    );
 
-    Ok(pubsub.into_on_message().map(|event| {
-        METRICS.add_event();
-        Event::try_from(event)
-    }))
+    let keys = pubsub.into_on_message().filter_map(|message| {
+        keyspace_key(message.get_channel_name(), message.get_payload_bytes()).map(String::from)
+    });
+    let prefix = prefix.to_string();
+    let events = futures::stream::unfold(
+        (Box::pin(keys), connection),
+        move |(mut keys, mut connection)| {
+            let prefix = prefix.clone();
+            let instance_id = instance_id.clone();
+            async move {
+                loop {
+                    let key = keys.next().await?;
+                    match connection.get_optional(&key).await {
+                        Ok(Some(payload)) => {
+                            let event = decode_message(
+                                &key,
+                                &prefix,
+                                payload.as_bytes(),
+                                instance_id.as_deref(),
+                            );
+                            return Some((event, (keys, connection)));
+                        }
+                        // the key has already been removed again
+                        Ok(None) => {}
+                        Err(e) => {
+                            log::error!("Failed to read event from {}: {:#}", key, e);
+                            return None;
+                        }
+                    }
+                }
+            }
+        },
+    );
+    Ok(Box::pin(events))
+}
+
+/// Get the key a keyspace notification is about, if the key has been set
+fn keyspace_key<'a>(channel: &'a str, operation: &[u8]) -> Option<&'a str> {
+    if operation != b"set" {
+        return None;
+    }
+    let key = channel.strip_prefix("__keyspace@")?;
+    let (_database, key) = key.split_once("__:")?;
+    Some(key)
+}
+
+/// Decode an event received on the channel or key, verifying the instance id if configured
+fn decode_message(
+    name: &str,
+    prefix: &str,
+    payload: &[u8],
+    instance_id: Option<&str>,
+) -> Result<Event, MessageDecodeError> {
+    let channel = name.strip_prefix(prefix).unwrap_or_default();
+    METRICS.add_event(channel);
+    if let Some(instance_id) = instance_id {
+        if !is_from_instance(payload, instance_id) {
+            METRICS.add_foreign_event();
+            log::debug!("Ignoring event on {} from another instance", name);
+            return Err(MessageDecodeError::ForeignInstance);
+        }
+    }
+    Event::decode(channel, payload).map_err(|e| {
+        METRICS.add_malformed_event();
+        log::debug!("Malformed event on {}: {}", name, truncate_payload(payload));
+        e
+    })
+}
+
+const MAX_LOGGED_PAYLOAD: usize = 256;
+
+/// Get a printable version of the payload for logging, truncated to a sane length
+fn truncate_payload(payload: &[u8]) -> String {
+    let payload = String::from_utf8_lossy(payload);
+    match payload.char_indices().nth(MAX_LOGGED_PAYLOAD) {
+        Some((end, _)) => format!("{}...", &payload[..end]),
+        None => payload.into_owned(),
+    }
+}
+
+/// What to do with received events when the event buffer is full
+#[derive(
+    Debug, Clone, Copy, Default, PartialEq, Eq, parse_display::Display, parse_display::FromStr,
+)]
+#[display(style = "kebab-case")]
+pub enum OverflowPolicy {
+    /// Stop reading from redis until there is room in the buffer
+    #[default]
+    Block,
+    /// Drop the oldest buffered event to make room for the new one
+    DropOldest,
+}
+
+pub const DEFAULT_EVENT_BUFFER_SIZE: usize = 1024;
+
+/// Bounded buffer between the redis subscription and the event processing
+pub struct EventBuffer<T> {
+    queue: Mutex<VecDeque<T>>,
+    capacity: usize,
+    policy: OverflowPolicy,
+    readable: Notify,
+    writable: Notify,
+    closed: AtomicBool,
+}
+
+impl<T> EventBuffer<T> {
+    pub fn new(capacity: usize, policy: OverflowPolicy) -> Self {
+        let capacity = capacity.max(1);
+        EventBuffer {
+            queue: Mutex::new(VecDeque::with_capacity(capacity)),
+            capacity,
+            policy,
+            readable: Notify::new(),
+            writable: Notify::new(),
+            closed: AtomicBool::new(false),
+        }
+    }
+
+    /// Add an event to the buffer, waiting for room or dropping the oldest event when it is full
+    pub async fn push(&self, item: T) {
+        loop {
+            {
+                let mut queue = self.queue.lock().unwrap();
+                if queue.len() >= self.capacity && self.policy == OverflowPolicy::DropOldest {
+                    queue.pop_front();
+                    METRICS.add_dropped_event();
+                }
+                if queue.len() < self.capacity {
+                    queue.push_back(item);
+                    self.readable.notify_one();
+                    return;
+                }
+            }
+            self.writable.notified().await;
+        }
+    }
+
+    /// Take the oldest event from the buffer, returns `None` once the buffer is closed and empty
+    pub async fn pop(&self) -> Option<T> {
+        loop {
+            {
+                let mut queue = self.queue.lock().unwrap();
+                if let Some(item) = queue.pop_front() {
+                    self.writable.notify_one();
+                    return Some(item);
+                }
+                if self.closed.load(Ordering::SeqCst) {
+                    return None;
+                }
+            }
+            self.readable.notified().await;
+        }
+    }
+
+    /// Mark the buffer as closed, no more events will be pushed
+    pub fn close(&self) {
+        self.closed.store(true, Ordering::SeqCst);
+        self.readable.notify_one();
+    }
+}
+
+#[test]
+fn test_decode_malformed() {
+    for channel in CHANNELS {
+        for payload in [
+            &b"garbage"[..],
+            b"",
+            b"[]",
+            b"null",
+            b"{}",
+            b"{\"user\": 1}",
+            b"\xff\xfe",
+        ] {
+            assert!(
+                Event::decode(channel, payload).is_err(),
+                "{} {:?}",
+                channel,
+                payload
+            );
+        }
+    }
+    assert!(Event::decode("notify_activity", br#"{"user":"foo"}"#).is_ok());
+}
+
+#[test]
+fn test_is_from_instance() {
+    assert!(is_from_instance(
+        br#"{"user":"foo","instance":"oc123"}"#,
+        "oc123"
+    ));
+    assert!(!is_from_instance(
+        br#"{"user":"foo","instance":"oc456"}"#,
+        "oc123"
+    ));
+    assert!(!is_from_instance(br#"{"user":"foo"}"#, "oc123"));
+    assert!(!is_from_instance(b"1234", "oc123"));
+}
+
+#[test]
+fn test_decode_notification() {
+    let payload = br#"{"user":"foo","object":{"type":"chat","id":"12"},"app":"spreed","timestamp":1700000000}"#;
+    match Event::decode("notify_notification", payload).unwrap() {
+        Event::Notification(Notification { user, object }) => {
+            assert_eq!(UserId::from("foo"), user);
+            let object = object.unwrap();
+            assert_eq!("chat", object.ty);
+            assert_eq!("12", object.id);
+        }
+        event => panic!("unexpected event {}", event),
+    }
+
+    for payload in [
+        &br#"{"user":"foo"}"#[..],
+        br#"{"user":"foo","object":"chat"}"#,
+    ] {
+        match Event::decode("notify_notification", payload).unwrap() {
+            Event::Notification(Notification { object, .. }) => assert!(object.is_none()),
+            event => panic!("unexpected event {}", event),
+        }
+    }
+}
+
+#[test]
+fn test_event_id() {
+    assert_eq!("0000002a", EventId(42).to_string());
+    assert_eq!(8, EventId::new().to_string().len());
+}
+
+#[test]
+fn test_truncate_payload() {
+    assert_eq!("foo", truncate_payload(b"foo"));
+    let long = "ä".repeat(300);
+    assert_eq!(
+        format!("{}...", "ä".repeat(MAX_LOGGED_PAYLOAD)),
+        truncate_payload(long.as_bytes())
+    );
+}
+
+#[tokio::test]
+async fn test_event_buffer_drop_oldest() {
+    let buffer = EventBuffer::new(2, OverflowPolicy::DropOldest);
+    let dropped = METRICS.dropped_events();
+    for event in 1..=5 {
+        buffer.push(event).await;
+    }
+    buffer.close();
+
+    assert_eq!(dropped + 3, METRICS.dropped_events());
+    assert_eq!(Some(4), buffer.pop().await);
+    assert_eq!(Some(5), buffer.pop().await);
+    assert_eq!(None, buffer.pop().await);
+}
+
+#[tokio::test]
+async fn test_event_buffer_block() {
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    let buffer = Arc::new(EventBuffer::new(1, OverflowPolicy::Block));
+    buffer.push(1).await;
+    let push_buffer = buffer.clone();
+    let pushed = tokio::spawn(async move { push_buffer.push(2).await });
+    tokio::time::sleep(Duration::from_millis(10)).await;
+    assert!(!pushed.is_finished());
+
+    assert_eq!(Some(1), buffer.pop().await);
+    pushed.await.unwrap();
+    assert_eq!(Some(2), buffer.pop().await);
+}
+
+#[test]
+fn test_overflow_policy() {
+    assert_eq!(OverflowPolicy::DropOldest, "drop-oldest".parse().unwrap());
+    assert_eq!(OverflowPolicy::Block, "block".parse().unwrap());
+    assert!("drop".parse::<OverflowPolicy>().is_err());
+}
+
+#[test]
+fn test_keyspace_event() {
+    let key = keyspace_key("__keyspace@0__:prefix_notify_storage_update", b"set").unwrap();
+    assert_eq!("prefix_notify_storage_update", key);
+    assert!(keyspace_key("__keyspace@0__:prefix_notify_storage_update", b"del").is_none());
+    assert!(keyspace_key("notify_storage_update", b"set").is_none());
+
+    let event = decode_message(
+        key,
+        "prefix_",
+        br#"{"storage":5,"path":"foo/bar","file_id":10}"#,
+        None,
+    )
+    .unwrap();
+    match event {
+        Event::StorageUpdate(update) => {
+            assert_eq!(5, update.storage);
+            assert_eq!("foo/bar", update.path);
+        }
+        event => panic!("unexpected event {:?}", event),
+    }
 }
@@ -1,89 +1,170 @@
-use crate::config::{Bind, Config, TlsConfig};
-use crate::connection::{handle_user_socket, ActiveConnections, ConnectionOptions};
+use crate::config::{Bind, Config, SocketOwner, TlsConfig};
+use crate::connection::{
+    handle_user_socket, user_event_stream, ActiveConnections, ConnectionId, ConnectionInfo,
+    ConnectionOptions, Delivery, QueuedMessage, MAX_MESSAGE_SIZE,
+};
 pub use crate::error::Error;
-use crate::error::{SelfTestError, SocketError};
+use crate::error::{DatabaseError, SelfTestError, SocketError};
 use crate::event::{
-    Activity, Custom, Event, GroupUpdate, Notification, PreAuth, ShareCreate, StorageUpdate,
+    Activity, CredentialInvalidation, Custom, Event, EventBuffer, EventId, GroupUpdate,
+    MessageDecodeError, Notification, OverflowPolicy, PreAuth, RedisMode, ShareCreate,
+    StorageUpdate, StorageUpdateBatch, StorageUpdateMove,
+};
+use crate::message::{
+    is_control_message_type, DebounceMode, ProtocolVersion, PushMessage, UpdatedFiles,
 };
-use crate::message::{PushMessage, UpdatedFiles};
 use crate::metrics::METRICS;
+use crate::nc::Authenticator;
+use crate::proxy::forwarded_chain;
+use crate::rate_limit::{AuthRateLimiter, EventThrottle};
+use crate::reconnect::{ReconnectTokens, RECONNECT_TOKEN_TTL};
 use crate::redis::Redis;
-use crate::storage_mapping::StorageMapping;
+use crate::replay::ReplayBuffer;
+use crate::sink::{send_isolated, NotificationSink, WebhookSink};
+use crate::storage_mapping::{is_path_within, StorageMapping, UserResolver};
 pub use crate::user::UserId;
+use ::redis::RedisError;
 use ahash::RandomState;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
 use dashmap::DashMap;
 use flexi_logger::LoggerHandle;
-use futures::future::{select, Either};
+use futures::future::{join, join_all, select, Either};
 use futures::StreamExt;
 use futures::{pin_mut, FutureExt};
+use ipnet::IpNet;
+use percent_encoding::percent_decode_str;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
 use smallvec::alloc::sync::Arc;
+use socket2::{Domain, Protocol, Socket, Type};
 use sqlx::AnyPool;
+use std::collections::HashMap;
 use std::convert::Infallible;
+use std::ffi::CString;
 use std::fs;
 use std::future::Future;
-use std::net::{IpAddr, SocketAddr};
-use std::os::unix::fs::PermissionsExt;
-use std::sync::atomic::{AtomicU32, Ordering};
+use std::net::{IpAddr, Ipv6Addr, SocketAddr};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicUsize, Ordering};
 use std::time::{Duration, Instant};
-use tokio::net::UnixListener;
+use tokio::net::{TcpListener, UnixListener};
 use tokio::sync::Mutex;
 use tokio::sync::{broadcast, oneshot};
-use tokio::time::sleep;
-use tokio_stream::wrappers::UnixListenerStream;
+use tokio::time::{sleep, MissedTickBehavior};
+use tokio_stream::wrappers::{TcpListenerStream, UnixListenerStream};
 use warp::filters::addr::remote;
-use warp::{Filter, Reply};
-use warp_real_ip::get_forwarded_for;
+use warp::http::StatusCode;
+use warp::path::Tail;
+use warp::reply::Response;
+use warp::{Filter, Rejection, Reply};
 
 pub mod config;
 pub mod connection;
 pub mod error;
 pub mod event;
+pub mod logging;
 pub mod message;
 pub mod metrics;
 pub mod nc;
 mod passthru_hasher;
+pub mod proxy;
+pub mod rate_limit;
+pub mod reconnect;
 pub mod redis;
+pub mod replay;
+pub mod sink;
 pub mod storage_mapping;
 pub mod user;
 
 pub type Result<T, E = Error> = std::result::Result<T, E>;
 
+/// Number of users to send an event to before yielding to other tasks
+const FAN_OUT_BATCH_SIZE: usize = 256;
+
 pub struct App {
     connections: ActiveConnections,
-    nc_client: nc::Client,
-    storage_mapping: StorageMapping,
-    pre_auth: DashMap<String, (Instant, UserId), RandomState>,
+    nc_client: Arc<nc::Client>,
+    /// Verifies the credentials of connecting clients, the Nextcloud server by default
+    authenticator: Arc<dyn Authenticator>,
+    storage_mapping: Box<dyn UserResolver>,
+    /// Mappings for other Nextcloud instances sharing the database, by instance id
+    instance_mappings: HashMap<String, Box<dyn UserResolver>>,
+    pre_auth: DashMap<String, (Instant, String), RandomState>,
     test_cookie: AtomicU32,
     redis: Redis,
+    redis_subscriptions: AtomicUsize,
+    /// Set while the database keepalive is running, the health check then uses its result
+    database_keepalive: AtomicBool,
+    database_failing: AtomicBool,
+    /// Number of consecutive failed test cookie round-trips of the cookie watchdog
+    cookie_failures: AtomicUsize,
+    redis_prefix: String,
     log_handle: Mutex<LoggerHandle>,
     reset_tx: broadcast::Sender<()>,
     _reset_rx: broadcast::Receiver<()>,
+    admin_secret: Option<String>,
+    auth_rate_limiter: AuthRateLimiter,
+    /// Limits the number of events processed per second
+    event_throttle: EventThrottle,
+    reconnect_tokens: ReconnectTokens,
+    /// Recent messages per user, for clients catching up after reconnecting
+    replay: ReplayBuffer,
+    dry_run: bool,
+    /// Custom message types to forward, all custom messages are forwarded when empty
+    allowed_channels: Vec<String>,
+    event_buffer_size: usize,
+    event_overflow: OverflowPolicy,
+    auth_timeout: Duration,
+    max_auth_message_length: usize,
+    /// Connections without inbound frames for this long are closed, zero to disable
+    idle_timeout: Duration,
+    heartbeat_interval: Duration,
+    debounce_mode: DebounceMode,
+    redis_mode: RedisMode,
+    /// Don't accept client connections, only process the events for the metrics
+    listen_only: bool,
+    metrics_path: Option<String>,
+    /// Proxies allowed to set the client address with forwarding headers
+    trusted_proxies: Vec<IpNet>,
+    /// Maximum number of open connections, unlimited if not set
+    max_connections: Option<usize>,
+    /// Origins allowed to make cross-origin requests, any origin is allowed when empty
+    allowed_origins: Vec<String>,
+    /// Path prefixes for which no file updates are sent
+    ignore_paths: Vec<String>,
+    disabled_events: Vec<String>,
+    /// Instance id events need to carry, if validating events
+    instance_id: Option<String>,
+    /// Transports that receive messages in addition to the websocket connections
+    sinks: Vec<Box<dyn NotificationSink>>,
+    /// Treat user ids that only differ in case as the same user
+    normalize_user_ids: bool,
 }
 
 impl App {
     pub async fn new(config: Config, log_handle: LoggerHandle) -> Result<Self> {
-        let connections = ActiveConnections::default();
-        let nc_client = nc::Client::new(&config.nextcloud_url, config.allow_self_signed)?;
-        let test_cookie = AtomicU32::new(0);
-
-        let storage_mapping = StorageMapping::new(config.database, config.database_prefix).await?;
-        let pre_auth = DashMap::default();
-
-        let redis = Redis::new(config.redis)?;
-
-        let (reset_tx, reset_rx) = broadcast::channel(1);
+        let storage_mapping = StorageMapping::new(
+            config.database.clone(),
+            config.database_prefix.clone(),
+            config.database_pool_options(),
+        )
+        .await?;
+        let storage_mapping = match config.database_url_replica.clone() {
+            Some(replica) => storage_mapping.with_replica(
+                config
+                    .database_pool_options()
+                    .connect_with(replica)
+                    .await
+                    .map_err(DatabaseError::Connect)?,
+            ),
+            None => storage_mapping,
+        };
+        let allow_self_signed = config.allow_self_signed;
 
-        Ok(App {
-            connections,
-            nc_client,
-            test_cookie,
-            pre_auth,
-            storage_mapping,
-            redis,
-            log_handle: Mutex::new(log_handle),
-            reset_tx,
-            _reset_rx: reset_rx,
-        })
+        Self::with_storage_mapping(storage_mapping, config, log_handle, allow_self_signed)
     }
 
     pub async fn with_connection(
@@ -92,30 +173,120 @@ impl App {
         log_handle: LoggerHandle,
         allow_self_signed: bool,
     ) -> Result<Self> {
-        let connections = ActiveConnections::default();
-        let nc_client = nc::Client::new(&config.nextcloud_url, allow_self_signed)?;
+        let storage_mapping =
+            StorageMapping::from_connection(connection, config.database_prefix.clone());
+
+        Self::with_storage_mapping(storage_mapping, config, log_handle, allow_self_signed)
+    }
+
+    fn with_storage_mapping(
+        storage_mapping: StorageMapping,
+        config: Config,
+        log_handle: LoggerHandle,
+        allow_self_signed: bool,
+    ) -> Result<Self> {
+        let connections = ActiveConnections::with_queue_size(config.send_queue_size);
+        let nc_client = nc::Client::new(&config.nextcloud_url, allow_self_signed)?
+            .with_credential_cache(Duration::from_secs(config.credential_cache_ttl as u64));
+        let nc_client = Arc::new(nc_client);
         let test_cookie = AtomicU32::new(0);
+        let sinks = notification_sinks(&config)?;
 
-        let storage_mapping = StorageMapping::from_connection(connection, config.database_prefix);
+        let storage_mapping = storage_mapping
+            .with_slow_query_threshold(Duration::from_millis(config.slow_query_ms as u64))
+            .with_cache_jitter(config.cache_jitter)
+            .with_stale_time(Duration::from_secs(config.cache_stale_time as u64))
+            .with_retries(
+                config.database_retries,
+                Duration::from_millis(config.database_retry_backoff as u64),
+            );
         let pre_auth = DashMap::default();
 
-        let redis = Redis::new(config.redis)?;
+        let redis = Redis::new(config.redis)?.with_sharding(config.redis_sharded);
 
         let (reset_tx, reset_rx) = broadcast::channel(1);
 
         Ok(App {
             connections,
+            authenticator: nc_client.clone(),
             nc_client,
             test_cookie,
             pre_auth,
-            storage_mapping,
+            instance_mappings: instance_mappings(&storage_mapping, config.instance_prefixes),
+            storage_mapping: Box::new(storage_mapping),
             redis,
+            redis_subscriptions: AtomicUsize::new(0),
+            database_keepalive: AtomicBool::new(false),
+            database_failing: AtomicBool::new(false),
+            cookie_failures: AtomicUsize::new(0),
+            redis_prefix: config.redis_prefix,
             log_handle: Mutex::new(log_handle),
             reset_tx,
             _reset_rx: reset_rx,
+            admin_secret: config.admin_secret,
+            reconnect_tokens: ReconnectTokens::new(RECONNECT_TOKEN_TTL),
+            replay: ReplayBuffer::new(
+                config.replay_buffer_size,
+                Duration::from_secs(config.replay_retention as u64),
+            ),
+            event_throttle: EventThrottle::new(config.max_events_per_second, config.event_throttle),
+            auth_rate_limiter: AuthRateLimiter::new(
+                config.auth_rate_limit,
+                Duration::from_secs(config.auth_rate_window as u64),
+            ),
+            dry_run: config.dry_run,
+            allowed_channels: config.allowed_channels,
+            event_buffer_size: config.event_buffer_size,
+            event_overflow: config.event_overflow,
+            auth_timeout: Duration::from_secs(config.auth_timeout as u64),
+            max_auth_message_length: config.max_auth_message_length,
+            idle_timeout: Duration::from_secs(config.idle_timeout as u64),
+            heartbeat_interval: Duration::from_secs(config.heartbeat_interval as u64),
+            debounce_mode: config.debounce_mode,
+            redis_mode: config.redis_mode,
+            listen_only: config.listen_only,
+            metrics_path: config.metrics_path,
+            trusted_proxies: config.trusted_proxies,
+            max_connections: config.max_connections,
+            allowed_origins: config.allowed_origins,
+            ignore_paths: config.ignore_paths,
+            disabled_events: config.disabled_events,
+            instance_id: config.instance_id,
+            sinks,
+            normalize_user_ids: config.normalize_user_ids,
         })
     }
 
+    /// Use a different backend for resolving the users to notify
+    pub fn with_user_resolver(mut self, resolver: impl UserResolver + 'static) -> Self {
+        self.storage_mapping = Box::new(resolver);
+        self
+    }
+
+    /// Deliver messages to an additional transport besides the websocket connections
+    pub fn with_sink(mut self, sink: impl NotificationSink + 'static) -> Self {
+        self.sinks.push(Box::new(sink));
+        self
+    }
+
+    /// Get the resolver for events from the instance, events without (known) instance use the default resolver
+    fn resolver(&self, instance: Option<&str>) -> &dyn UserResolver {
+        instance
+            .and_then(|instance| self.instance_mappings.get(instance))
+            .unwrap_or(&self.storage_mapping)
+            .as_ref()
+    }
+
+    pub fn with_authenticator(mut self, authenticator: impl Authenticator + 'static) -> Self {
+        self.authenticator = Arc::new(authenticator);
+        self
+    }
+
+    /// Open a connection to redis, to check that it can be reached
+    pub async fn connect_redis(&self) -> Result<(), RedisError> {
+        self.redis.connect().await.map(|_| ())
+    }
+
     pub async fn self_test(&self) -> Result<(), SelfTestError> {
         let _ = self
             .storage_mapping
@@ -139,55 +310,281 @@ impl App {
         Ok(())
     }
 
-    async fn handle_event(&self, event: Event) {
+    /// The id used for the user by this server, ignoring case if user ids are normalized
+    fn user_id(&self, user: &UserId) -> UserId {
+        if self.normalize_user_ids {
+            user.normalized()
+        } else {
+            user.clone()
+        }
+    }
+
+    fn notify_user(&self, user: &UserId, msg: QueuedMessage) -> Delivery {
+        let user = &self.user_id(user);
+        if self.dry_run {
+            log::info!(
+                "would notify {} about {} (event {})",
+                user,
+                msg.message,
+                msg.event
+            );
+            Delivery::default()
+        } else {
+            let mut delivery = Delivery::default();
+            for sink in &self.sinks {
+                let sent = send_isolated(sink.as_ref(), user, &msg);
+                delivery.connections += sent.connections;
+                delivery.overflowed |= sent.overflowed;
+            }
+            send_isolated(&self.replay, user, &msg);
+            let sent = send_isolated(&self.connections, user, &msg);
+            delivery.connections += sent.connections;
+            delivery.overflowed |= sent.overflowed;
+            delivery
+        }
+    }
+
+    /// Send a message to a list of users, yielding between batches so large fan-outs don't hold up other events
+    ///
+    /// The message for every user is created from the index of the user in the list
+    async fn fan_out(&self, users: &[UserId], msg: impl Fn(usize) -> QueuedMessage) {
+        let started = Instant::now();
+        let mut overflowed = 0;
+        for (i, batch) in users.chunks(FAN_OUT_BATCH_SIZE).enumerate() {
+            if i > 0 {
+                tokio::task::yield_now().await;
+            }
+            for (j, user) in batch.iter().enumerate() {
+                if self
+                    .notify_user(user, msg(i * FAN_OUT_BATCH_SIZE + j))
+                    .overflowed
+                {
+                    overflowed += 1;
+                }
+            }
+        }
+        METRICS.fan_out_duration.observe(started.elapsed());
+        if overflowed > 0 {
+            log::debug!(
+                "Send queue full for {} of {} users, slow connections will be closed",
+                overflowed,
+                users.len()
+            );
+        }
+    }
+
+    /// The response for refusing a new connection, if the connection limit is reached
+    fn connection_limit_response(&self) -> Option<Response> {
+        let max_connections = self.max_connections?;
+        if self.connections.connection_count() < max_connections {
+            return None;
+        }
+        METRICS.add_rejected_connection();
+        log::warn!(
+            "Refusing new connection, connection limit of {} reached",
+            max_connections
+        );
+        Some(
+            warp::reply::with_status("Too many connections", StatusCode::SERVICE_UNAVAILABLE)
+                .into_response(),
+        )
+    }
+
+    fn is_ignored_path(&self, path: &str) -> bool {
+        self.ignore_paths
+            .iter()
+            .any(|prefix| is_path_within(path, prefix))
+    }
+
+    fn is_disabled_event(&self, event: &Event) -> bool {
+        self.disabled_events
+            .iter()
+            .any(|disabled| disabled == event.event_type())
+    }
+
+    async fn handle_event(&self, event: Event, id: EventId) {
+        if self.is_disabled_event(&event) {
+            METRICS.add_skipped_event();
+            log::debug!("Skipping disabled {} (event {})", event, id);
+            return;
+        }
+        let queued = |msg: PushMessage| QueuedMessage::new(msg, id);
         match event {
             Event::StorageUpdate(StorageUpdate {
                 storage,
                 path,
                 file_id,
+                instance,
             }) => {
+                if self.is_ignored_path(&path) {
+                    log::debug!("Ignoring update for {} (event {})", path, id);
+                    return;
+                }
                 match self
-                    .storage_mapping
-                    .get_users_for_storage_path(storage, &path)
+                    .resolver(instance.as_deref())
+                    .get_user_paths_for_storage_path(storage, &path)
+                    .await
+                {
+                    Ok(user_paths) => {
+                        log::debug!(
+                            "Resolved {} users for storage {} (event {})",
+                            user_paths.len(),
+                            storage,
+                            id
+                        );
+                        // every user gets the path as seen from their own home folder
+                        let (users, paths): (Vec<UserId>, Vec<String>) =
+                            user_paths.into_iter().unzip();
+                        self.fan_out(&users, |i| {
+                            queued(PushMessage::File(file_id.into()))
+                                .with_path(paths[i].as_str().into())
+                        })
+                        .await;
+                    }
+                    Err(e) => log::error!("{:#}", e),
+                }
+            }
+            Event::StorageUpdateBatch(StorageUpdateBatch {
+                storage,
+                mut paths,
+                instance,
+            }) => {
+                paths.retain(|path| !self.is_ignored_path(path));
+                if paths.is_empty() {
+                    log::debug!(
+                        "Ignoring update batch for storage {} (event {})",
+                        storage,
+                        id
+                    );
+                    return;
+                }
+                match self
+                    .resolver(instance.as_deref())
+                    .get_users_for_storage_paths(storage, &paths)
                     .await
                 {
                     Ok(users) => {
-                        for user in users {
-                            self.connections
-                                .send_to_user(&user, PushMessage::File(file_id.into()));
-                        }
+                        log::debug!(
+                            "Resolved {} users for {} paths in storage {} (event {})",
+                            users.len(),
+                            paths.len(),
+                            storage,
+                            id
+                        );
+                        self.fan_out(&users, |_| queued(PushMessage::File(UpdatedFiles::Unknown)))
+                            .await;
                     }
                     Err(e) => log::error!("{:#}", e),
                 }
             }
-            Event::GroupUpdate(GroupUpdate { user, .. }) => {
-                self.connections
-                    .send_to_user(&user, PushMessage::File(UpdatedFiles::Unknown));
+            Event::StorageUpdateMove(StorageUpdateMove {
+                storage,
+                from,
+                to,
+                instance,
+            }) => {
+                if self.is_ignored_path(&from) && self.is_ignored_path(&to) {
+                    log::debug!("Ignoring move from {} to {} (event {})", from, to, id);
+                    return;
+                }
+                // users with access to either the source or the destination are notified
+                let paths = [from, to];
+                match self
+                    .resolver(instance.as_deref())
+                    .get_users_for_storage_paths(storage, &paths)
+                    .await
+                {
+                    Ok(users) => {
+                        log::debug!(
+                            "Resolved {} users for move in storage {} (event {})",
+                            users.len(),
+                            storage,
+                            id
+                        );
+                        let [from, to] = paths;
+                        self.fan_out(&users, |_| {
+                            queued(PushMessage::FileMove {
+                                from: from.clone(),
+                                to: to.clone(),
+                            })
+                        })
+                        .await;
+                    }
+                    Err(e) => log::error!("{:#}", e),
+                }
+            }
+            Event::GroupUpdate(GroupUpdate {
+                user,
+                group,
+                instance,
+            }) => {
+                self.notify_user(&user, queued(PushMessage::File(UpdatedFiles::Unknown)));
+                match self
+                    .resolver(instance.as_deref())
+                    .get_users_for_group(&group)
+                    .await
+                {
+                    Ok(members) => {
+                        log::debug!(
+                            "Resolved {} members for group {} (event {})",
+                            members.len(),
+                            group,
+                            id
+                        );
+                        let user = self.user_id(&user);
+                        let members: Vec<UserId> = members
+                            .into_iter()
+                            .filter(|member| self.user_id(member) != user)
+                            .collect();
+                        self.fan_out(&members, |_| {
+                            queued(PushMessage::File(UpdatedFiles::Unknown))
+                        })
+                        .await;
+                    }
+                    Err(e) => log::error!("{:#}", e),
+                }
             }
             Event::ShareCreate(ShareCreate { user }) => {
-                self.connections
-                    .send_to_user(&user, PushMessage::File(UpdatedFiles::Unknown));
+                self.notify_user(&user, queued(PushMessage::File(UpdatedFiles::Unknown)));
             }
             Event::TestCookie(cookie) => {
                 self.test_cookie.store(cookie, Ordering::SeqCst);
             }
             Event::Activity(Activity { user }) => {
-                self.connections.send_to_user(&user, PushMessage::Activity);
+                self.notify_user(&user, queued(PushMessage::Activity));
             }
-            Event::Notification(Notification { user }) => {
-                self.connections
-                    .send_to_user(&user, PushMessage::Notification);
+            Event::Notification(Notification { user, .. }) => {
+                self.notify_user(&user, queued(PushMessage::Notification));
             }
             Event::PreAuth(PreAuth { user, token }) => {
                 self.pre_auth.insert(token, (Instant::now(), user));
             }
+            Event::CredentialInvalidation(CredentialInvalidation { user }) => {
+                self.authenticator.forget_credentials(&user);
+                self.reconnect_tokens.invalidate(&user);
+                let closed = self.connections.disconnect_user(&self.user_id(&user));
+                log::info!(
+                    "Closed {} connections for {} after credential invalidation",
+                    closed,
+                    user
+                );
+            }
             Event::Custom(Custom {
                 user,
                 message,
                 body,
             }) => {
-                self.connections
-                    .send_to_user(&user, PushMessage::Custom(message, body));
+                if self.allowed_channels.is_empty() || self.allowed_channels.contains(&message) {
+                    if is_control_message_type(&message) {
+                        log::debug!(
+                            "Custom message {} conflicts with a control message and is only sent to v1 clients",
+                            message
+                        );
+                    }
+                    self.notify_user(&user, queued(PushMessage::Custom(message, body)));
+                } else {
+                    log::debug!("Dropping custom message {} which is not allowed", message);
+                }
             }
             Event::Config(event::Config::LogSpec(spec)) => {
                 match self.log_handle.lock().await.parse_and_push_temp_spec(&spec) {
@@ -199,20 +596,23 @@ impl App {
                 self.log_handle.lock().await.pop_temp_spec();
                 log::info!("Restored log level");
             }
-            Event::Query(event::Query::Metrics) => match self.redis.connect().await {
-                Ok(mut redis) => {
-                    if let Err(e) = redis
-                        .set(
-                            "notify_push_metrics",
-                            &serde_json::to_string(&METRICS).unwrap(),
-                        )
-                        .await
-                    {
-                        log::warn!("Failed to set metrics: {}", e);
+            Event::Query(event::Query::Metrics) => {
+                let metrics = match serde_json::to_string(&METRICS) {
+                    Ok(metrics) => metrics,
+                    Err(e) => {
+                        log::warn!("Failed to serialize metrics: {}", e);
+                        return;
                     }
+                };
+                match self.redis.connect().await {
+                    Ok(mut redis) => {
+                        if let Err(e) = redis.set("notify_push_metrics", &metrics).await {
+                            log::warn!("Failed to set metrics: {}", e);
+                        }
+                    }
+                    Err(e) => log::warn!("Failed to set metrics: {}", e),
                 }
-                Err(e) => log::warn!("Failed to set metrics: {}", e),
-            },
+            }
             Event::Signal(event::Signal::Reset) => {
                 log::info!("Stopping all open connections");
                 if let Err(e) = self.reset_tx.send(()) {
@@ -225,6 +625,159 @@ impl App {
     pub fn reset_rx(&self) -> broadcast::Receiver<()> {
         self.reset_tx.subscribe()
     }
+
+    /// Check the `Authorization: Bearer <secret>` header against the configured admin secret
+    fn is_admin(&self, authorization: Option<&str>) -> bool {
+        match (&self.admin_secret, authorization) {
+            (Some(secret), Some(authorization)) => {
+                let provided = authorization.strip_prefix("Bearer ").unwrap_or_default();
+                // compare in constant time to not leak the secret through timing
+                provided.len() == secret.len()
+                    && provided
+                        .bytes()
+                        .zip(secret.bytes())
+                        .fold(0, |diff, (a, b)| diff | (a ^ b))
+                        == 0
+            }
+            _ => false,
+        }
+    }
+
+    /// Number of open websocket connections
+    pub fn connection_count(&self) -> usize {
+        self.connections.connection_count()
+    }
+
+    /// Check if all redis subscriptions are active
+    fn is_subscribed(&self) -> bool {
+        self.redis_subscriptions.load(Ordering::Relaxed) >= self.redis.sources().len()
+    }
+
+    /// Request a test cookie from nextcloud and wait for it to arrive through redis, returns the round-trip time
+    pub async fn test_cookie_round_trip(
+        &self,
+        timeout: Duration,
+    ) -> Result<Duration, SelfTestError> {
+        let deadline = Instant::now() + timeout;
+        while !self.is_subscribed() {
+            if Instant::now() > deadline {
+                return Err(SelfTestError::SubscribeTimeout);
+            }
+            sleep(Duration::from_millis(10)).await;
+        }
+
+        let start = Instant::now();
+        let expected = self.nc_client.get_test_cookie().await?;
+        loop {
+            let received = self.test_cookie.load(Ordering::SeqCst);
+            if received == expected {
+                return Ok(start.elapsed());
+            }
+            if Instant::now() > deadline {
+                return Err(SelfTestError::CookieMismatch { expected, received });
+            }
+            sleep(Duration::from_millis(10)).await;
+        }
+    }
+
+    /// Check the status of the external services the push server depends on
+    pub async fn health(&self) -> Health {
+        let mut failing = Vec::new();
+        if !self.is_subscribed() {
+            failing.push("redis");
+        }
+        if self.database_keepalive.load(Ordering::Relaxed) {
+            if self.database_failing.load(Ordering::Relaxed) {
+                failing.push("database");
+            }
+        } else if let Err(e) = self.storage_mapping.ping().await {
+            log::warn!("Database health check failed: {:#}", e);
+            failing.push("database");
+        }
+        if self.cookie_failures.load(Ordering::Relaxed) >= COOKIE_WATCHDOG_FAILURES {
+            failing.push("cookie");
+        }
+        Health { failing }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct Health {
+    pub failing: Vec<&'static str>,
+}
+
+impl Health {
+    pub fn is_healthy(&self) -> bool {
+        self.failing.is_empty()
+    }
+}
+
+impl Reply for Health {
+    fn into_response(self) -> Response {
+        let status = if self.is_healthy() {
+            StatusCode::OK
+        } else {
+            StatusCode::SERVICE_UNAVAILABLE
+        };
+        warp::reply::with_status(warp::reply::json(&self), status).into_response()
+    }
+}
+
+#[derive(Deserialize)]
+struct Broadcast {
+    message: String,
+}
+
+#[derive(Deserialize)]
+struct Disconnect {
+    user: UserId,
+    connection: ConnectionId,
+}
+
+#[derive(Deserialize)]
+struct CacheFlush {
+    storage: Option<u32>,
+}
+
+/// Version information of the running binary
+#[derive(Debug, Serialize)]
+pub struct BuildInfo {
+    pub version: &'static str,
+    /// Git commit the binary was built from, "unknown" when not built from a git checkout
+    pub commit: &'static str,
+    /// Unix timestamp of the build
+    pub build_time: u64,
+}
+
+impl BuildInfo {
+    pub fn get() -> Self {
+        BuildInfo {
+            version: env!("NOTIFY_PUSH_VERSION"),
+            commit: env!("NOTIFY_PUSH_COMMIT"),
+            build_time: env!("NOTIFY_PUSH_BUILD_TIME").parse().unwrap_or_default(),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct SseQuery {
+    /// Pre-authenticated token
+    token: Option<String>,
+}
+
+/// Get the username and password from a basic authorization header
+fn parse_basic_auth(header: &str) -> Option<(String, String)> {
+    let encoded = header.strip_prefix("Basic ")?;
+    let decoded = BASE64.decode(encoded.trim()).ok()?;
+    let (username, password) = std::str::from_utf8(&decoded).ok()?.split_once(':')?;
+    Some((username.to_string(), password.to_string()))
+}
+
+#[derive(Serialize)]
+struct UserConnections {
+    user: String,
+    count: usize,
+    connections: Vec<ConnectionInfo>,
 }
 
 pub fn serve(
@@ -235,31 +788,142 @@ pub fn serve(
     max_debounce_time: usize,
     max_connection_time: usize,
 ) -> Result<impl Future<Output = ()> + Send> {
+    let cors = warp::cors().allow_methods(["GET", "POST"]).allow_headers([
+        "authorization",
+        "content-type",
+        "sec-websocket-protocol",
+    ]);
+    let cors = if app.allowed_origins.is_empty() {
+        cors.allow_any_origin()
+    } else {
+        cors.allow_origins(app.allowed_origins.iter().map(String::as_str))
+    };
+
     let app = warp::any().map(move || app.clone());
 
-    let cors = warp::cors().allow_any_origin();
+    // in listen-only mode the client endpoints aren't served at all
+    let accept_clients = app
+        .clone()
+        .and_then(|app: Arc<App>| async move {
+            if app.listen_only {
+                Err(warp::reject::not_found())
+            } else {
+                Ok(())
+            }
+        })
+        .untuple_one();
 
     // GET /ws -> websocket upgrade
     let socket = warp::path!("ws")
+        .and(accept_clients.clone())
         // The `ws()` filter will prepare Websocket handshake...
         .and(warp::ws())
+        .map(|ws: warp::ws::Ws| ws.max_message_size(MAX_MESSAGE_SIZE))
         .and(app.clone())
         .and(remote())
-        .and(get_forwarded_for())
+        .and(warp::header::optional::<String>("x-forwarded-for"))
+        .and(warp::header::optional::<String>("x-real-ip"))
+        .and(warp::header::optional::<String>("sec-websocket-protocol"))
         .map(
             move |ws: warp::ws::Ws,
-                  app,
+                  app: Arc<App>,
                   remote: Option<SocketAddr>,
-                  mut forwarded_for: Vec<IpAddr>| {
-                if let Some(remote) = remote {
-                    forwarded_for.push(remote.ip());
+                  forwarded_for: Option<String>,
+                  real_ip: Option<String>,
+                  offered_protocols: Option<String>| {
+                if let Some(response) = app.connection_limit_response() {
+                    return response;
                 }
+                let forwarded_for = forwarded_chain(
+                    remote.map(|remote| remote.ip()),
+                    forwarded_for.as_deref(),
+                    real_ip.as_deref(),
+                    &app.trusted_proxies,
+                );
                 log::debug!("new websocket connection from {:?}", forwarded_for.first());
+                let protocol = match offered_protocols.as_deref().map(ProtocolVersion::negotiate) {
+                    Some(Some(protocol)) => Some(protocol),
+                    Some(None) => {
+                        return warp::reply::with_status(
+                            "Unsupported websocket protocol",
+                            StatusCode::BAD_REQUEST,
+                        )
+                        .into_response()
+                    }
+                    None => None,
+                };
+                let opts = ConnectionOptions::new(max_debounce_time, max_connection_time)
+                    .with_protocol(protocol.unwrap_or_default());
+                let reply = ws
+                    .on_upgrade(move |socket| handle_user_socket(socket, app, forwarded_for, opts));
+                match protocol {
+                    Some(protocol) => warp::reply::with_header(
+                        reply,
+                        "sec-websocket-protocol",
+                        protocol.to_string(),
+                    )
+                    .into_response(),
+                    None => reply.into_response(),
+                }
+            },
+        );
+
+    // GET /sse -> server-sent events
+    let sse = warp::path!("sse")
+        .and(accept_clients)
+        .and(warp::get())
+        .and(app.clone())
+        .and(remote())
+        .and(warp::header::optional::<String>("x-forwarded-for"))
+        .and(warp::header::optional::<String>("x-real-ip"))
+        .and(warp::header::optional::<String>("authorization"))
+        .and(warp::query::<SseQuery>())
+        .and_then(
+            move |app: Arc<App>,
+                  remote: Option<SocketAddr>,
+                  forwarded_for: Option<String>,
+                  real_ip: Option<String>,
+                  authorization: Option<String>,
+                  query: SseQuery| async move {
+                if let Some(response) = app.connection_limit_response() {
+                    return Result::<_, Infallible>::Ok(response);
+                }
+                let forwarded_for = forwarded_chain(
+                    remote.map(|remote| remote.ip()),
+                    forwarded_for.as_deref(),
+                    real_ip.as_deref(),
+                    &app.trusted_proxies,
+                );
+                // pre-authenticated tokens can be passed as query parameter, credentials only through the authorization header
+                let (username, password) = match (
+                    authorization.as_deref().and_then(parse_basic_auth),
+                    query.token,
+                ) {
+                    (Some(credentials), _) => credentials,
+                    (None, Some(token)) => (String::new(), token),
+                    (None, None) => {
+                        return Ok(warp::reply::with_header(
+                            StatusCode::UNAUTHORIZED,
+                            "www-authenticate",
+                            "Basic realm=\"notify_push\"",
+                        )
+                        .into_response())
+                    }
+                };
                 let opts = ConnectionOptions::new(max_debounce_time, max_connection_time);
-                ws.on_upgrade(move |socket| handle_user_socket(socket, app, forwarded_for, opts))
+                Ok(
+                    match user_event_stream(app, username, password, forwarded_for, opts).await {
+                        Ok(stream) => {
+                            warp::sse::reply(warp::sse::keep_alive().stream(stream)).into_response()
+                        }
+                        Err(e) => {
+                            log::warn!("{}", e);
+                            warp::reply::with_status(e.to_string(), e.status_code()).into_response()
+                        }
+                    },
+                )
             },
-        )
-        .with(cors);
+        );
 
     let cookie_test = warp::path!("test" / "cookie")
         .and(app.clone())
@@ -294,7 +958,7 @@ pub fn serve(
                 .get_users_for_storage_path(storage_id, "")
                 .await
                 .map(|access| {
-                    let count = access.count();
+                    let count = access.len();
                     log::debug!("storage mapping count for {} = {}", storage_id, count);
                     count
                 })
@@ -325,7 +989,7 @@ pub fn serve(
 
     let version = warp::path!("test" / "version")
         .and(warp::post())
-        .and(app)
+        .and(app.clone())
         .and_then(|app: Arc<App>| async move {
             Result::<_, Infallible>::Ok(match app.redis.connect().await {
                 Ok(mut client) => {
@@ -342,18 +1006,180 @@ pub fn serve(
             })
         });
 
-    let routes = socket
+    let health = warp::path!("health")
+        .and(app.clone())
+        .and_then(|app: Arc<App>| async move { Result::<_, Infallible>::Ok(app.health().await) });
+
+    let root = warp::path::end().and(warp::get()).map(|| {
+        warp::reply::json(&json!({
+            "name": "notify_push",
+            "version": env!("NOTIFY_PUSH_VERSION"),
+        }))
+    });
+
+    let build_info = warp::path!("version")
+        .and(warp::get())
+        .map(|| warp::reply::json(&BuildInfo::get()));
+
+    let broadcast = warp::path!("admin" / "broadcast")
+        .and(warp::post())
+        .and(app.clone())
+        .and(warp::header::optional::<String>("authorization"))
+        .and(warp::body::json())
+        .map(
+            |app: Arc<App>, authorization: Option<String>, broadcast: Broadcast| {
+                if !app.is_admin(authorization.as_deref()) {
+                    return StatusCode::FORBIDDEN;
+                }
+                log::info!("Broadcasting admin message to all connections");
+                app.connections.send_to_all(&broadcast.message);
+                StatusCode::OK
+            },
+        );
+
+    let disconnect = warp::path!("admin" / "disconnect")
+        .and(warp::post())
+        .and(app.clone())
+        .and(warp::header::optional::<String>("authorization"))
+        .and(warp::body::json())
+        .map(
+            |app: Arc<App>, authorization: Option<String>, disconnect: Disconnect| {
+                if !app.is_admin(authorization.as_deref()) {
+                    return StatusCode::FORBIDDEN;
+                }
+                if app
+                    .connections
+                    .close_connection(&disconnect.user, disconnect.connection)
+                {
+                    log::info!(
+                        "Closed connection {} for {} by admin request",
+                        disconnect.connection,
+                        disconnect.user
+                    );
+                    StatusCode::OK
+                } else {
+                    StatusCode::NOT_FOUND
+                }
+            },
+        );
+
+    let user_connections = warp::path!("connections" / String)
+        .and(warp::get())
+        .and(app.clone())
+        .and(warp::header::optional::<String>("authorization"))
+        .map(
+            |user: String, app: Arc<App>, authorization: Option<String>| {
+                if !app.is_admin(authorization.as_deref()) {
+                    return StatusCode::FORBIDDEN.into_response();
+                }
+                let user = percent_decode_str(&user).decode_utf8_lossy().into_owned();
+                let connections = app
+                    .connections
+                    .user_connections(&app.user_id(&UserId::from(user.as_str())));
+                warp::reply::json(&UserConnections {
+                    count: connections.len(),
+                    user,
+                    connections,
+                })
+                .into_response()
+            },
+        );
+
+    let cached_storages = warp::path!("cache" / "storages")
+        .and(warp::get())
+        .and(app.clone())
+        .and(warp::header::optional::<String>("authorization"))
+        .map(|app: Arc<App>, authorization: Option<String>| {
+            if !app.is_admin(authorization.as_deref()) {
+                return StatusCode::FORBIDDEN.into_response();
+            }
+            warp::reply::json(&app.storage_mapping.cached_storages()).into_response()
+        });
+
+    let cache_flush = warp::path!("cache" / "flush")
+        .and(warp::post())
+        .and(app.clone())
+        .and(warp::header::optional::<String>("authorization"))
+        .and(warp::query::<CacheFlush>())
+        .map(
+            |app: Arc<App>, authorization: Option<String>, flush: CacheFlush| {
+                if !app.is_admin(authorization.as_deref()) {
+                    return StatusCode::FORBIDDEN;
+                }
+                match flush.storage {
+                    Some(storage) => log::info!(
+                        "Flushing cached storage mapping for {} by admin request",
+                        storage
+                    ),
+                    None => log::info!("Flushing storage mapping cache by admin request"),
+                }
+                app.storage_mapping.flush_cache(flush.storage);
+                StatusCode::OK
+            },
+        );
+
+    let metrics = warp::get()
+        .and(warp::path::tail())
+        .and(app.clone())
+        .and_then(|path: Tail, app: Arc<App>| async move {
+            match &app.metrics_path {
+                Some(metrics_path) if path.as_str() == metrics_path => Ok(METRICS.render()),
+                _ => Err(warp::reject::not_found()),
+            }
+        });
+
+    let routes = root
+        .or(socket)
+        .or(sse)
+        .or(health)
+        .or(build_info)
+        .or(broadcast)
+        .or(disconnect)
+        .or(user_connections)
+        .or(cached_storages)
+        .or(cache_flush)
         .or(cookie_test)
         .or(reverse_cookie_test)
         .or(mapping_test)
         .or(remote_test)
-        .or(version);
+        .or(version)
+        .or(metrics);
 
-    let routes = routes.clone().or(warp::path!("push" / ..).and(routes));
+    let routes = routes
+        .clone()
+        .or(warp::path!("push" / ..).and(routes))
+        .recover(handle_not_found)
+        .with(cors);
 
     serve_at(routes, bind, cancel, tls)
 }
 
+/// Respond to unknown routes with a json error instead of an empty response
+async fn handle_not_found(rejection: Rejection) -> Result<impl Reply, Rejection> {
+    if rejection.is_not_found() {
+        Ok(warp::reply::with_status(
+            warp::reply::json(&json!({
+                "error": "not_found",
+                "message": "Unknown route",
+            })),
+            StatusCode::NOT_FOUND,
+        ))
+    } else {
+        Err(rejection)
+    }
+}
+
+/// Create a listener on the unspecified ipv6 address that also accepts ipv4 connections
+fn dual_stack_listener(port: u16) -> std::io::Result<TcpListener> {
+    let socket = Socket::new(Domain::IPV6, Type::STREAM, Some(Protocol::TCP))?;
+    socket.set_only_v6(false)?;
+    socket.set_reuse_address(true)?;
+    socket.set_nonblocking(true)?;
+    socket.bind(&SocketAddr::from((Ipv6Addr::UNSPECIFIED, port)).into())?;
+    socket.listen(1024)?;
+    TcpListener::from_std(socket.into())
+}
+
 fn serve_at<F, C>(
     filter: F,
     bind: Bind,
@@ -380,66 +1206,250 @@ where
             let (_, server) = server.bind_with_graceful_shutdown(addr, cancel);
             Ok(Either::Left(Either::Right(server)))
         }
-        (Bind::Unix(socket_path, permissions), tls) => {
+        (Bind::DualStack(port), _) => {
+            let listener = dual_stack_listener(port)
+                .map_err(|e| SocketError::Bind(e, format!("[::]:{}", port)))?;
+            let stream = TcpListenerStream::new(listener);
+            Ok(Either::Right(Either::Left(
+                server.serve_incoming_with_graceful_shutdown(stream, cancel),
+            )))
+        }
+        (Bind::Unix(socket_path, permissions, owner), tls) => {
             if tls.is_some() {
                 log::warn!("Serving with TLS over a unix socket is not supported");
             }
-            fs::remove_file(&socket_path).ok();
+            remove_stale_socket(&socket_path)?;
 
             let listener = UnixListener::bind(&socket_path)
                 .map_err(|e| SocketError::Bind(e, socket_path.to_string_lossy().to_string()))?;
             fs::set_permissions(&socket_path, PermissionsExt::from_mode(permissions))
                 .map_err(SocketError::SocketPermissions)?;
+            if owner != SocketOwner::default() {
+                chown(&socket_path, owner).map_err(SocketError::SocketOwner)?;
+            }
 
             let stream = UnixListenerStream::new(listener);
-            Ok(Either::Right(
+            Ok(Either::Right(Either::Right(
                 server
                     .serve_incoming_with_graceful_shutdown(stream, cancel)
                     .map(move |_| {
                         fs::remove_file(socket_path).ok();
                     }),
-            ))
+            )))
         }
     }
 }
 
-pub async fn listen_loop(app: Arc<App>, cancel: oneshot::Receiver<()>) {
-    let loop_ = async move {
-        loop {
-            if let Err(e) = listen(app.clone()).await {
-                log::error!("Failed to setup redis subscription: {:#}", e);
+/// Create the mappings for the other instances sharing the database
+fn instance_mappings(
+    storage_mapping: &StorageMapping,
+    prefixes: Vec<(String, String)>,
+) -> HashMap<String, Box<dyn UserResolver>> {
+    prefixes
+        .into_iter()
+        .map(|(instance, prefix)| {
+            log::info!("Using table prefix {} for instance {}", prefix, instance);
+            let mapping: Box<dyn UserResolver> = Box::new(storage_mapping.with_prefix(prefix));
+            (instance, mapping)
+        })
+        .collect()
+}
+
+/// Create the configured sinks, besides the websocket connections
+fn notification_sinks(config: &Config) -> Result<Vec<Box<dyn NotificationSink>>> {
+    let mut sinks: Vec<Box<dyn NotificationSink>> = Vec::new();
+    if let Some(url) = &config.webhook_url {
+        log::info!("Sending messages to webhook at {}", url);
+        sinks.push(Box::new(WebhookSink::new(url.clone())?));
+    }
+    Ok(sinks)
+}
+
+/// Change the owner and group of a file, leaving unset ids unchanged
+fn chown(path: &Path, owner: SocketOwner) -> std::io::Result<()> {
+    let path = CString::new(path.as_os_str().as_bytes())
+        .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidInput, e))?;
+    // an id of -1 leaves the owner or group unchanged
+    let uid = owner.uid.unwrap_or(u32::MAX);
+    let gid = owner.gid.unwrap_or(u32::MAX);
+    // safety: the path is a valid nul terminated string
+    if unsafe { libc::chown(path.as_ptr(), uid, gid) } == 0 {
+        Ok(())
+    } else {
+        Err(std::io::Error::last_os_error())
+    }
+}
+
+/// Remove a socket file left behind by a previous run, refusing to remove sockets that are still in use
+fn remove_stale_socket(path: &Path) -> Result<(), SocketError> {
+    let display = || path.to_string_lossy().to_string();
+    match fs::symlink_metadata(path) {
+        Ok(metadata) if metadata.file_type().is_socket() => {
+            if std::os::unix::net::UnixStream::connect(path).is_ok() {
+                return Err(SocketError::InUse(display()));
             }
-            log::warn!("Redis server disconnected, reconnecting in 1s");
-            sleep(Duration::from_secs(1)).await;
+            log::info!("Removing stale socket {}", path.display());
+            fs::remove_file(path).map_err(|e| SocketError::Bind(e, display()))
         }
-    };
-    pin_mut!(loop_);
-    select(cancel, loop_).await;
+        Ok(_) => Err(SocketError::NotASocket(display())),
+        Err(_) => Ok(()),
+    }
 }
 
-pub async fn listen(app: Arc<App>) -> Result<()> {
-    let mut event_stream = event::subscribe(&app.redis).await?;
+/// Wait for a connection to a dependency on startup, failing if it takes longer than the timeout
+///
+/// A zero timeout waits indefinitely
+pub async fn startup_connect<T, E>(
+    dependency: &'static str,
+    timeout: Duration,
+    connect: impl Future<Output = Result<T, E>>,
+) -> Result<T>
+where
+    Error: From<E>,
+{
+    if timeout.is_zero() {
+        return Ok(connect.await?);
+    }
+    match tokio::time::timeout(timeout, connect).await {
+        Ok(result) => Ok(result?),
+        Err(_) => {
+            log::error!(
+                "Timed out after {}s while connecting to the {}",
+                timeout.as_secs(),
+                dependency
+            );
+            Err(Error::StartupTimeout(dependency, timeout.as_secs()))
+        }
+    }
+}
+
+/// Periodically query the database to keep the pooled connections open and detect database outages
+pub async fn database_keepalive(app: Arc<App>, interval: Duration) {
+    app.database_keepalive.store(true, Ordering::Relaxed);
+    let mut interval = tokio::time::interval(interval);
+    loop {
+        interval.tick().await;
+        let failing = match app.storage_mapping.ping().await {
+            Ok(()) => false,
+            Err(e) => {
+                log::warn!("Database keepalive failed: {:#}", e);
+                true
+            }
+        };
+        if app.database_failing.swap(failing, Ordering::Relaxed) && !failing {
+            log::info!("Database connection restored");
+        }
+    }
+}
+
+/// Number of consecutive failed cookie round-trips before the server is reported as unhealthy
+pub const COOKIE_WATCHDOG_FAILURES: usize = 3;
+
+/// Periodically request a test cookie from Nextcloud and check that it arrives through redis
+///
+/// This detects a broken path from Nextcloud to the push server, even when the redis subscription itself is up
+pub async fn cookie_watchdog(app: Arc<App>, interval: Duration) {
+    let mut interval = tokio::time::interval(interval);
+    interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    loop {
+        interval.tick().await;
+        match app.test_cookie_round_trip(interval.period()).await {
+            Ok(_) => {
+                if app.cookie_failures.swap(0, Ordering::Relaxed) >= COOKIE_WATCHDOG_FAILURES {
+                    log::info!("Test cookies are arriving through redis again");
+                }
+            }
+            Err(e) => {
+                let failures = app.cookie_failures.fetch_add(1, Ordering::Relaxed) + 1;
+                if failures == COOKIE_WATCHDOG_FAILURES {
+                    log::error!(
+                        "Test cookies failed to arrive through redis {} times in a row, events from Nextcloud are not being received: {:#}",
+                        failures,
+                        e
+                    );
+                } else {
+                    log::warn!("Test cookie round-trip failed: {:#}", e);
+                }
+            }
+        }
+    }
+}
+
+pub async fn listen_loop(app: Arc<App>, cancel: oneshot::Receiver<()>) {
+    let loops = app
+        .redis
+        .sources()
+        .into_iter()
+        .map(|source| listen_source_loop(app.clone(), source));
+    let loops = join_all(loops);
+    pin_mut!(loops);
+    select(cancel, loops).await;
+}
+
+async fn listen_source_loop(app: Arc<App>, source: Redis) {
+    loop {
+        if let Err(e) = listen(app.clone(), &source).await {
+            log::error!("Failed to setup redis subscription: {:#}", e);
+        }
+        log::warn!("Redis server disconnected, reconnecting in 1s");
+        sleep(Duration::from_secs(1)).await;
+    }
+}
+
+pub async fn listen(app: Arc<App>, source: &Redis) -> Result<()> {
+    let mut event_stream = event::subscribe(
+        source,
+        &app.redis_prefix,
+        app.instance_id.clone(),
+        app.redis_mode,
+    )
+    .await?;
+    app.redis_subscriptions.fetch_add(1, Ordering::Relaxed);
+    let source_name = source.source_name();
 
-    let handle = move |event: Event| {
+    let handle_app = app.clone();
+    let handle = move |event: Event, id: EventId| {
         // todo: any way to do this without cloning the arc every event (scoped?)
-        let app = app.clone();
+        let app = handle_app.clone();
         async move {
-            app.handle_event(event).await;
+            app.handle_event(event, id).await;
         }
     };
 
-    while let Some(event) = event_stream.next().await {
-        match event {
-            Ok(event) => {
+    let buffer = EventBuffer::new(app.event_buffer_size, app.event_overflow);
+    let receive = async {
+        while let Some(event) = event_stream.next().await {
+            METRICS.add_source_event(&source_name);
+            match event {
+                Ok(event) => {
+                    let id = EventId::new();
+                    log::debug!(
+                        target: "notify_push::receive",
+                        "Received {} (event {})",
+                        event,
+                        id
+                    );
+                    buffer.push((event, id)).await;
+                }
+                Err(MessageDecodeError::ForeignInstance) => {}
+                Err(e) => log::warn!("{:#}", e),
+            }
+        }
+        buffer.close();
+    };
+    let process = async {
+        while let Some((event, id)) = buffer.pop().await {
+            if !app.event_throttle.acquire().await {
                 log::debug!(
-                    target: "notify_push::receive",
-                    "Received {}",
-                    event
+                    "Dropping event {} exceeding the maximum events per second",
+                    id
                 );
-                tokio::spawn(handle(event));
+                continue;
             }
-            Err(e) => log::warn!("{:#}", e),
+            tokio::spawn(handle(event, id));
         }
-    }
+    };
+    join(receive, process).await;
+    app.redis_subscriptions.fetch_sub(1, Ordering::Relaxed);
     Ok(())
 }
@@ -0,0 +1,101 @@
+use flexi_logger::{DeferredNow, FlexiLoggerError, LogSpecification};
+use log::Record;
+use serde::Serialize;
+use std::io::Write;
+
+/// Build the log specification from the configured log level
+///
+/// A non-empty `RUST_LOG` overrides the configured level to allow enabling debug logging without changing the config.
+pub fn log_spec(
+    log_level: &str,
+    rust_log: Option<&str>,
+) -> Result<LogSpecification, FlexiLoggerError> {
+    match rust_log {
+        Some(spec) if !spec.trim().is_empty() => LogSpecification::parse(spec),
+        _ => LogSpecification::parse(log_level),
+    }
+}
+
+#[derive(Serialize)]
+struct JsonLine<'a> {
+    timestamp: String,
+    level: &'a str,
+    target: &'a str,
+    message: String,
+}
+
+/// Log formatter that writes every log record as a single line of json
+pub fn json_format(
+    w: &mut dyn Write,
+    now: &mut DeferredNow,
+    record: &Record,
+) -> Result<(), std::io::Error> {
+    let line = JsonLine {
+        timestamp: now.format_rfc3339(),
+        level: record.level().as_str(),
+        target: record.target(),
+        message: record.args().to_string(),
+    };
+    serde_json::to_writer(w, &line)?;
+    Ok(())
+}
+
+#[cfg(test)]
+fn level_for(spec: &LogSpecification, target: &str) -> log::LevelFilter {
+    spec.module_filters()
+        .iter()
+        .filter(|filter| match &filter.module_name {
+            Some(module) => target.starts_with(module.as_str()),
+            None => true,
+        })
+        .max_by_key(|filter| filter.module_name.as_ref().map(String::len))
+        .map(|filter| filter.level_filter)
+        .unwrap_or(log::LevelFilter::Off)
+}
+
+#[test]
+fn test_log_spec() {
+    let spec = log_spec("debug", None).unwrap();
+    assert_eq!(log::LevelFilter::Debug, level_for(&spec, "notify_push"));
+    assert_eq!(
+        log::LevelFilter::Debug,
+        level_for(&spec, "notify_push::receive")
+    );
+
+    let spec = log_spec("warn,notify_push::receive=debug", None).unwrap();
+    assert_eq!(log::LevelFilter::Warn, level_for(&spec, "notify_push"));
+    assert_eq!(
+        log::LevelFilter::Debug,
+        level_for(&spec, "notify_push::receive")
+    );
+
+    let spec = log_spec("warn", Some("trace")).unwrap();
+    assert_eq!(log::LevelFilter::Trace, level_for(&spec, "notify_push"));
+
+    let spec = log_spec("warn", Some("")).unwrap();
+    assert_eq!(log::LevelFilter::Warn, level_for(&spec, "notify_push"));
+
+    assert!(log_spec("warn,notify_push=nonsense", None).is_err());
+}
+
+#[test]
+fn test_json_format() {
+    let mut output = Vec::new();
+    json_format(
+        &mut output,
+        &mut DeferredNow::new(),
+        &Record::builder()
+            .args(format_args!("Sending \"notify_file\" to {}", "foo"))
+            .level(log::Level::Debug)
+            .target("notify_push::send")
+            .build(),
+    )
+    .unwrap();
+
+    let line: serde_json::Value = serde_json::from_slice(&output).unwrap();
+    assert_eq!("DEBUG", line["level"]);
+    assert_eq!("notify_push::send", line["target"]);
+    assert_eq!("Sending \"notify_file\" to foo", line["message"]);
+    assert!(line["timestamp"].is_string());
+    assert!(!output.contains(&b'\n'));
+}
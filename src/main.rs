@@ -1,12 +1,18 @@
 use flexi_logger::{detailed_format, AdaptiveFormat, Logger, LoggerHandle};
-use miette::{IntoDiagnostic, Result, WrapErr};
-use notify_push::config::{Config, Opt};
+use miette::{miette, IntoDiagnostic, Result, WrapErr};
+use notify_push::config::{env_help, Config, LogFormat, Opt};
 use notify_push::error::ConfigError;
+use notify_push::logging::{json_format, log_spec};
 use notify_push::message::DEBOUNCE_ENABLE;
-use notify_push::metrics::serve_metrics;
-use notify_push::{listen_loop, serve, App, Error};
+use notify_push::metrics::{serve_metrics, METRICS};
+use notify_push::redis::Redis;
+use notify_push::storage_mapping::StorageMapping;
+use notify_push::{
+    cookie_watchdog, database_keepalive, listen_loop, serve, startup_connect, App, Error,
+};
 use std::sync::atomic::Ordering;
 use std::sync::Arc;
+use std::time::Duration;
 use structopt::StructOpt;
 use tokio::select;
 use tokio::signal::unix::{signal, SignalKind};
@@ -22,7 +28,14 @@ fn main() -> Result<()> {
         println!("notify_push {}", env!("NOTIFY_PUSH_VERSION"));
         return Ok(());
     }
+    if opt.help_env {
+        print!("{}", env_help());
+        return Ok(());
+    }
     let dump_config = opt.dump_config;
+    let check = opt.check;
+    let self_test = opt.self_test;
+    let print_metrics_once = opt.print_metrics_once;
     let config = Config::from_opt(opt)?;
 
     if dump_config {
@@ -30,38 +43,190 @@ fn main() -> Result<()> {
         return Ok(());
     }
 
+    if check {
+        return tokio::runtime::Builder::new_multi_thread()
+            .enable_all()
+            .build()
+            .unwrap()
+            .block_on(check_config(config));
+    }
+
+    if print_metrics_once {
+        return tokio::runtime::Builder::new_multi_thread()
+            .enable_all()
+            .build()
+            .unwrap()
+            .block_on(print_metrics(config));
+    }
+
     // initialize the logger before starting the tokio runtime
     // this prevents potential issues around getting the local time offset
     // which isn't properly tread safe on linux
-    let log_handle = Logger::try_with_str(&config.log_level)
-        .map_err(ConfigError::LogLevel)?
-        .log_to_stdout();
-    let log_handle = if config.no_ansi {
-        log_handle.format_for_stdout(detailed_format)
-    } else {
-        log_handle.adaptive_format_for_stdout(AdaptiveFormat::Detailed)
+    let log_spec = log_spec(&config.log_level, std::env::var("RUST_LOG").ok().as_deref())
+        .map_err(ConfigError::LogLevel)?;
+    let log_handle = Logger::with(log_spec).log_to_stdout();
+    let log_handle = match (config.log_format, config.no_ansi) {
+        (LogFormat::Json, _) => log_handle.format_for_stdout(json_format),
+        (LogFormat::Text, true) => log_handle.format_for_stdout(detailed_format),
+        (LogFormat::Text, false) => log_handle.adaptive_format_for_stdout(AdaptiveFormat::Detailed),
     }
     .start()
     .into_diagnostic()
     .wrap_err("Failed to initialize log handler")?;
 
-    tokio::runtime::Builder::new_multi_thread()
+    let runtime = tokio::runtime::Builder::new_multi_thread()
         .enable_all()
         .build()
-        .unwrap()
-        .block_on(run(config, log_handle))?;
+        .unwrap();
+    if self_test {
+        return runtime.block_on(run_self_test(config, log_handle));
+    }
+    runtime.block_on(run(config, log_handle))?;
     Ok(())
 }
 
+/// Check that all dependencies can be reached with the provided configuration
+async fn check_config(config: Config) -> Result<()> {
+    let mut success = true;
+
+    let database = async {
+        StorageMapping::new(
+            config.database.clone(),
+            config.database_prefix.clone(),
+            config.database_pool_options(),
+        )
+        .await?
+        .get_users_for_storage_path(1, "")
+        .await
+        .map(|_| ())
+    };
+    success &= report_check("database", database.await);
+
+    let redis = async {
+        Redis::new(config.redis)?
+            .with_sharding(config.redis_sharded)
+            .connect()
+            .await?;
+        Result::<_, Error>::Ok(())
+    };
+    success &= report_check("redis", redis.await);
+
+    if success {
+        Ok(())
+    } else {
+        Err(miette!("Configuration check failed"))
+    }
+}
+
+/// Probe the database and redis once and print the collected metrics
+async fn print_metrics(config: Config) -> Result<()> {
+    let probe = async {
+        StorageMapping::new(
+            config.database.clone(),
+            config.database_prefix.clone(),
+            config.database_pool_options(),
+        )
+        .await?
+        .get_users_for_storage_path(1, "")
+        .await
+        .map(|_| ())?;
+        Redis::new(config.redis)?
+            .with_sharding(config.redis_sharded)
+            .connect()
+            .await?;
+        Result::<_, Error>::Ok(())
+    };
+    probe.await?;
+
+    print!("{}", METRICS.render());
+    Ok(())
+}
+
+async fn log_stats(app: Arc<App>, interval: Duration) {
+    let mut interval = tokio::time::interval(interval);
+    loop {
+        interval.tick().await;
+        log::info!(
+            "{} active connections for {} users",
+            app.connection_count(),
+            METRICS.active_user_count()
+        );
+    }
+}
+
+/// Run the self test and verify that events from nextcloud are received through redis
+async fn run_self_test(config: Config, log_handle: LoggerHandle) -> Result<()> {
+    let app = Arc::new(connect(config, log_handle).await?);
+    let (listen_cancel, listen_cancel_handle) = oneshot::channel();
+    spawn(listen_loop(app.clone(), listen_cancel_handle));
+
+    let mut success = report_check("self test", app.self_test().await);
+    match app.test_cookie_round_trip(Duration::from_secs(10)).await {
+        Ok(time) => println!("cookie round-trip: ok ({}ms)", time.as_millis()),
+        Err(e) => success &= report_check("cookie round-trip", Err(e)),
+    }
+    listen_cancel.send(()).ok();
+
+    if success {
+        Ok(())
+    } else {
+        Err(miette!("Self test failed"))
+    }
+}
+
+fn report_check<E: std::error::Error>(name: &str, result: Result<(), E>) -> bool {
+    match result {
+        Ok(()) => {
+            println!("{}: ok", name);
+            true
+        }
+        Err(e) => {
+            let mut message = e.to_string();
+            let mut source = e.source();
+            while let Some(error) = source {
+                message.push_str(": ");
+                message.push_str(&error.to_string());
+                source = error.source();
+            }
+            println!("{}: failed: {}", name, message);
+            false
+        }
+    }
+}
+
+/// Setup the app and check that redis can be reached, within the configured startup timeout
+async fn connect(config: Config, log_handle: LoggerHandle) -> Result<App, Error> {
+    let timeout = Duration::from_secs(config.startup_timeout as u64);
+    let app = startup_connect("database", timeout, App::new(config, log_handle)).await?;
+    // redis errors aren't fatal since the subscription keeps retrying, only a hanging connection is
+    match startup_connect("redis", timeout, app.connect_redis()).await {
+        Err(e @ Error::StartupTimeout(..)) => return Err(e),
+        Err(e) => log::warn!("Failed to connect to redis: {:#}", e),
+        Ok(()) => {}
+    }
+    Ok(app)
+}
+
 async fn run(config: Config, log_handle: LoggerHandle) -> Result<()> {
     let (serve_cancel, serve_cancel_handle) = oneshot::channel();
     let (metrics_cancel, metrics_cancel_handle) = oneshot::channel();
     let (listen_cancel, listen_cancel_handle) = oneshot::channel();
 
+    log::info!("Starting push server: {}", config.summary());
     log::trace!("Running with config: {:?}", config);
 
     if config.allow_self_signed {
-        log::info!("Running with certificate validation disabled");
+        log::warn!("Running with certificate validation disabled, connections to the Nextcloud server are not protected against interception");
+    }
+
+    if config.dry_run {
+        log::warn!(
+            "Running in dry-run mode, notifications are logged instead of sent to the clients"
+        );
+    }
+
+    if config.listen_only {
+        log::warn!("Running in listen-only mode, no client connections are accepted");
     }
 
     if dotenv::var("DEBOUNCE_DISABLE").is_ok() {
@@ -73,7 +238,10 @@ async fn run(config: Config, log_handle: LoggerHandle) -> Result<()> {
     let metrics_bind = config.metrics_bind.clone();
     let max_debounce_time = config.max_debounce_time;
     let max_connection_time = config.max_connection_time;
-    let app = Arc::new(App::new(config, log_handle).await?);
+    let stats_interval = config.stats_interval;
+    let keepalive_interval = config.database_keepalive;
+    let cookie_watchdog_interval = config.cookie_watchdog;
+    let app = Arc::new(connect(config, log_handle).await?);
     if let Err(e) = app.self_test().await {
         log::error!("Self test failed: {:#}", e);
     }
@@ -85,7 +253,7 @@ async fn run(config: Config, log_handle: LoggerHandle) -> Result<()> {
         serve_cancel_handle,
         tls.as_ref(),
         max_debounce_time,
-        max_connection_time
+        max_connection_time,
     )?);
 
     if let Some(metrics_bind) = metrics_bind {
@@ -97,6 +265,27 @@ async fn run(config: Config, log_handle: LoggerHandle) -> Result<()> {
         )?);
     }
 
+    if stats_interval > 0 {
+        spawn(log_stats(
+            app.clone(),
+            Duration::from_secs(stats_interval as u64),
+        ));
+    }
+
+    if keepalive_interval > 0 {
+        spawn(database_keepalive(
+            app.clone(),
+            Duration::from_secs(keepalive_interval as u64),
+        ));
+    }
+
+    if cookie_watchdog_interval > 0 {
+        spawn(cookie_watchdog(
+            app.clone(),
+            Duration::from_secs(cookie_watchdog_interval as u64),
+        ));
+    }
+
     spawn(listen_loop(app, listen_cancel_handle));
 
     // wait for either a sigint or sigterm
@@ -121,5 +310,7 @@ async fn run(config: Config, log_handle: LoggerHandle) -> Result<()> {
         .into_diagnostic()
         .wrap_err("Error while running warp server")?;
 
+    log::info!("shutdown complete, {}", METRICS.summary());
+
     Ok(())
 }
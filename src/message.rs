@@ -1,6 +1,7 @@
 use crate::connection::ConnectionOptions;
-use parse_display::Display;
-use serde_json::Value;
+use crate::error::AuthenticationError;
+use parse_display::{Display, FromStr};
+use serde_json::{json, Value};
 use smallvec::{smallvec, SmallVec};
 use std::cmp::{max, min};
 use std::fmt::Write;
@@ -36,6 +37,107 @@ impl From<u64> for UpdatedFiles {
     }
 }
 
+/// Version of the message format, negotiated using the websocket subprotocol
+#[derive(Debug, Clone, Copy, Default, Display, FromStr, PartialEq, Eq)]
+pub enum ProtocolVersion {
+    /// Plain text messages
+    #[default]
+    #[display("notify_push.v1")]
+    V1,
+    /// Json encoded messages
+    #[display("notify_push.v2")]
+    V2,
+}
+
+impl ProtocolVersion {
+    /// Select the newest supported version from the comma separated list of offered protocols
+    pub fn negotiate(offered: &str) -> Option<Self> {
+        offered
+            .split(',')
+            .filter_map(|protocol| protocol.trim().parse().ok())
+            .max_by_key(|version| match version {
+                ProtocolVersion::V1 => 1,
+                ProtocolVersion::V2 => 2,
+            })
+    }
+
+    /// The message acknowledging a successful authentication, the reconnect token is only included for v2 clients
+    pub fn authenticated_message(self, user: &str, reconnect_token: &str) -> Message {
+        match self {
+            ProtocolVersion::V1 => Message::text("authenticated"),
+            ProtocolVersion::V2 => Message::text(
+                json!({"type": "authenticated", "user": user, "reconnect_token": reconnect_token})
+                    .to_string(),
+            ),
+        }
+    }
+
+    /// The message sent when resuming a session failed and the client needs to send its credentials
+    pub fn resume_failed_message(self) -> Message {
+        match self {
+            ProtocolVersion::V1 => Message::text("resume failed"),
+            ProtocolVersion::V2 => Message::text(json!({"type": "resume_failed"}).to_string()),
+        }
+    }
+
+    /// The message telling the client the sequence number of the replay buffer it is caught up to
+    pub fn seq_message(self, seq: u64) -> Message {
+        match self {
+            ProtocolVersion::V1 => Message::text(format!("seq {}", seq)),
+            ProtocolVersion::V2 => Message::text(json!({"type": "seq", "seq": seq}).to_string()),
+        }
+    }
+
+    /// The message sent before the connection is closed because of an error
+    pub fn error_message(self, error: &AuthenticationError) -> Message {
+        match self {
+            // timeouts and connection limits have always been sent without the "err: " prefix to v1 clients
+            ProtocolVersion::V1 => match error {
+                AuthenticationError::Timeout | AuthenticationError::LimitExceeded => {
+                    Message::text(error.to_string())
+                }
+                _ => Message::text(format!("err: {}", error)),
+            },
+            ProtocolVersion::V2 => Message::text(
+                json!({"type": "error", "reason": error.reason(), "message": error.to_string()})
+                    .to_string(),
+            ),
+        }
+    }
+
+    /// Check if the message can be sent to clients using this protocol,
+    /// v2 clients don't receive custom messages they could mistake for control messages
+    pub fn can_send(self, message: &PushMessage) -> bool {
+        match (self, message) {
+            (ProtocolVersion::V2, PushMessage::Custom(ty, _)) => !is_control_message_type(ty),
+            _ => true,
+        }
+    }
+
+    /// The periodic heartbeat message sent when heartbeats are enabled
+    pub fn heartbeat_message(self) -> Message {
+        match self {
+            ProtocolVersion::V1 => Message::text("heartbeat"),
+            ProtocolVersion::V2 => Message::text(json!({"type": "heartbeat"}).to_string()),
+        }
+    }
+}
+
+/// Message types used by the push server itself, custom messages with these types are not forwarded to v2 clients
+/// so they can't confuse them with control messages
+pub const CONTROL_MESSAGE_TYPES: &[&str] = &[
+    "authenticated",
+    "resume_failed",
+    "heartbeat",
+    "seq",
+    "error",
+];
+
+/// Check if a custom message type could be mistaken for a control message
+pub fn is_control_message_type(ty: &str) -> bool {
+    CONTROL_MESSAGE_TYPES.contains(&ty) || ty.starts_with("err:")
+}
+
 #[derive(Debug, Clone, Display, PartialEq)]
 pub enum PushMessage {
     #[display("notify_file")]
@@ -44,6 +146,8 @@ pub enum PushMessage {
     Activity,
     #[display("notify_notification")]
     Notification,
+    #[display("file_move")]
+    FileMove { from: String, to: String },
     #[display("{0}")]
     Custom(String, Box<Value>),
 }
@@ -63,13 +167,20 @@ impl PushMessage {
             PushMessage::File(_) => Duration::from_secs(time as u64),
             PushMessage::Activity => Duration::from_secs(time as u64),
             PushMessage::Notification => Duration::from_secs(1),
-            PushMessage::Custom(..) => Duration::from_millis(1), // no debouncing for custom messages
+            // no debouncing for moves and custom messages
+            PushMessage::FileMove { .. } | PushMessage::Custom(..) => Duration::from_millis(1),
         }
     }
 }
 
 impl PushMessage {
     pub fn into_message(self, opts: &ConnectionOptions) -> Message {
+        if opts.protocol == ProtocolVersion::V2 {
+            return Message::text(
+                self.into_json(opts.listen_file_id.load(Ordering::Relaxed))
+                    .to_string(),
+            );
+        }
         match self {
             PushMessage::File(ids) => match ids {
                 UpdatedFiles::Known(ids) if opts.listen_file_id.load(Ordering::Relaxed) => {
@@ -82,6 +193,8 @@ impl PushMessage {
             },
             PushMessage::Activity => Message::text(String::from("notify_activity")),
             PushMessage::Notification => Message::text(String::from("notify_notification")),
+            // v1 clients don't know about moves and treat it like any other file update
+            PushMessage::FileMove { .. } => Message::text(String::from("notify_file")),
             PushMessage::Custom(ty, body) => Message::text({
                 if *body == Value::Null {
                     ty
@@ -95,8 +208,38 @@ impl PushMessage {
     }
 }
 
+impl PushMessage {
+    /// The json representation of the message, as sent to v2 clients
+    pub fn into_json(self, with_file_ids: bool) -> Value {
+        match self {
+            PushMessage::File(UpdatedFiles::Known(ids)) if with_file_ids => {
+                json!({"type": "notify_file_id", "file_ids": ids})
+            }
+            PushMessage::FileMove { from, to } => {
+                json!({"type": "file_move", "from": from, "to": to})
+            }
+            PushMessage::Custom(ty, body) if *body == Value::Null => json!({ "type": ty }),
+            PushMessage::Custom(ty, body) => json!({"type": ty, "body": body}),
+            message => json!({"type": message.to_string()}),
+        }
+    }
+}
+
 pub static DEBOUNCE_ENABLE: AtomicBool = AtomicBool::new(true);
 
+/// Which messages of a burst are sent when debouncing
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Display, FromStr)]
+#[display(style = "kebab-case")]
+pub enum DebounceMode {
+    /// Send the first message of a burst immediately and drop the rest of the burst
+    Leading,
+    /// Merge all messages of a burst and send them once the debounce time has passed
+    #[default]
+    Trailing,
+    /// Send the first message of a burst immediately and the merged rest once the debounce time has passed
+    Both,
+}
+
 #[derive(Clone, Debug)]
 struct SendQueueItem {
     received: Instant,
@@ -117,6 +260,7 @@ impl Default for SendQueueItem {
 #[derive(Default, Debug)]
 pub struct SendQueue {
     items: [SendQueueItem; 3],
+    mode: DebounceMode,
 }
 
 impl SendQueue {
@@ -124,24 +268,49 @@ impl SendQueue {
         SendQueue::default()
     }
 
+    pub fn with_mode(mode: DebounceMode) -> Self {
+        SendQueue {
+            mode,
+            ..SendQueue::default()
+        }
+    }
+
     fn item_mut(&mut self, message: &PushMessage) -> Option<&mut SendQueueItem> {
         match message {
             PushMessage::File(_) => Some(&mut self.items[0]),
             PushMessage::Activity => Some(&mut self.items[1]),
             PushMessage::Notification => Some(&mut self.items[2]),
-            PushMessage::Custom(_, _) => None,
+            PushMessage::FileMove { .. } | PushMessage::Custom(_, _) => None,
         }
     }
 
-    pub fn push(&mut self, message: PushMessage, time: Instant) -> Option<PushMessage> {
+    pub fn push(
+        &mut self,
+        message: PushMessage,
+        time: Instant,
+        connection_count: usize,
+        max_debounce_time: usize,
+    ) -> Option<PushMessage> {
         if !DEBOUNCE_ENABLE.load(Ordering::Relaxed) {
             return Some(message);
         }
+        let mode = self.mode;
         let item = match self.item_mut(&message) {
             Some(item) => item,
             None => return Some(message),
         };
 
+        if mode != DebounceMode::Trailing {
+            let debounce_time = message.debounce_time(connection_count, max_debounce_time);
+            if item.message.is_none() && time.duration_since(item.sent) > debounce_time {
+                item.sent = time;
+                return Some(message);
+            }
+            if mode == DebounceMode::Leading {
+                return None;
+            }
+        }
+
         match &mut item.message {
             Some(queued) => {
                 queued.merge(&message);
@@ -180,18 +349,70 @@ impl SendQueue {
     }
 }
 
+#[test]
+fn test_negotiate_protocol() {
+    assert_eq!(
+        Some(ProtocolVersion::V2),
+        ProtocolVersion::negotiate("notify_push.v1, notify_push.v2")
+    );
+    assert_eq!(
+        Some(ProtocolVersion::V2),
+        ProtocolVersion::negotiate("notify_push.v2,notify_push.v1")
+    );
+    assert_eq!(
+        Some(ProtocolVersion::V1),
+        ProtocolVersion::negotiate("other, notify_push.v1")
+    );
+    assert_eq!(None, ProtocolVersion::negotiate("other"));
+}
+
+#[test]
+fn test_authenticated_message() {
+    assert_eq!(
+        Message::text("authenticated"),
+        ProtocolVersion::V1.authenticated_message("foo", "token")
+    );
+    assert_eq!(
+        Message::text(r#"{"reconnect_token":"token","type":"authenticated","user":"foo"}"#),
+        ProtocolVersion::V2.authenticated_message("foo", "token")
+    );
+}
+
+#[test]
+fn test_json_message() {
+    let opts = ConnectionOptions::default().with_protocol(ProtocolVersion::V2);
+    let file = PushMessage::File(UpdatedFiles::Known(vec![1, 2].into()));
+    assert_eq!(
+        Message::text(r#"{"type":"notify_file"}"#),
+        file.clone().into_message(&opts)
+    );
+    opts.listen_file_id.store(true, Ordering::Relaxed);
+    assert_eq!(
+        Message::text(r#"{"file_ids":[1,2],"type":"notify_file_id"}"#),
+        file.into_message(&opts)
+    );
+    assert_eq!(
+        Message::text(r#"{"body":{"foo":1},"type":"custom"}"#),
+        PushMessage::Custom("custom".into(), Box::new(json!({"foo": 1}))).into_message(&opts)
+    );
+}
+
 #[test]
 fn test_send_queue_100() {
     let base_time = Instant::now();
     let mut queue = SendQueue::new();
-    queue.push(PushMessage::Activity, base_time);
+    queue.push(PushMessage::Activity, base_time, 100, 15);
     queue.push(
         PushMessage::File(UpdatedFiles::Known(vec![1].into())),
         base_time,
+        100,
+        15,
     );
     queue.push(
         PushMessage::File(UpdatedFiles::Known(vec![2].into())),
         base_time + Duration::from_millis(10),
+        100,
+        15,
     );
 
     // within 100ms the messages get merged
@@ -217,10 +438,14 @@ fn test_send_queue_100() {
     queue.push(
         PushMessage::File(UpdatedFiles::Known(vec![3].into())),
         base_time + Duration::from_secs(5),
+        100,
+        15,
     );
     queue.push(
         PushMessage::File(UpdatedFiles::Known(vec![4].into())),
         base_time + Duration::from_secs(6),
+        100,
+        15,
     );
     assert_eq!(
         Vec::<PushMessage>::new(),
@@ -250,14 +475,18 @@ fn test_send_queue_100() {
 fn test_send_queue_1() {
     let base_time = Instant::now();
     let mut queue = SendQueue::new();
-    queue.push(PushMessage::Activity, base_time);
+    queue.push(PushMessage::Activity, base_time, 1, 15);
     queue.push(
         PushMessage::File(UpdatedFiles::Known(vec![1].into())),
         base_time,
+        1,
+        15,
     );
     queue.push(
         PushMessage::File(UpdatedFiles::Known(vec![2].into())),
         base_time + Duration::from_millis(10),
+        1,
+        15,
     );
 
     // within 100ms the messages get merged
@@ -283,10 +512,14 @@ fn test_send_queue_1() {
     queue.push(
         PushMessage::File(UpdatedFiles::Known(vec![3].into())),
         base_time + Duration::from_secs_f32(1.2),
+        1,
+        15,
     );
     queue.push(
         PushMessage::File(UpdatedFiles::Known(vec![4].into())),
         base_time + Duration::from_secs_f32(1.3),
+        1,
+        15,
     );
     assert_eq!(
         Vec::<PushMessage>::new(),
@@ -311,3 +544,89 @@ fn test_send_queue_1() {
             .collect::<Vec<_>>()
     );
 }
+
+#[cfg(test)]
+fn send_burst(mode: DebounceMode) -> Vec<(u64, PushMessage)> {
+    // a burst of updates every 100ms for 2 seconds, with the queue being drained every 50ms
+    let base_time = Instant::now();
+    let mut queue = SendQueue::with_mode(mode);
+    let mut sent = Vec::new();
+    for ms in (0..5000).step_by(50) {
+        let now = base_time + Duration::from_millis(ms);
+        if ms < 2000 && ms % 100 == 0 {
+            let message = PushMessage::File(UpdatedFiles::Known(vec![ms].into()));
+            if let Some(message) = queue.push(message, now, 1, 15) {
+                sent.push((ms, message));
+            }
+        }
+        sent.extend(queue.drain(now, 1, 15).map(|message| (ms, message)));
+    }
+    sent
+}
+
+#[test]
+fn test_debounce_trailing() {
+    let sent = send_burst(DebounceMode::Trailing);
+    // nothing is sent until the burst is over
+    assert_eq!(1, sent.len());
+    assert!(sent[0].0 > 1900);
+    assert_eq!(
+        PushMessage::File(UpdatedFiles::Known((0..20).map(|i| i * 100).collect())),
+        sent[0].1
+    );
+}
+
+#[test]
+fn test_debounce_leading() {
+    let sent = send_burst(DebounceMode::Leading);
+    // the first message is sent immediately, the rest of the burst is dropped until the debounce time has passed
+    assert_eq!(
+        vec![
+            (0, PushMessage::File(UpdatedFiles::Known(vec![0].into()))),
+            (
+                1100,
+                PushMessage::File(UpdatedFiles::Known(vec![1100].into()))
+            ),
+        ],
+        sent
+    );
+}
+
+#[test]
+fn test_debounce_both() {
+    let sent = send_burst(DebounceMode::Both);
+    assert_eq!(2, sent.len());
+    assert_eq!(
+        (0, PushMessage::File(UpdatedFiles::Known(vec![0].into()))),
+        sent[0]
+    );
+    // the rest of the burst is merged and sent after the burst
+    assert!(sent[1].0 > 1900);
+    assert_eq!(
+        PushMessage::File(UpdatedFiles::Known((1..20).map(|i| i * 100).collect())),
+        sent[1].1
+    );
+}
+
+#[test]
+fn test_error_message_type() {
+    let error = ProtocolVersion::V2.error_message(&AuthenticationError::Invalid);
+    let error: Value = serde_json::from_str(error.to_str().unwrap()).unwrap();
+    assert_eq!("error", error["type"]);
+
+    let data = PushMessage::Custom("calendar_update".into(), Box::default()).into_json(false);
+    assert_ne!(error["type"], data["type"]);
+
+    assert!(is_control_message_type("error"));
+    assert!(is_control_message_type("err: fake"));
+    assert!(!is_control_message_type("calendar_update"));
+
+    // v1 clients only get typed framing for errors, so custom messages can't be confused with control messages
+    let conflicting = PushMessage::Custom("error".into(), Box::default());
+    assert!(ProtocolVersion::V1.can_send(&conflicting));
+    assert!(!ProtocolVersion::V2.can_send(&conflicting));
+    assert!(ProtocolVersion::V2.can_send(&PushMessage::Custom(
+        "calendar_update".into(),
+        Box::default()
+    )));
+}
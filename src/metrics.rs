@@ -1,21 +1,140 @@
 use crate::config::{Bind, TlsConfig};
+use crate::error::AUTH_FAILURE_REASONS;
+use crate::event::CHANNELS;
 use crate::{serve_at, Result};
 use serde::{Serialize, Serializer};
+use std::collections::BTreeMap;
 use std::fmt::Write;
 use std::future::Future;
-use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
 use tokio::sync::oneshot;
 use warp::Filter;
 
 pub static METRICS: Metrics = Metrics::new();
 
+const MAX_HISTOGRAM_BUCKETS: usize = 16;
+
+/// Histogram bucket boundaries for database queries, in milliseconds
+pub const QUERY_DURATION_BUCKETS: &[u64] = &[5, 10, 25, 50, 100, 250, 500, 1000, 2500, 5000, 10000];
+
+/// Histogram bucket boundaries for authenticating against Nextcloud, in milliseconds
+pub const AUTH_DURATION_BUCKETS: &[u64] = &[10, 25, 50, 100, 250, 500, 1000, 2500, 5000, 10000];
+
+/// Histogram bucket boundaries for the time connections stay open, in milliseconds
+pub const CONNECTION_DURATION_BUCKETS: &[u64] = &[
+    1000,
+    10 * 1000,
+    60 * 1000,
+    5 * 60 * 1000,
+    15 * 60 * 1000,
+    60 * 60 * 1000,
+    4 * 60 * 60 * 1000,
+    12 * 60 * 60 * 1000,
+    24 * 60 * 60 * 1000,
+];
+
+/// Histogram bucket boundaries for sending an event to all affected users, in milliseconds
+pub const FAN_OUT_DURATION_BUCKETS: &[u64] = &[1, 5, 10, 25, 50, 100, 250, 500, 1000, 5000];
+
+/// A prometheus style histogram of durations with cumulative buckets
+pub struct Histogram {
+    /// Upper bounds of the buckets, in milliseconds
+    bounds: &'static [u64],
+    buckets: [AtomicUsize; MAX_HISTOGRAM_BUCKETS],
+    count: AtomicUsize,
+    sum_micros: AtomicU64,
+}
+
+impl Histogram {
+    pub const fn new(bounds: &'static [u64]) -> Self {
+        assert!(bounds.len() <= MAX_HISTOGRAM_BUCKETS);
+        // only used to initialize the buckets
+        #[allow(clippy::declare_interior_mutable_const)]
+        const ZERO: AtomicUsize = AtomicUsize::new(0);
+        Histogram {
+            bounds,
+            buckets: [ZERO; MAX_HISTOGRAM_BUCKETS],
+            count: AtomicUsize::new(0),
+            sum_micros: AtomicU64::new(0),
+        }
+    }
+
+    pub fn observe(&self, duration: Duration) {
+        let millis = duration.as_millis() as u64;
+        for (bound, bucket) in self.bounds.iter().zip(self.buckets.iter()) {
+            if millis <= *bound {
+                bucket.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+        self.count.fetch_add(1, Ordering::Relaxed);
+        self.sum_micros
+            .fetch_add(duration.as_micros() as u64, Ordering::Relaxed);
+    }
+
+    pub fn count(&self) -> usize {
+        self.count.load(Ordering::Relaxed)
+    }
+
+    /// Number of observations less than or equal to the bound, in milliseconds
+    pub fn bucket_count(&self, bound: u64) -> Option<usize> {
+        self.bounds
+            .iter()
+            .position(|known| *known == bound)
+            .map(|index| self.buckets[index].load(Ordering::Relaxed))
+    }
+
+    fn render(&self, name: &str, response: &mut String) {
+        let _ = writeln!(response, "# TYPE {} histogram", name);
+        for (bound, bucket) in self.bounds.iter().zip(self.buckets.iter()) {
+            let _ = writeln!(
+                response,
+                "{}_bucket{{le=\"{}\"}} {}",
+                name,
+                *bound as f64 / 1000.0,
+                bucket.load(Ordering::Relaxed)
+            );
+        }
+        let _ = writeln!(response, "{}_bucket{{le=\"+Inf\"}} {}", name, self.count());
+        let _ = writeln!(
+            response,
+            "{}_sum {}",
+            name,
+            self.sum_micros.load(Ordering::Relaxed) as f64 / 1_000_000.0
+        );
+        let _ = writeln!(response, "{}_count {}", name, self.count());
+    }
+}
+
 pub struct Metrics {
     active_connection_count: AtomicUsize,
     active_user_count: AtomicUsize,
+    /// Active connections per reported client type
+    client_connections: Mutex<BTreeMap<String, usize>>,
     total_connection_count: AtomicUsize,
+    peak_connection_count: AtomicUsize,
     mapping_query_count: AtomicUsize,
+    cache_refreshes: AtomicUsize,
+    dropped_events: AtomicUsize,
+    throttled_events: AtomicUsize,
+    skipped_events: AtomicUsize,
+    foreign_events: AtomicUsize,
+    rejected_connections: AtomicUsize,
+    failed_deliveries: AtomicUsize,
+    send_panics: AtomicUsize,
     events_received: AtomicUsize,
+    events_received_by_type: [AtomicUsize; CHANNELS.len()],
+    events_received_by_source: Mutex<BTreeMap<String, usize>>,
+    malformed_events: AtomicUsize,
     messages_sent: AtomicUsize,
+    authentication_success_count: AtomicUsize,
+    authentication_failure_count: AtomicUsize,
+    authentication_failures_by_reason: [AtomicUsize; AUTH_FAILURE_REASONS.len()],
+    pub query_duration: Histogram,
+    pub auth_duration: Histogram,
+    pub connection_duration: Histogram,
+    pub fan_out_duration: Histogram,
 }
 
 #[derive(Serialize)]
@@ -50,15 +169,44 @@ impl Serialize for Metrics {
     }
 }
 
+impl Default for Metrics {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl Metrics {
     pub const fn new() -> Self {
+        // only used to initialize the per-type counters
+        #[allow(clippy::declare_interior_mutable_const)]
+        const ZERO: AtomicUsize = AtomicUsize::new(0);
         Metrics {
             active_connection_count: AtomicUsize::new(0),
             active_user_count: AtomicUsize::new(0),
+            client_connections: Mutex::new(BTreeMap::new()),
             total_connection_count: AtomicUsize::new(0),
+            peak_connection_count: AtomicUsize::new(0),
             mapping_query_count: AtomicUsize::new(0),
+            cache_refreshes: AtomicUsize::new(0),
+            dropped_events: AtomicUsize::new(0),
+            throttled_events: AtomicUsize::new(0),
+            skipped_events: AtomicUsize::new(0),
+            foreign_events: AtomicUsize::new(0),
+            rejected_connections: AtomicUsize::new(0),
+            failed_deliveries: AtomicUsize::new(0),
+            send_panics: AtomicUsize::new(0),
             events_received: AtomicUsize::new(0),
+            events_received_by_type: [ZERO; CHANNELS.len()],
+            events_received_by_source: Mutex::new(BTreeMap::new()),
+            malformed_events: AtomicUsize::new(0),
             messages_sent: AtomicUsize::new(0),
+            authentication_success_count: AtomicUsize::new(0),
+            authentication_failure_count: AtomicUsize::new(0),
+            authentication_failures_by_reason: [ZERO; AUTH_FAILURE_REASONS.len()],
+            query_duration: Histogram::new(QUERY_DURATION_BUCKETS),
+            auth_duration: Histogram::new(AUTH_DURATION_BUCKETS),
+            connection_duration: Histogram::new(CONNECTION_DURATION_BUCKETS),
+            fan_out_duration: Histogram::new(FAN_OUT_DURATION_BUCKETS),
         }
     }
 
@@ -70,29 +218,140 @@ impl Metrics {
         self.total_connection_count.load(Ordering::Relaxed)
     }
 
+    /// Highest number of simultaneously open connections
+    pub fn peak_connection_count(&self) -> usize {
+        self.peak_connection_count.load(Ordering::Relaxed)
+    }
+
     pub fn mapping_query_count(&self) -> usize {
         self.mapping_query_count.load(Ordering::Relaxed)
     }
 
+    /// Number of expired storage mappings refreshed in the background
+    pub fn cache_refreshes(&self) -> usize {
+        self.cache_refreshes.load(Ordering::Relaxed)
+    }
+
+    /// Number of events dropped because the event buffer was full
+    pub fn dropped_events(&self) -> usize {
+        self.dropped_events.load(Ordering::Relaxed)
+    }
+
+    pub fn throttled_events(&self) -> usize {
+        self.throttled_events.load(Ordering::Relaxed)
+    }
+
+    pub fn skipped_events(&self) -> usize {
+        self.skipped_events.load(Ordering::Relaxed)
+    }
+
+    pub fn foreign_events(&self) -> usize {
+        self.foreign_events.load(Ordering::Relaxed)
+    }
+
+    /// Number of websocket connections refused because the connection limit was reached
+    pub fn rejected_connections(&self) -> usize {
+        self.rejected_connections.load(Ordering::Relaxed)
+    }
+
+    /// Number of messages queued for a user whose send queue was full
+    pub fn failed_deliveries(&self) -> usize {
+        self.failed_deliveries.load(Ordering::Relaxed)
+    }
+
+    pub fn send_panics(&self) -> usize {
+        self.send_panics.load(Ordering::Relaxed)
+    }
+
     pub fn events_received(&self) -> usize {
         self.events_received.load(Ordering::Relaxed)
     }
 
+    pub fn malformed_events(&self) -> usize {
+        self.malformed_events.load(Ordering::Relaxed)
+    }
+
     pub fn messages_sent(&self) -> usize {
         self.messages_sent.load(Ordering::Relaxed)
     }
 
+    /// Number of received events for every event channel
+    pub fn events_received_by_type(&self) -> impl Iterator<Item = (&'static str, usize)> + '_ {
+        CHANNELS
+            .iter()
+            .zip(self.events_received_by_type.iter())
+            .map(|(channel, count)| (*channel, count.load(Ordering::Relaxed)))
+    }
+
+    /// Number of received events from the redis source
+    pub fn events_received_from_source(&self, source: &str) -> usize {
+        self.events_received_by_source
+            .lock()
+            .unwrap()
+            .get(source)
+            .copied()
+            .unwrap_or_default()
+    }
+
+    pub fn authentication_success_count(&self) -> usize {
+        self.authentication_success_count.load(Ordering::Relaxed)
+    }
+
+    pub fn authentication_failure_count(&self) -> usize {
+        self.authentication_failure_count.load(Ordering::Relaxed)
+    }
+
+    pub fn authentication_failures_by_reason(
+        &self,
+    ) -> impl Iterator<Item = (&'static str, usize)> + '_ {
+        AUTH_FAILURE_REASONS
+            .iter()
+            .zip(self.authentication_failures_by_reason.iter())
+            .map(|(reason, count)| (*reason, count.load(Ordering::Relaxed)))
+    }
+
     pub fn add_connection(&self) {
         self.total_connection_count.fetch_add(1, Ordering::Relaxed);
-        self.active_connection_count.fetch_add(1, Ordering::Relaxed);
+        let active = self.active_connection_count.fetch_add(1, Ordering::Relaxed) + 1;
+        self.peak_connection_count
+            .fetch_max(active, Ordering::Relaxed);
     }
 
     pub fn remove_connection(&self) {
         self.active_connection_count.fetch_sub(1, Ordering::Relaxed);
     }
 
+    /// Number of active connections that reported the client type
+    pub fn client_connection_count(&self, client: &str) -> usize {
+        self.client_connections
+            .lock()
+            .unwrap()
+            .get(client)
+            .copied()
+            .unwrap_or_default()
+    }
+
+    pub fn add_client_connection(&self, client: &str) {
+        *self
+            .client_connections
+            .lock()
+            .unwrap()
+            .entry(client.to_string())
+            .or_default() += 1;
+    }
+
+    pub fn remove_client_connection(&self, client: &str) {
+        let mut clients = self.client_connections.lock().unwrap();
+        if let Some(count) = clients.get_mut(client) {
+            *count = count.saturating_sub(1);
+            if *count == 0 {
+                clients.remove(client);
+            }
+        }
+    }
+
     pub fn active_user_count(&self) -> usize {
-        self.active_user_count.load( Ordering::Relaxed)
+        self.active_user_count.load(Ordering::Relaxed)
     }
 
     pub fn add_user(&self) {
@@ -107,54 +366,295 @@ impl Metrics {
         self.mapping_query_count.fetch_add(1, Ordering::Relaxed);
     }
 
-    pub fn add_event(&self) {
+    pub fn add_cache_refresh(&self) {
+        self.cache_refreshes.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn add_dropped_event(&self) {
+        self.dropped_events.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn add_throttled_event(&self) {
+        self.throttled_events.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn add_skipped_event(&self) {
+        self.skipped_events.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn add_foreign_event(&self) {
+        self.foreign_events.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn add_rejected_connection(&self) {
+        self.rejected_connections.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn add_failed_delivery(&self) {
+        self.failed_deliveries.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn add_send_panic(&self) {
+        self.send_panics.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn add_event(&self, channel: &str) {
         self.events_received.fetch_add(1, Ordering::Relaxed);
+        if let Some(index) = CHANNELS.iter().position(|known| *known == channel) {
+            self.events_received_by_type[index].fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    pub fn add_source_event(&self, source: &str) {
+        let mut sources = self.events_received_by_source.lock().unwrap();
+        match sources.get_mut(source) {
+            Some(count) => *count += 1,
+            None => {
+                sources.insert(source.to_string(), 1);
+            }
+        }
+    }
+
+    pub fn add_malformed_event(&self) {
+        self.malformed_events.fetch_add(1, Ordering::Relaxed);
     }
 
     pub fn add_message(&self) {
         self.messages_sent.fetch_add(1, Ordering::Relaxed);
     }
-}
 
-pub fn serve_metrics(
-    bind: Bind,
-    cancel: oneshot::Receiver<()>,
-    tls: Option<&TlsConfig>,
-) -> Result<impl Future<Output = ()> + Send> {
-    let metrics = warp::path!("metrics").map(|| {
-        let mut response = String::with_capacity(128);
+    pub fn add_authentication_success(&self) {
+        self.authentication_success_count
+            .fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn add_authentication_failure(&self, reason: &str) {
+        self.authentication_failure_count
+            .fetch_add(1, Ordering::Relaxed);
+        if let Some(index) = AUTH_FAILURE_REASONS
+            .iter()
+            .position(|known| *known == reason)
+        {
+            self.authentication_failures_by_reason[index].fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Render the active connections by client type, connections without a client type are counted as "unknown"
+    fn render_client_connections(&self, response: &mut String) {
+        let clients = self.client_connections.lock().unwrap();
+        let known: usize = clients.values().sum();
+        let _ = writeln!(
+            response,
+            "# TYPE notify_push_active_connections_by_client gauge"
+        );
+        for (client, count) in clients.iter() {
+            let _ = writeln!(
+                response,
+                "notify_push_active_connections_by_client{{client=\"{}\"}} {}",
+                client, count
+            );
+        }
+        let _ = writeln!(
+            response,
+            "notify_push_active_connections_by_client{{client=\"unknown\"}} {}",
+            self.active_connection_count().saturating_sub(known)
+        );
+    }
+
+    /// One line summary of the totals, logged on shutdown
+    pub fn summary(&self) -> String {
+        format!(
+            "processed {} events, peak of {} connections ({} total), {} mapping queries, {} authentication failures",
+            self.events_received(),
+            self.peak_connection_count(),
+            self.total_connection_count(),
+            self.mapping_query_count(),
+            self.authentication_failure_count()
+        )
+    }
+
+    /// Render the metrics in the prometheus text format
+    pub fn render(&self) -> String {
+        let mut response = String::with_capacity(512);
         let _ = writeln!(
             &mut response,
             "active_connection_count {}",
-            METRICS.active_connection_count()
+            self.active_connection_count()
         );
+        let _ = writeln!(
+            &mut response,
+            "# TYPE notify_push_active_connections gauge\nnotify_push_active_connections {}",
+            self.active_connection_count()
+        );
+        self.render_client_connections(&mut response);
         let _ = writeln!(
             &mut response,
             "active_user_count {}",
-            METRICS.active_user_count()
+            self.active_user_count()
         );
         let _ = writeln!(
             &mut response,
             "total_connection_count {}",
-            METRICS.total_connection_count()
+            self.total_connection_count()
         );
         let _ = writeln!(
             &mut response,
             "mapping_query_count {}",
-            METRICS.mapping_query_count()
+            self.mapping_query_count()
+        );
+        let _ = writeln!(
+            &mut response,
+            "mapping_cache_refresh_count_total {}",
+            self.cache_refreshes()
         );
         let _ = writeln!(
             &mut response,
             "event_count_total {}",
-            METRICS.events_received()
+            self.events_received()
+        );
+        for (channel, count) in self.events_received_by_type() {
+            let _ = writeln!(
+                &mut response,
+                "event_type_count_total{{type=\"{}\"}} {}",
+                channel, count
+            );
+        }
+        for (source, count) in self.events_received_by_source.lock().unwrap().iter() {
+            let _ = writeln!(
+                &mut response,
+                "event_source_count_total{{source=\"{}\"}} {}",
+                source, count
+            );
+        }
+        let _ = writeln!(
+            &mut response,
+            "malformed_event_count_total {}",
+            self.malformed_events()
+        );
+        let _ = writeln!(
+            &mut response,
+            "# TYPE notify_push_dropped_events counter\nnotify_push_dropped_events {}",
+            self.dropped_events()
+        );
+        let _ = writeln!(
+            &mut response,
+            "# TYPE notify_push_throttled_events counter\nnotify_push_throttled_events {}",
+            self.throttled_events()
+        );
+        let _ = writeln!(
+            &mut response,
+            "# TYPE notify_push_skipped_events counter\nnotify_push_skipped_events {}",
+            self.skipped_events()
+        );
+        let _ = writeln!(
+            &mut response,
+            "# TYPE notify_push_foreign_events counter\nnotify_push_foreign_events {}",
+            self.foreign_events()
+        );
+        let _ = writeln!(
+            &mut response,
+            "# TYPE notify_push_rejected_connections counter\nnotify_push_rejected_connections {}",
+            self.rejected_connections()
+        );
+        let _ = writeln!(
+            &mut response,
+            "# TYPE notify_push_failed_deliveries counter\nnotify_push_failed_deliveries {}",
+            self.failed_deliveries()
+        );
+        let _ = writeln!(
+            &mut response,
+            "# TYPE notify_push_send_panics counter\nnotify_push_send_panics {}",
+            self.send_panics()
         );
         let _ = writeln!(
             &mut response,
             "message_count_total {}",
-            METRICS.messages_sent()
+            self.messages_sent()
+        );
+        let _ = writeln!(
+            &mut response,
+            "authentication_success_count_total {}",
+            self.authentication_success_count()
         );
+        let _ = writeln!(
+            &mut response,
+            "authentication_failure_count_total {}",
+            self.authentication_failure_count()
+        );
+        for (reason, count) in self.authentication_failures_by_reason() {
+            let _ = writeln!(
+                &mut response,
+                "authentication_failure_reason_count_total{{reason=\"{}\"}} {}",
+                reason, count
+            );
+        }
+        self.query_duration
+            .render("database_query_duration_seconds", &mut response);
+        self.auth_duration
+            .render("authentication_duration_seconds", &mut response);
+        self.connection_duration
+            .render("connection_duration_seconds", &mut response);
+        self.fan_out_duration
+            .render("fan_out_duration_seconds", &mut response);
         response
-    });
+    }
+}
+
+pub fn serve_metrics(
+    bind: Bind,
+    cancel: oneshot::Receiver<()>,
+    tls: Option<&TlsConfig>,
+) -> Result<impl Future<Output = ()> + Send> {
+    let metrics = warp::path!("metrics").map(|| METRICS.render());
 
     serve_at(metrics, bind, cancel, tls)
 }
+
+#[test]
+fn test_summary() {
+    let metrics = Metrics::new();
+    metrics.add_connection();
+    metrics.add_connection();
+    metrics.remove_connection();
+    metrics.add_connection();
+    metrics.add_event("notify_activity");
+    metrics.add_authentication_failure("invalid_credentials");
+    assert_eq!(
+        "processed 1 events, peak of 2 connections (3 total), 0 mapping queries, 1 authentication failures",
+        metrics.summary()
+    );
+}
+
+#[test]
+fn test_events_by_source() {
+    let metrics = Metrics::new();
+    metrics.add_source_event("redis-a:6379");
+    metrics.add_source_event("redis-b:6379");
+    metrics.add_source_event("redis-a:6379");
+
+    assert_eq!(2, metrics.events_received_from_source("redis-a:6379"));
+    assert_eq!(1, metrics.events_received_from_source("redis-b:6379"));
+    let rendered = metrics.render();
+    assert!(rendered.contains("event_source_count_total{source=\"redis-a:6379\"} 2"));
+    assert!(rendered.contains("event_source_count_total{source=\"redis-b:6379\"} 1"));
+}
+
+#[test]
+fn test_histogram() {
+    let histogram = Histogram::new(&[10, 100]);
+    histogram.observe(Duration::from_millis(5));
+    histogram.observe(Duration::from_millis(50));
+    histogram.observe(Duration::from_millis(500));
+
+    let mut rendered = String::new();
+    histogram.render("test", &mut rendered);
+    assert_eq!(
+        "# TYPE test histogram\n\
+        test_bucket{le=\"0.01\"} 1\n\
+        test_bucket{le=\"0.1\"} 2\n\
+        test_bucket{le=\"+Inf\"} 3\n\
+        test_sum 0.555\n\
+        test_count 3\n",
+        rendered
+    );
+}
@@ -1,15 +1,41 @@
 use crate::error::{AuthenticationError, NextCloudError};
 use crate::{Result, UserId};
+use ahash::RandomState;
+use dashmap::DashMap;
+use futures::future::BoxFuture;
+use hmac::{Hmac, Mac};
+use rand::RngCore;
 use reqwest::{Response, StatusCode, Url};
+use sha2::Sha256;
 use std::fmt::Write;
 use std::net::IpAddr;
+use std::time::{Duration, Instant};
 use warp::http::HeaderName;
 
 static X_FORWARDED_FOR: HeaderName = HeaderName::from_static("x-forwarded-for");
 
+type CredentialHash = [u8; 32];
+
+/// Verify the credentials of connecting clients
+pub trait Authenticator: Send + Sync {
+    /// Get the user id for the credentials, if they are valid
+    fn verify_credentials<'a>(
+        &'a self,
+        username: &'a str,
+        password: &'a str,
+        forwarded_for: Vec<IpAddr>,
+    ) -> BoxFuture<'a, Result<String, AuthenticationError>>;
+
+    /// Forget any cached credentials for the user after their credentials have been invalidated
+    fn forget_credentials(&self, _user: &UserId) {}
+}
+
 pub struct Client {
     http: reqwest::Client,
     base_url: Url,
+    credential_cache: DashMap<CredentialHash, (Instant, String), RandomState>,
+    credential_cache_ttl: Duration,
+    credential_cache_key: [u8; 32],
 }
 
 impl Client {
@@ -18,7 +44,31 @@ impl Client {
         let http = reqwest::Client::builder()
             .danger_accept_invalid_certs(allow_self_signed)
             .build()?;
-        Ok(Client { http, base_url })
+        let mut credential_cache_key = [0; 32];
+        rand::thread_rng().fill_bytes(&mut credential_cache_key);
+        Ok(Client {
+            http,
+            base_url,
+            credential_cache: DashMap::default(),
+            credential_cache_ttl: Duration::ZERO,
+            credential_cache_key,
+        })
+    }
+
+    /// Remember successful credential verifications for the provided duration
+    pub fn with_credential_cache(mut self, ttl: Duration) -> Self {
+        self.credential_cache_ttl = ttl;
+        self
+    }
+
+    /// Keyed hash of the credentials, so we don't have to keep the plaintext password around
+    fn credential_hash(&self, username: &str, password: &str) -> CredentialHash {
+        let mut mac = Hmac::<Sha256>::new_from_slice(&self.credential_cache_key)
+            .expect("hmac accepts keys of any size");
+        mac.update(username.as_bytes());
+        mac.update(&[0]);
+        mac.update(password.as_bytes());
+        mac.finalize().into_bytes().into()
     }
 
     pub async fn verify_credentials(
@@ -26,16 +76,39 @@ impl Client {
         username: &str,
         password: &str,
         forwarded_for: Vec<IpAddr>,
-    ) -> Result<UserId, AuthenticationError> {
+    ) -> Result<String, AuthenticationError> {
+        let cache_key = if self.credential_cache_ttl > Duration::ZERO {
+            let hash = self.credential_hash(username, password);
+            if let Some(cached) = self.credential_cache.get(&hash) {
+                let (verified, user) = cached.value();
+                if verified.elapsed() < self.credential_cache_ttl {
+                    log::debug!("Using cached credential verification for {}", username);
+                    return Ok(user.clone());
+                }
+            }
+            Some(hash)
+        } else {
+            None
+        };
+
         log::debug!("Verifying credentials for {}", username);
         let response = self.auth_request(username, password, forwarded_for).await?;
 
         match response.status() {
-            StatusCode::OK => Ok(response
-                .text()
-                .await
-                .map_err(|_| AuthenticationError::InvalidMessage)?
-                .into()),
+            StatusCode::OK => {
+                let user = response
+                    .text()
+                    .await
+                    .map_err(|_| AuthenticationError::InvalidMessage)?;
+                if let Some(cache_key) = cache_key {
+                    let now = Instant::now();
+                    let ttl = self.credential_cache_ttl;
+                    self.credential_cache
+                        .retain(|_, (verified, _)| now.duration_since(*verified) < ttl);
+                    self.credential_cache.insert(cache_key, (now, user.clone()));
+                }
+                Ok(user)
+            }
             StatusCode::UNAUTHORIZED => Err(AuthenticationError::Invalid),
             status if status.is_server_error() => Err(NextCloudError::Server(status).into()),
             status if status.is_client_error() => Err(NextCloudError::Client(status).into()),
@@ -70,6 +143,13 @@ impl Client {
             .map_err(NextCloudError::NextcloudConnect)
     }
 
+    /// Remove all cached credentials for a user, ignoring case so this also works with normalized user ids
+    pub fn forget_credentials(&self, user: &UserId) {
+        let user = user.normalized();
+        self.credential_cache
+            .retain(|_, (_, cached_user)| UserId::from(cached_user.as_str()).normalized() != user);
+    }
+
     pub async fn get_test_cookie(&self) -> Result<u32, NextCloudError> {
         let response = self
             .http
@@ -124,3 +204,23 @@ impl Client {
         Ok(())
     }
 }
+
+impl Authenticator for Client {
+    fn verify_credentials<'a>(
+        &'a self,
+        username: &'a str,
+        password: &'a str,
+        forwarded_for: Vec<IpAddr>,
+    ) -> BoxFuture<'a, Result<String, AuthenticationError>> {
+        Box::pin(Client::verify_credentials(
+            self,
+            username,
+            password,
+            forwarded_for,
+        ))
+    }
+
+    fn forget_credentials(&self, user: &UserId) {
+        Client::forget_credentials(self, user)
+    }
+}
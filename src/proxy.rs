@@ -0,0 +1,112 @@
+use ipnet::IpNet;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+/// Proxies trusted when no trusted proxies are configured
+pub fn default_trusted_proxies() -> Vec<IpNet> {
+    vec![
+        IpNet::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 8).unwrap(),
+        IpNet::from(IpAddr::V6(Ipv6Addr::LOCALHOST)),
+    ]
+}
+
+/// Parse a trusted proxy, either as CIDR range or a single address
+pub fn parse_trusted_proxy(proxy: &str) -> Result<IpNet, ipnet::AddrParseError> {
+    proxy
+        .parse::<IpNet>()
+        .or_else(|e| proxy.parse::<IpAddr>().map(IpNet::from).map_err(|_| e))
+}
+
+/// Determine the chain of addresses a request passed through, starting with the client
+///
+/// The `X-Forwarded-For` (or `X-Real-IP`) header is only used if the peer is a trusted proxy,
+/// hops are followed from the right for as long as they are trusted proxies.
+/// Requests without a peer address come in over a unix socket and are treated as coming from a trusted proxy.
+pub fn forwarded_chain(
+    peer: Option<IpAddr>,
+    forwarded_for: Option<&str>,
+    real_ip: Option<&str>,
+    trusted_proxies: &[IpNet],
+) -> Vec<IpAddr> {
+    let is_trusted = |ip: &IpAddr| trusted_proxies.iter().any(|net| net.contains(ip));
+
+    let mut chain: Vec<IpAddr> = peer.into_iter().collect();
+    if !peer.as_ref().map(is_trusted).unwrap_or(true) {
+        return chain;
+    }
+
+    let header = match forwarded_for.or(real_ip) {
+        Some(header) => header,
+        None => return chain,
+    };
+    let hops = match header
+        .split(',')
+        .map(|hop| hop.trim().parse::<IpAddr>())
+        .collect::<Result<Vec<_>, _>>()
+    {
+        Ok(hops) => hops,
+        Err(_) => {
+            log::debug!("Ignoring malformed forwarded header {:?}", header);
+            return chain;
+        }
+    };
+
+    for hop in hops.into_iter().rev() {
+        chain.insert(0, hop);
+        if !is_trusted(&hop) {
+            break;
+        }
+    }
+    chain
+}
+
+#[test]
+fn test_untrusted_peer() {
+    let peer = "1.2.3.4".parse().ok();
+    assert_eq!(
+        vec![peer.unwrap()],
+        forwarded_chain(peer, Some("5.6.7.8"), None, &default_trusted_proxies())
+    );
+}
+
+#[test]
+fn test_trusted_peer() {
+    let trusted = vec![
+        parse_trusted_proxy("10.0.0.0/8").unwrap(),
+        parse_trusted_proxy("192.168.1.1").unwrap(),
+    ];
+    let ip = |ip: &str| ip.parse::<IpAddr>().unwrap();
+
+    assert_eq!(
+        vec![ip("5.6.7.8"), ip("10.1.1.1"), ip("192.168.1.1")],
+        forwarded_chain(
+            Some(ip("192.168.1.1")),
+            Some("1.1.1.1, 5.6.7.8, 10.1.1.1"),
+            None,
+            &trusted
+        )
+    );
+    assert_eq!(
+        vec![ip("5.6.7.8"), ip("10.1.1.1")],
+        forwarded_chain(Some(ip("10.1.1.1")), None, Some("5.6.7.8"), &trusted)
+    );
+    assert_eq!(
+        vec![ip("5.6.7.8")],
+        forwarded_chain(None, Some("5.6.7.8"), None, &trusted)
+    );
+}
+
+#[test]
+fn test_malformed_forwarded_for() {
+    let peer = "127.0.0.1".parse().ok();
+    assert_eq!(
+        vec![peer.unwrap()],
+        forwarded_chain(
+            peer,
+            Some("5.6.7.8, not-an-ip"),
+            None,
+            &default_trusted_proxies()
+        )
+    );
+    assert!(parse_trusted_proxy("10.0.0.0/33").is_err());
+    assert!(parse_trusted_proxy("foo").is_err());
+}
@@ -0,0 +1,162 @@
+use crate::metrics::METRICS;
+use ahash::RandomState;
+use dashmap::DashMap;
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Limit the number of failed authentication attempts for a single ip within a time window
+pub struct AuthRateLimiter {
+    limit: usize,
+    window: Duration,
+    failures: DashMap<IpAddr, (Instant, usize), RandomState>,
+}
+
+impl AuthRateLimiter {
+    /// Create a new rate limiter, a limit of zero disables rate limiting
+    pub fn new(limit: usize, window: Duration) -> Self {
+        AuthRateLimiter {
+            limit,
+            window,
+            failures: DashMap::default(),
+        }
+    }
+
+    pub fn is_limited(&self, ip: &IpAddr) -> bool {
+        if self.limit == 0 {
+            return false;
+        }
+        match self.failures.get(ip) {
+            Some(entry) => {
+                let (start, count) = *entry;
+                start.elapsed() < self.window && count >= self.limit
+            }
+            None => false,
+        }
+    }
+
+    pub fn add_failure(&self, ip: IpAddr) {
+        if self.limit == 0 {
+            return;
+        }
+        let now = Instant::now();
+        self.failures
+            .retain(|_, (start, _)| now.duration_since(*start) < self.window);
+        let mut entry = self.failures.entry(ip).or_insert((now, 0));
+        entry.1 += 1;
+    }
+}
+
+/// What to do with events that exceed the maximum number of events per second
+#[derive(
+    Debug, Clone, Copy, Default, PartialEq, Eq, parse_display::Display, parse_display::FromStr,
+)]
+#[display(style = "kebab-case")]
+pub enum ThrottlePolicy {
+    /// Wait until the event can be processed
+    #[default]
+    Delay,
+    /// Drop the event
+    Drop,
+}
+
+/// Token bucket limiting the number of events processed per second, across all redis sources
+pub struct EventThrottle {
+    rate: usize,
+    policy: ThrottlePolicy,
+    /// Available tokens and the time they were last refilled
+    bucket: Mutex<(f64, Instant)>,
+}
+
+impl EventThrottle {
+    /// Create a new throttle, a rate of zero disables throttling
+    pub fn new(rate: usize, policy: ThrottlePolicy) -> Self {
+        EventThrottle {
+            rate,
+            policy,
+            bucket: Mutex::new((rate as f64, Instant::now())),
+        }
+    }
+
+    /// Wait until an event can be processed, returns false if the event should be dropped
+    pub async fn acquire(&self) -> bool {
+        if self.rate == 0 {
+            return true;
+        }
+        match self.take(Instant::now()) {
+            None => true,
+            Some(wait) => {
+                METRICS.add_throttled_event();
+                match self.policy {
+                    ThrottlePolicy::Drop => false,
+                    ThrottlePolicy::Delay => {
+                        // the token is already reserved, the bucket going negative delays the next events
+                        tokio::time::sleep(wait).await;
+                        true
+                    }
+                }
+            }
+        }
+    }
+
+    /// Take a token from the bucket, returning the time to wait if no token is available
+    ///
+    /// When delaying, the token is taken anyway so waiting events are processed in order
+    fn take(&self, now: Instant) -> Option<Duration> {
+        let rate = self.rate as f64;
+        let mut bucket = self.bucket.lock().unwrap();
+        let (tokens, last) = &mut *bucket;
+        *tokens = (*tokens + now.saturating_duration_since(*last).as_secs_f64() * rate).min(rate);
+        *last = now;
+        if *tokens >= 1.0 {
+            *tokens -= 1.0;
+            None
+        } else {
+            let wait = Duration::from_secs_f64((1.0 - *tokens) / rate);
+            if self.policy == ThrottlePolicy::Delay {
+                *tokens -= 1.0;
+            }
+            Some(wait)
+        }
+    }
+}
+
+#[test]
+fn test_rate_limit() {
+    let ip: IpAddr = "1.2.3.4".parse().unwrap();
+    let other: IpAddr = "1.2.3.5".parse().unwrap();
+    let limiter = AuthRateLimiter::new(2, Duration::from_secs(60));
+    assert!(!limiter.is_limited(&ip));
+    limiter.add_failure(ip);
+    assert!(!limiter.is_limited(&ip));
+    limiter.add_failure(ip);
+    assert!(limiter.is_limited(&ip));
+    assert!(!limiter.is_limited(&other));
+}
+
+#[test]
+fn test_rate_limit_window() {
+    let ip: IpAddr = "1.2.3.4".parse().unwrap();
+    let limiter = AuthRateLimiter::new(1, Duration::from_millis(10));
+    limiter.add_failure(ip);
+    assert!(limiter.is_limited(&ip));
+    std::thread::sleep(Duration::from_millis(20));
+    assert!(!limiter.is_limited(&ip));
+}
+
+#[test]
+fn test_event_throttle() {
+    let throttle = EventThrottle::new(2, ThrottlePolicy::Drop);
+    let start = Instant::now();
+    assert_eq!(None, throttle.take(start));
+    assert_eq!(None, throttle.take(start));
+    assert!(throttle.take(start).is_some());
+    assert_eq!(None, throttle.take(start + Duration::from_millis(500)));
+    assert!(throttle.take(start + Duration::from_millis(500)).is_some());
+
+    let throttle = EventThrottle::new(2, ThrottlePolicy::Delay);
+    throttle.take(start);
+    throttle.take(start);
+    assert_eq!(Some(Duration::from_millis(500)), throttle.take(start));
+    assert_eq!(Some(Duration::from_millis(1000)), throttle.take(start));
+}
@@ -0,0 +1,151 @@
+use crate::UserId;
+use ahash::RandomState;
+use dashmap::DashMap;
+use hmac::{Hmac, Mac};
+use rand::RngCore;
+use sha2::Sha256;
+use std::fmt::Write;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Time a reconnect token can be used after it has been issued
+pub const RECONNECT_TOKEN_TTL: Duration = Duration::from_secs(5 * 60);
+
+/// Prefix of the handshake message used to resume a session with a reconnect token
+pub const RESUME_PREFIX: &str = "resume:";
+
+/// Issues and verifies short-lived tokens that let clients reconnect without a Nextcloud round trip
+///
+/// Tokens have the form `<expiry>:<signature>:<user>`, signed with a key that is generated on startup
+pub struct ReconnectTokens {
+    key: [u8; 32],
+    ttl: Duration,
+    /// Time of the last credential invalidation per user, tokens issued before it are rejected
+    invalidated: DashMap<UserId, u64, RandomState>,
+}
+
+impl ReconnectTokens {
+    pub fn new(ttl: Duration) -> Self {
+        let mut key = [0; 32];
+        rand::thread_rng().fill_bytes(&mut key);
+        ReconnectTokens {
+            key,
+            ttl,
+            invalidated: DashMap::default(),
+        }
+    }
+
+    fn mac(&self, expiry: u64, user: &str) -> Hmac<Sha256> {
+        let mut mac =
+            Hmac::<Sha256>::new_from_slice(&self.key).expect("hmac accepts keys of any size");
+        mac.update(&expiry.to_be_bytes());
+        mac.update(user.as_bytes());
+        mac
+    }
+
+    /// Create a token for the user
+    pub fn issue(&self, user: &str) -> String {
+        self.issue_at(user, SystemTime::now())
+    }
+
+    fn issue_at(&self, user: &str, now: SystemTime) -> String {
+        let expiry = unix_time(now + self.ttl);
+        let signature = self.mac(expiry, user).finalize().into_bytes();
+        let mut token = format!("{}:", expiry);
+        for byte in signature {
+            let _ = write!(&mut token, "{:02x}", byte);
+        }
+        token.push(':');
+        token.push_str(user);
+        token
+    }
+
+    /// Get the user from a token, if the token is valid and not expired
+    pub fn verify(&self, token: &str) -> Option<String> {
+        let mut parts = token.splitn(3, ':');
+        let expiry: u64 = parts.next()?.parse().ok()?;
+        let signature = decode_hex(parts.next()?)?;
+        let user = parts.next()?;
+
+        let now = unix_time(SystemTime::now());
+        if expiry <= now {
+            return None;
+        }
+        if let Some(invalidated) = self.invalidated.get(&UserId::from(user).normalized()) {
+            if expiry.saturating_sub(self.ttl.as_secs()) <= *invalidated {
+                return None;
+            }
+        }
+        self.mac(expiry, user).verify_slice(&signature).ok()?;
+        Some(user.to_string())
+    }
+
+    /// Reject all tokens issued for the user until now
+    ///
+    /// Users are matched ignoring case, rejecting the tokens of a differently cased user is harmless
+    pub fn invalidate(&self, user: &UserId) {
+        let now = unix_time(SystemTime::now());
+        self.invalidated
+            .retain(|_, invalidated| *invalidated + self.ttl.as_secs() > now);
+        self.invalidated.insert(user.normalized(), now);
+    }
+}
+
+fn unix_time(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+fn decode_hex(hex: &str) -> Option<Vec<u8>> {
+    if hex.len() % 2 != 0 || !hex.is_ascii() {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).ok())
+        .collect()
+}
+
+#[test]
+fn test_valid_token() {
+    let tokens = ReconnectTokens::new(RECONNECT_TOKEN_TTL);
+    let token = tokens.issue("foo:bar");
+    assert_eq!(Some("foo:bar".to_string()), tokens.verify(&token));
+
+    // tokens from other instances aren't accepted
+    assert_eq!(
+        None,
+        ReconnectTokens::new(RECONNECT_TOKEN_TTL).verify(&token)
+    );
+}
+
+#[test]
+fn test_expired_token() {
+    let tokens = ReconnectTokens::new(RECONNECT_TOKEN_TTL);
+    let token = tokens.issue_at("foo", SystemTime::now() - RECONNECT_TOKEN_TTL * 2);
+    assert_eq!(None, tokens.verify(&token));
+}
+
+#[test]
+fn test_invalidated_token() {
+    let tokens = ReconnectTokens::new(RECONNECT_TOKEN_TTL);
+    let token = tokens.issue("foo");
+    let other = tokens.issue("bar");
+    tokens.invalidate(&UserId::from("foo"));
+    assert_eq!(None, tokens.verify(&token));
+    assert_eq!(Some("bar".to_string()), tokens.verify(&other));
+}
+
+#[test]
+fn test_tampered_token() {
+    let tokens = ReconnectTokens::new(RECONNECT_TOKEN_TTL);
+    let token = tokens.issue("foo");
+    let (expiry, rest) = token.split_once(':').unwrap();
+
+    let other_user = format!("{}bar", token);
+    assert_eq!(None, tokens.verify(&other_user));
+    let extended = format!("{}:{}", expiry.parse::<u64>().unwrap() + 1000, rest);
+    assert_eq!(None, tokens.verify(&extended));
+    assert_eq!(None, tokens.verify("garbage"));
+    assert_eq!(None, tokens.verify("1:zz:foo"));
+}
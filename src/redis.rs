@@ -3,10 +3,11 @@ use crate::Result;
 use redis::aio::{Connection, PubSub};
 use redis::cluster::ClusterClient;
 use redis::cluster_async::ClusterConnection;
-use redis::{AsyncCommands, Client, ConnectionInfo, RedisError};
+use redis::{AsyncCommands, Client, ConnectionInfo, ErrorKind, RedisError};
 
 pub struct Redis {
     config: Vec<ConnectionInfo>,
+    sharded: bool,
 }
 
 impl Redis {
@@ -14,19 +15,64 @@ impl Redis {
         if config.is_empty() {
             return Err(ConfigError::NoRedis.into());
         }
-        Ok(Redis { config })
+        Ok(Redis {
+            config,
+            sharded: false,
+        })
+    }
+
+    /// Treat the configured servers as independent servers instead of a cluster
+    pub fn with_sharding(mut self, sharded: bool) -> Self {
+        self.sharded = sharded;
+        self
+    }
+
+    /// Get the redis servers that need to be subscribed to for receiving all events
+    ///
+    /// For sharded setups every server publishes its own events, for cluster setups a single server is sufficient
+    pub fn sources(&self) -> Vec<Redis> {
+        if self.sharded {
+            self.config
+                .iter()
+                .map(|config| Redis {
+                    config: vec![config.clone()],
+                    sharded: false,
+                })
+                .collect()
+        } else {
+            vec![Redis {
+                config: self.config.clone(),
+                sharded: false,
+            }]
+        }
+    }
+
+    /// Identifier of the servers, used to label the events received from them
+    pub fn source_name(&self) -> String {
+        self.config
+            .iter()
+            .map(|config| config.addr.to_string())
+            .collect::<Vec<_>>()
+            .join(",")
     }
 
     /// Get an async pubsub connection
     pub async fn pubsub(&self) -> Result<PubSub, RedisError> {
         // since pubsub performs a multicast for all nodes in a cluster,
         // listening to a single server in the cluster is sufficient for cluster setups
-        let client = Client::open(self.config.first().unwrap().clone())?;
+        let config = self.config.first().ok_or_else(|| {
+            RedisError::from((ErrorKind::InvalidClientConfig, "No redis server configured"))
+        })?;
+        let client = Client::open(config.clone())?;
         Ok(client.get_async_connection().await?.into_pubsub())
     }
 
     pub async fn connect(&self) -> Result<RedisConnection, RedisError> {
         let connection = match self.config.as_slice() {
+            [single, ..] if self.sharded => {
+                let client = Client::open(single.clone())?.get_async_connection().await?;
+                RedisConnection::Async(client)
+            }
             [single] => {
                 let client = Client::open(single.clone())?.get_async_connection().await?;
                 RedisConnection::Async(client)
@@ -51,10 +97,10 @@ impl RedisConnection {
     pub async fn del(&mut self, key: &str) -> Result<(), RedisError> {
         match self {
             RedisConnection::Async(client) => {
-                client.del(key).await?;
+                client.del::<_, ()>(key).await?;
             }
             RedisConnection::Cluster(client) => {
-                client.del(key).await?;
+                client.del::<_, ()>(key).await?;
             }
         }
         Ok(())
@@ -67,15 +113,39 @@ impl RedisConnection {
         })
     }
 
+    /// Get a key that might not exist
+    pub async fn get_optional(&mut self, key: &str) -> Result<Option<String>> {
+        Ok(match self {
+            RedisConnection::Async(client) => client.get(key).await?,
+            RedisConnection::Cluster(client) => client.get(key).await?,
+        })
+    }
+
     pub async fn set(&mut self, key: &str, value: &str) -> Result<()> {
         match self {
             RedisConnection::Async(client) => {
-                client.set(key, value).await?;
+                client.set::<_, _, ()>(key, value).await?;
             }
             RedisConnection::Cluster(client) => {
-                client.set(key, value).await?;
+                client.set::<_, _, ()>(key, value).await?;
             }
         }
         Ok(())
     }
 }
+
+#[test]
+fn test_source_names() {
+    use redis::IntoConnectionInfo;
+
+    let config = vec![
+        "redis://redis-a:6379".into_connection_info().unwrap(),
+        "redis://redis-b:6380".into_connection_info().unwrap(),
+    ];
+    let sharded = Redis::new(config.clone()).unwrap().with_sharding(true);
+    let names: Vec<String> = sharded.sources().iter().map(Redis::source_name).collect();
+    assert_eq!(vec!["redis-a:6379", "redis-b:6380"], names);
+
+    let cluster = Redis::new(config).unwrap();
+    assert_eq!("redis-a:6379,redis-b:6380", cluster.source_name());
+}
@@ -0,0 +1,187 @@
+use crate::connection::{Delivery, QueuedMessage};
+use crate::sink::NotificationSink;
+use crate::UserId;
+use ahash::RandomState;
+use dashmap::DashMap;
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+/// Default time in seconds messages are kept for replaying
+pub const DEFAULT_REPLAY_RETENTION: usize = 60;
+
+/// Prefix of the message a client sends to receive the messages it missed
+pub const SINCE_PREFIX: &str = "since:";
+
+/// Number of recorded messages between removing buffers that only contain expired messages
+const SWEEP_INTERVAL: u64 = 1024;
+
+struct BufferedMessage {
+    seq: u64,
+    received: Instant,
+    message: QueuedMessage,
+}
+
+/// Keeps the most recent messages for every user,
+/// so clients that were briefly disconnected can catch up on the messages they missed
+pub struct ReplayBuffer {
+    users: DashMap<UserId, VecDeque<BufferedMessage>, RandomState>,
+    size: usize,
+    retention: Duration,
+    seq: AtomicU64,
+}
+
+impl ReplayBuffer {
+    /// Create a buffer keeping up to `size` messages per user, a size of zero disables the buffer
+    pub fn new(size: usize, retention: Duration) -> Self {
+        ReplayBuffer {
+            users: DashMap::default(),
+            size,
+            retention,
+            seq: AtomicU64::default(),
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.size > 0
+    }
+
+    /// Sequence number of the last recorded message
+    pub fn latest(&self) -> u64 {
+        self.seq.load(Ordering::SeqCst)
+    }
+
+    /// Store a message for the user, returning its sequence number
+    pub fn record(&self, user: &UserId, msg: &QueuedMessage) -> u64 {
+        self.record_at(user, msg, Instant::now())
+    }
+
+    fn record_at(&self, user: &UserId, msg: &QueuedMessage, now: Instant) -> u64 {
+        let mut buffer = self.users.entry(user.clone()).or_default();
+        // assign the sequence number while holding the entry, so the messages of a user stay ordered
+        let seq = self.seq.fetch_add(1, Ordering::SeqCst) + 1;
+        self.expire(&mut buffer, now);
+        if buffer.len() >= self.size {
+            buffer.pop_front();
+        }
+        buffer.push_back(BufferedMessage {
+            seq,
+            received: now,
+            message: msg.clone(),
+        });
+        drop(buffer);
+
+        if seq % SWEEP_INTERVAL == 0 {
+            self.sweep(now);
+        }
+        seq
+    }
+
+    /// Get the buffered messages for the user after the given sequence number,
+    /// together with the sequence number the client has caught up to
+    pub fn since(&self, user: &UserId, seq: u64) -> (Vec<QueuedMessage>, u64) {
+        self.since_at(user, seq, Instant::now())
+    }
+
+    fn since_at(&self, user: &UserId, seq: u64, now: Instant) -> (Vec<QueuedMessage>, u64) {
+        let latest = self.latest();
+        let messages = match self.users.get_mut(user) {
+            Some(mut buffer) => {
+                self.expire(&mut buffer, now);
+                buffer
+                    .iter()
+                    .filter(|buffered| buffered.seq > seq && buffered.seq <= latest)
+                    .map(|buffered| buffered.message.clone())
+                    .collect()
+            }
+            None => Vec::new(),
+        };
+        (messages, latest.max(seq))
+    }
+
+    fn expire(&self, buffer: &mut VecDeque<BufferedMessage>, now: Instant) {
+        while let Some(oldest) = buffer.front() {
+            if now.duration_since(oldest.received) > self.retention {
+                buffer.pop_front();
+            } else {
+                break;
+            }
+        }
+    }
+
+    /// Remove the buffers of users that haven't received any message within the retention time
+    fn sweep(&self, now: Instant) {
+        self.users.retain(|_, buffer| {
+            buffer
+                .back()
+                .map(|newest| now.duration_since(newest.received) <= self.retention)
+                .unwrap_or_default()
+        });
+    }
+}
+
+impl NotificationSink for ReplayBuffer {
+    fn send(&self, user: &UserId, msg: &QueuedMessage) -> Delivery {
+        if self.is_enabled() {
+            self.record(user, msg);
+        }
+        Delivery::default()
+    }
+}
+
+#[cfg(test)]
+use crate::message::PushMessage;
+
+#[test]
+fn test_replay_since() {
+    let buffer = ReplayBuffer::new(2, Duration::from_secs(60));
+    let foo = UserId::from("foo");
+    let bar = UserId::from("bar");
+
+    let first = buffer.record(&foo, &PushMessage::Activity.into());
+    buffer.record(&bar, &PushMessage::Notification.into());
+    buffer.record(&foo, &PushMessage::Notification.into());
+
+    let (messages, latest) = buffer.since(&foo, first);
+    assert_eq!(3, latest);
+    assert_eq!(
+        vec![PushMessage::Notification],
+        messages
+            .into_iter()
+            .map(|msg| msg.message)
+            .collect::<Vec<_>>()
+    );
+    assert_eq!(2, buffer.since(&foo, 0).0.len());
+    assert!(buffer.since(&foo, latest).0.is_empty());
+    assert!(buffer.since(&UserId::from("baz"), 0).0.is_empty());
+
+    // the oldest message is dropped once the buffer is full
+    buffer.record(&foo, &PushMessage::Activity.into());
+    assert_eq!(2, buffer.since(&foo, 0).0.len());
+}
+
+#[test]
+fn test_replay_retention() {
+    let buffer = ReplayBuffer::new(8, Duration::from_secs(60));
+    let foo = UserId::from("foo");
+    let start = Instant::now();
+
+    buffer.record_at(&foo, &PushMessage::Activity.into(), start);
+    buffer.record_at(
+        &foo,
+        &PushMessage::Notification.into(),
+        start + Duration::from_secs(30),
+    );
+
+    let (messages, _) = buffer.since_at(&foo, 0, start + Duration::from_secs(61));
+    assert_eq!(
+        vec![PushMessage::Notification],
+        messages
+            .into_iter()
+            .map(|msg| msg.message)
+            .collect::<Vec<_>>()
+    );
+
+    buffer.sweep(start + Duration::from_secs(120));
+    assert!(buffer.users.is_empty());
+}
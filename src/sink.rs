@@ -0,0 +1,141 @@
+use crate::connection::{ActiveConnections, Delivery, QueuedMessage};
+use crate::metrics::METRICS;
+use crate::user::record_user_names;
+use crate::{Error, UserId};
+use serde_json::{json, Value};
+use std::panic::{catch_unwind, AssertUnwindSafe};
+use tokio::sync::mpsc;
+use url::Url;
+
+/// Number of messages that can be waiting to be posted to the webhook
+const WEBHOOK_QUEUE_SIZE: usize = 1024;
+
+/// A transport that delivers messages to users
+pub trait NotificationSink: Send + Sync {
+    /// Queue a message for the user, without waiting for it to be delivered
+    fn send(&self, user: &UserId, msg: &QueuedMessage) -> Delivery;
+}
+
+impl NotificationSink for ActiveConnections {
+    fn send(&self, user: &UserId, msg: &QueuedMessage) -> Delivery {
+        self.send_to_user(user, msg.clone())
+    }
+}
+
+/// Queue a message with a sink, a panic while sending only fails the delivery to this user
+pub fn send_isolated(sink: &dyn NotificationSink, user: &UserId, msg: &QueuedMessage) -> Delivery {
+    match catch_unwind(AssertUnwindSafe(|| sink.send(user, msg))) {
+        Ok(delivery) => delivery,
+        Err(panic) => {
+            let reason = panic
+                .downcast_ref::<&str>()
+                .copied()
+                .or_else(|| panic.downcast_ref::<String>().map(String::as_str))
+                .unwrap_or("unknown panic");
+            log::error!(
+                "Sending message to {} panicked (event {}): {}",
+                user,
+                msg.event,
+                reason
+            );
+            METRICS.add_send_panic();
+            Delivery::default()
+        }
+    }
+}
+
+/// Post every message as json to a configured url
+pub struct WebhookSink {
+    queue: mpsc::Sender<Value>,
+}
+
+impl WebhookSink {
+    pub fn new(url: Url) -> Result<Self, Error> {
+        let client = reqwest::Client::builder().build().map_err(Error::Webhook)?;
+        let (queue, mut rx) = mpsc::channel::<Value>(WEBHOOK_QUEUE_SIZE);
+        // the webhook payload includes the user name
+        record_user_names();
+
+        tokio::spawn(async move {
+            while let Some(payload) = rx.recv().await {
+                match client.post(url.clone()).json(&payload).send().await {
+                    Ok(response) if !response.status().is_success() => {
+                        log::warn!("Webhook responded with status {}", response.status());
+                    }
+                    Ok(_) => {}
+                    Err(e) => log::warn!("Failed to send webhook: {}", e),
+                }
+            }
+        });
+
+        Ok(WebhookSink { queue })
+    }
+}
+
+impl NotificationSink for WebhookSink {
+    fn send(&self, user: &UserId, msg: &QueuedMessage) -> Delivery {
+        let user = match user.name() {
+            Some(user) => user,
+            None => return Delivery::default(),
+        };
+        let payload = json!({
+            "user": user,
+            "event": msg.event.to_string(),
+            "message": msg.message.clone().into_json(true),
+        });
+        match self.queue.try_send(payload) {
+            Ok(()) => Delivery {
+                connections: 1,
+                overflowed: false,
+            },
+            Err(_) => {
+                METRICS.add_failed_delivery();
+                Delivery {
+                    connections: 0,
+                    overflowed: true,
+                }
+            }
+        }
+    }
+}
+
+#[test]
+fn test_send_isolated() {
+    use crate::message::PushMessage;
+
+    struct PanickingSink;
+
+    impl NotificationSink for PanickingSink {
+        fn send(&self, user: &UserId, _msg: &QueuedMessage) -> Delivery {
+            if user == &UserId::from("foo") {
+                panic!("broken sink");
+            }
+            Delivery::default()
+        }
+    }
+
+    let connections = ActiveConnections::default();
+    let foo = UserId::from("foo");
+    let bar = UserId::from("bar");
+    let mut foo_connection = connections.add(foo.clone()).unwrap();
+    let mut bar_connection = connections.add(bar.clone()).unwrap();
+    let panics = METRICS.send_panics();
+
+    let sinks: [&dyn NotificationSink; 2] = [&PanickingSink, &connections];
+    let msg = QueuedMessage::from(PushMessage::Activity);
+    for user in [&foo, &bar] {
+        for sink in sinks {
+            send_isolated(sink, user, &msg);
+        }
+    }
+
+    assert_eq!(panics + 1, METRICS.send_panics());
+    assert_eq!(
+        PushMessage::Activity,
+        foo_connection.messages.try_recv().unwrap().message
+    );
+    assert_eq!(
+        PushMessage::Activity,
+        bar_connection.messages.try_recv().unwrap().message
+    );
+}
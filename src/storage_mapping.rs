@@ -4,12 +4,18 @@ use crate::{Result, UserId};
 use ahash::RandomState;
 use dashmap::mapref::one::Ref;
 use dashmap::DashMap;
+use futures::future::{ready, BoxFuture};
 use log::debug;
 use rand::{thread_rng, Rng};
-use sqlx::any::AnyConnectOptions;
+use serde::Serialize;
+use sqlx::any::{AnyConnectOptions, AnyKind, AnyPoolOptions};
 use sqlx::{Any, AnyPool, FromRow};
+use std::future::Future;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Arc;
 use std::time::Instant;
-use tokio::time::Duration;
+use tokio::sync::Mutex;
+use tokio::time::{sleep, Duration};
 
 #[derive(Debug, Clone, FromRow)]
 pub struct UserStorageAccess {
@@ -17,63 +23,353 @@ pub struct UserStorageAccess {
     user: UserId,
     #[sqlx(rename = "path")]
     root: String,
+    /// Location of the mount in the user's filesystem, in the form `/<user>/files/...`
+    mount_point: String,
 }
 
-struct CachedAccess {
-    access: Vec<UserStorageAccess>,
+impl UserStorageAccess {
+    /// Map a path in the storage to the path as seen by the user, if the path is inside the mount
+    pub fn user_path(&self, path: &str) -> Option<String> {
+        let root = self.root.trim_end_matches('/');
+        let relative = if root.is_empty() {
+            path.trim_end_matches('/')
+        } else {
+            match path.trim_end_matches('/').strip_prefix(root)? {
+                "" => "",
+                rest => rest.strip_prefix('/')?,
+            }
+        };
+        // strip the user from the mount point, the remainder is relative to the user's home
+        let mount_point = self.mount_point.trim_matches('/');
+        let mount_point = mount_point
+            .split_once('/')
+            .map(|(_user, mount_point)| mount_point)
+            .unwrap_or_default();
+        Some(match (mount_point, relative) {
+            ("", relative) => relative.to_string(),
+            (mount_point, "") => mount_point.to_string(),
+            (mount_point, relative) => format!("{}/{}", mount_point, relative),
+        })
+    }
+}
+
+struct CachedAccess<T = Vec<UserStorageAccess>> {
+    access: T,
     valid_till: Instant,
+    /// Set when a background refresh for the expired entry is running
+    refreshing: AtomicBool,
 }
 
-impl CachedAccess {
-    pub fn new(access: Vec<UserStorageAccess>) -> Self {
-        let mut rng = thread_rng();
+impl<T> CachedAccess<T> {
+    pub fn new(access: T, valid_for: Duration) -> Self {
         Self {
             access,
-            valid_till: Instant::now()
-                + Duration::from_millis(rng.gen_range((4 * 60 * 1000)..(5 * 60 * 1000))),
+            valid_till: Instant::now() + valid_for,
+            refreshing: AtomicBool::new(false),
         }
     }
 
     pub fn is_valid(&self) -> bool {
         self.valid_till > Instant::now()
     }
+
+    /// Check if the entry expired less than `stale_time` ago
+    pub fn is_usable_stale(&self, stale_time: Duration) -> bool {
+        self.valid_till + stale_time > Instant::now()
+    }
+}
+
+/// Resolve the users that need to be notified for an event
+pub trait UserResolver: Send + Sync {
+    /// Get all users that have access to the path in the storage
+    fn get_users_for_storage_path<'a>(
+        &'a self,
+        storage: u32,
+        path: &'a str,
+    ) -> BoxFuture<'a, Result<Vec<UserId>, DatabaseError>>;
+
+    /// Get all users that have access to the path in the storage, with the path as seen by each user
+    ///
+    /// Resolvers that don't know where storages are mounted give every user the path in the storage
+    fn get_user_paths_for_storage_path<'a>(
+        &'a self,
+        storage: u32,
+        path: &'a str,
+    ) -> BoxFuture<'a, Result<Vec<(UserId, String)>, DatabaseError>> {
+        Box::pin(async move {
+            Ok(self
+                .get_users_for_storage_path(storage, path)
+                .await?
+                .into_iter()
+                .map(|user| (user, path.to_string()))
+                .collect())
+        })
+    }
+
+    /// Get all users that have access to any of the paths in the storage, without duplicates
+    fn get_users_for_storage_paths<'a>(
+        &'a self,
+        storage: u32,
+        paths: &'a [String],
+    ) -> BoxFuture<'a, Result<Vec<UserId>, DatabaseError>> {
+        Box::pin(async move {
+            let mut users = Vec::new();
+            for path in paths {
+                for user in self.get_users_for_storage_path(storage, path).await? {
+                    if !users.contains(&user) {
+                        users.push(user);
+                    }
+                }
+            }
+            Ok(users)
+        })
+    }
+
+    /// Get all members of the group
+    fn get_users_for_group<'a>(
+        &'a self,
+        group: &'a str,
+    ) -> BoxFuture<'a, Result<Vec<UserId>, DatabaseError>>;
+
+    /// Check if the backend is reachable
+    fn ping(&self) -> BoxFuture<'_, Result<(), DatabaseError>>;
+
+    /// List the storages that currently have their mapping cached
+    fn cached_storages(&self) -> Vec<CachedStorage> {
+        Vec::new()
+    }
+
+    /// Remove the cached mapping for a single storage, or the entire cache
+    fn flush_cache(&self, _storage: Option<u32>) {}
+}
+
+/// A storage with its mapping in the cache, for debugging
+#[derive(Debug, Serialize)]
+pub struct CachedStorage {
+    pub storage: u32,
+    /// Seconds until the cached mapping expires, zero if it already expired and is being served stale
+    pub ttl: u64,
+    /// Number of users with access to the storage
+    pub users: usize,
 }
 
+/// Time storage mappings are cached for
+const CACHE_TIME: Duration = Duration::from_secs(5 * 60);
+/// Default fraction to randomly shorten the cache time by
+pub const DEFAULT_CACHE_JITTER: f64 = 0.2;
+/// Number of times a query is retried after a transient database error
+pub const DEFAULT_DATABASE_RETRIES: usize = 2;
+/// Delay before the first retry of a query, doubled for every following retry, in milliseconds
+pub const DEFAULT_DATABASE_RETRY_BACKOFF: usize = 100;
+
+#[derive(Clone)]
 pub struct StorageMapping {
-    cache: DashMap<u32, CachedAccess, RandomState>,
+    cache: Arc<DashMap<u32, CachedAccess, RandomState>>,
+    group_cache: Arc<DashMap<String, CachedAccess<Vec<UserId>>, RandomState>>,
+    /// Storages that are currently being loaded, so concurrent misses wait for a single query
+    loading: Arc<DashMap<u32, Arc<Mutex<()>>, RandomState>>,
+    /// Number of storage mapping queries sent by this mapping
+    query_count: Arc<AtomicUsize>,
     connection: AnyPool,
+    /// Read only replica to use for the mapping queries
+    replica: Option<AnyPool>,
+    kind: AnyKind,
     prefix: String,
+    slow_query_threshold: Duration,
+    cache_jitter: f64,
+    stale_time: Duration,
+    retries: usize,
+    retry_backoff: Duration,
 }
 
 impl StorageMapping {
     pub fn from_connection(connection: AnyPool, prefix: String) -> Self {
+        let kind = connection.any_kind();
+        log::info!("Using {:?} database backend", kind);
         Self {
             cache: Default::default(),
+            group_cache: Default::default(),
+            loading: Default::default(),
+            query_count: Default::default(),
             connection,
+            replica: None,
+            kind,
             prefix,
+            slow_query_threshold: Duration::from_secs(1),
+            cache_jitter: DEFAULT_CACHE_JITTER,
+            stale_time: Duration::ZERO,
+            retries: DEFAULT_DATABASE_RETRIES,
+            retry_backoff: Duration::from_millis(DEFAULT_DATABASE_RETRY_BACKOFF as u64),
         }
     }
 
-    pub async fn new(options: AnyConnectOptions, prefix: String) -> Result<Self, DatabaseError> {
-        let connection = AnyPool::connect_with(options)
+    /// Log a warning for every mapping query that takes longer than the threshold
+    pub fn with_slow_query_threshold(mut self, threshold: Duration) -> Self {
+        self.slow_query_threshold = threshold;
+        self
+    }
+
+    /// Randomly shorten the cache time of entries by up to the fraction, to prevent entries created together from expiring together
+    pub fn with_cache_jitter(mut self, jitter: f64) -> Self {
+        self.cache_jitter = jitter.clamp(0.0, 1.0);
+        self
+    }
+
+    /// Keep using expired storage mappings for up to `stale_time` while they are refreshed in the background
+    pub fn with_stale_time(mut self, stale_time: Duration) -> Self {
+        self.stale_time = stale_time;
+        self
+    }
+
+    /// Retry queries that fail with a transient error up to `retries` times, with exponential backoff starting at `backoff`
+    pub fn with_retries(mut self, retries: usize, backoff: Duration) -> Self {
+        self.retries = retries;
+        self.retry_backoff = backoff;
+        self
+    }
+
+    fn cache_time(&self) -> Duration {
+        CACHE_TIME.mul_f64(1.0 - self.cache_jitter * thread_rng().gen::<f64>())
+    }
+
+    /// Send the mapping queries to a read only replica, falling back to the primary database if the replica fails
+    pub fn with_replica(mut self, replica: AnyPool) -> Self {
+        self.replica = Some(replica);
+        self
+    }
+
+    /// Create a mapping for the tables with another prefix in the same database, with its own cache
+    pub fn with_prefix(&self, prefix: String) -> Self {
+        StorageMapping {
+            cache: Default::default(),
+            group_cache: Default::default(),
+            loading: Default::default(),
+            query_count: Default::default(),
+            prefix,
+            ..self.clone()
+        }
+    }
+
+    pub async fn new(
+        options: AnyConnectOptions,
+        prefix: String,
+        pool_options: AnyPoolOptions,
+    ) -> Result<Self, DatabaseError> {
+        let connection = pool_options
+            .connect_with(options)
             .await
             .map_err(DatabaseError::Connect)?;
 
         Ok(Self::from_connection(connection, prefix))
     }
 
+    /// Check if the database is reachable
+    pub async fn ping(&self) -> Result<(), DatabaseError> {
+        sqlx::query("SELECT 1")
+            .execute(&self.connection)
+            .await
+            .map_err(DatabaseError::Query)?;
+        Ok(())
+    }
+
     async fn get_storage_mapping(
         &self,
         storage: u32,
     ) -> Result<Ref<'_, u32, CachedAccess, RandomState>, DatabaseError> {
-        if let Some(cached) = self.cache.get(&storage).filter(|cached| cached.is_valid()) {
-            Ok(cached)
-        } else {
-            let users = self.load_storage_mapping(storage).await?;
+        if let Some(cached) = self.cache.get(&storage) {
+            if cached.is_valid() {
+                return Ok(cached);
+            }
+            if cached.is_usable_stale(self.stale_time) {
+                if !cached.refreshing.swap(true, Ordering::SeqCst) {
+                    self.spawn_refresh(storage);
+                }
+                return Ok(cached);
+            }
+        }
 
-            self.cache.insert(storage, CachedAccess::new(users));
-            Ok(self.cache.get(&storage).unwrap())
+        let lock = self.loading.entry(storage).or_default().clone();
+        let guard = lock.lock().await;
+        // another lookup might have loaded the mapping while waiting for the lock
+        if let Some(cached) = self.cache.get(&storage) {
+            if cached.is_valid() {
+                drop(guard);
+                self.finish_loading(storage, &lock);
+                return Ok(cached);
+            }
         }
+
+        let result = self.load_storage_mapping(storage).await;
+        let cached = result.map(|users| {
+            self.cache
+                .entry(storage)
+                .insert(CachedAccess::new(users, self.cache_time()))
+                .downgrade()
+        });
+        drop(guard);
+        self.finish_loading(storage, &lock);
+        cached
+    }
+
+    /// Remove the load lock for the storage once no other lookup is waiting for it
+    fn finish_loading(&self, storage: u32, lock: &Arc<Mutex<()>>) {
+        // one reference is held by the map and one by the caller
+        self.loading.remove_if(&storage, |_, current| {
+            Arc::ptr_eq(current, lock) && Arc::strong_count(current) <= 2
+        });
+    }
+
+    /// Number of storage mapping queries sent by this mapping
+    pub fn query_count(&self) -> usize {
+        self.query_count.load(Ordering::Relaxed)
+    }
+
+    fn spawn_refresh(&self, storage: u32) {
+        debug!("refreshing expired storage mapping for {}", storage);
+        METRICS.add_cache_refresh();
+        let mapping = self.clone();
+        tokio::spawn(async move {
+            match mapping.load_storage_mapping(storage).await {
+                Ok(users) => {
+                    mapping
+                        .cache
+                        .insert(storage, CachedAccess::new(users, mapping.cache_time()));
+                }
+                Err(e) => {
+                    log::warn!("Failed to refresh storage mapping for {}: {:#}", storage, e);
+                    if let Some(cached) = mapping.cache.get(&storage) {
+                        cached.refreshing.store(false, Ordering::SeqCst);
+                    }
+                }
+            }
+        });
+    }
+
+    /// Remove all cached storage and group mappings
+    pub fn clear(&self) {
+        self.cache.clear();
+        self.group_cache.clear();
+    }
+
+    /// Remove the cached mapping for the storage
+    pub fn invalidate(&self, storage: u32) {
+        self.cache.remove(&storage);
+    }
+
+    /// List the cached storage mappings, sorted by storage id
+    pub fn cached_storages(&self) -> Vec<CachedStorage> {
+        let now = Instant::now();
+        let mut storages: Vec<CachedStorage> = self
+            .cache
+            .iter()
+            .map(|entry| CachedStorage {
+                storage: *entry.key(),
+                ttl: entry.valid_till.saturating_duration_since(now).as_secs(),
+                users: entry.access.len(),
+            })
+            .collect();
+        storages.sort_by_key(|cached| cached.storage);
+        storages
     }
 
     pub async fn get_users_for_storage_path(
@@ -81,19 +377,43 @@ impl StorageMapping {
         storage: u32,
         path: &str,
     ) -> Result<impl Iterator<Item = UserId>, DatabaseError> {
+        Ok(self
+            .get_user_paths_for_storage_path(storage, path)
+            .await?
+            .into_iter()
+            .map(|(user, _)| user))
+    }
+
+    /// Get all users that have access to the path in the storage, with the path as seen by each user
+    pub async fn get_user_paths_for_storage_path(
+        &self,
+        storage: u32,
+        path: &str,
+    ) -> Result<Vec<(UserId, String)>, DatabaseError> {
         let cached = self.get_storage_mapping(storage).await?;
         Ok(cached
             .access
             .iter()
-            .filter_map(move |access| {
-                if path.starts_with(&access.root) {
-                    Some(access.user.clone())
-                } else {
-                    None
-                }
-            })
-            .collect::<Vec<_>>()
-            .into_iter())
+            .filter_map(|access| Some((access.user.clone(), access.user_path(path)?)))
+            .collect())
+    }
+
+    /// Get all users that have access to any of the paths in the storage, using a single lookup of the storage mapping
+    pub async fn get_users_for_storage_paths(
+        &self,
+        storage: u32,
+        paths: &[String],
+    ) -> Result<Vec<UserId>, DatabaseError> {
+        let cached = self.get_storage_mapping(storage).await?;
+        let mut users: Vec<UserId> = Vec::new();
+        for access in cached.access.iter() {
+            if !users.contains(&access.user)
+                && paths.iter().any(|path| is_path_within(path, &access.root))
+            {
+                users.push(access.user.clone());
+            }
+        }
+        Ok(users)
     }
 
     async fn load_storage_mapping(
@@ -101,22 +421,777 @@ impl StorageMapping {
         storage: u32,
     ) -> Result<Vec<UserStorageAccess>, DatabaseError> {
         debug!("querying storage mapping for {}", storage);
-        let users = sqlx::query_as::<Any, UserStorageAccess>(&format!(
-            "\
-                SELECT user_id, path \
-                FROM {prefix}mounts \
-                INNER JOIN {prefix}filecache ON root_id = fileid \
-                WHERE storage_id = {storage}",
-            prefix = self.prefix,
-            storage = storage
-        ))
-        .fetch_all(&self.connection)
-        .await
-        .map_err(DatabaseError::Query)?;
+        let start = Instant::now();
+        let query = storage_mapping_query(self.kind, &self.prefix, storage);
+        let users = self
+            .fetch(|pool| sqlx::query_as::<Any, UserStorageAccess>(&query).fetch_all(pool))
+            .await?;
         METRICS.add_mapping_query();
+        self.query_count.fetch_add(1, Ordering::Relaxed);
+        self.record_query_duration(storage, start.elapsed());
 
         debug!("got storage mappings for {}: {:?}", storage, users);
 
         Ok(users)
     }
+
+    /// Run a query on the replica if configured, or the primary database otherwise
+    async fn fetch<'a, T, F, Fut>(&'a self, query: F) -> Result<T, DatabaseError>
+    where
+        F: Fn(&'a AnyPool) -> Fut,
+        Fut: Future<Output = Result<T, sqlx::Error>>,
+    {
+        if let Some(replica) = &self.replica {
+            match query(replica).await {
+                Ok(result) => return Ok(result),
+                Err(e) => log::warn!(
+                    "Failed to query database replica, falling back to primary: {}",
+                    e
+                ),
+            }
+        }
+        let mut attempt = 0;
+        loop {
+            match query(&self.connection).await {
+                Ok(result) => return Ok(result),
+                Err(e) if attempt < self.retries && is_transient(&e) => {
+                    let backoff = self.retry_backoff * (1 << attempt.min(16));
+                    log::warn!(
+                        "Transient database error, retrying in {}ms: {}",
+                        backoff.as_millis(),
+                        e
+                    );
+                    attempt += 1;
+                    sleep(backoff).await;
+                }
+                Err(e) => return Err(DatabaseError::Query(e)),
+            }
+        }
+    }
+
+    /// Record the query duration, returns true if the query was slow
+    fn record_query_duration(&self, storage: u32, duration: Duration) -> bool {
+        METRICS.query_duration.observe(duration);
+        let slow = duration > self.slow_query_threshold;
+        if slow {
+            log::warn!(
+                "Slow storage mapping query for storage {} took {}ms",
+                storage,
+                duration.as_millis()
+            );
+        }
+        slow
+    }
+
+    pub async fn get_users_for_group(&self, group: &str) -> Result<Vec<UserId>, DatabaseError> {
+        if let Some(cached) = self
+            .group_cache
+            .get(group)
+            .filter(|cached| cached.is_valid())
+        {
+            return Ok(cached.access.clone());
+        }
+
+        let users = self.load_group_members(group).await?;
+        self.group_cache.insert(
+            group.to_string(),
+            CachedAccess::new(users.clone(), self.cache_time()),
+        );
+        Ok(users)
+    }
+
+    async fn load_group_members(&self, group: &str) -> Result<Vec<UserId>, DatabaseError> {
+        debug!("querying group members for {}", group);
+        let query = group_members_query(self.kind, &self.prefix);
+        let users = self
+            .fetch(|pool| {
+                sqlx::query_scalar::<Any, UserId>(&query)
+                    .bind(group)
+                    .fetch_all(pool)
+            })
+            .await?;
+        METRICS.add_mapping_query();
+
+        debug!("got group members for {}: {:?}", group, users);
+
+        Ok(users)
+    }
+}
+
+impl UserResolver for StorageMapping {
+    fn get_users_for_storage_path<'a>(
+        &'a self,
+        storage: u32,
+        path: &'a str,
+    ) -> BoxFuture<'a, Result<Vec<UserId>, DatabaseError>> {
+        Box::pin(async move {
+            Ok(
+                StorageMapping::get_users_for_storage_path(self, storage, path)
+                    .await?
+                    .collect(),
+            )
+        })
+    }
+
+    fn get_user_paths_for_storage_path<'a>(
+        &'a self,
+        storage: u32,
+        path: &'a str,
+    ) -> BoxFuture<'a, Result<Vec<(UserId, String)>, DatabaseError>> {
+        Box::pin(StorageMapping::get_user_paths_for_storage_path(
+            self, storage, path,
+        ))
+    }
+
+    fn get_users_for_storage_paths<'a>(
+        &'a self,
+        storage: u32,
+        paths: &'a [String],
+    ) -> BoxFuture<'a, Result<Vec<UserId>, DatabaseError>> {
+        Box::pin(StorageMapping::get_users_for_storage_paths(
+            self, storage, paths,
+        ))
+    }
+
+    fn get_users_for_group<'a>(
+        &'a self,
+        group: &'a str,
+    ) -> BoxFuture<'a, Result<Vec<UserId>, DatabaseError>> {
+        Box::pin(StorageMapping::get_users_for_group(self, group))
+    }
+
+    fn ping(&self) -> BoxFuture<'_, Result<(), DatabaseError>> {
+        Box::pin(StorageMapping::ping(self))
+    }
+
+    fn cached_storages(&self) -> Vec<CachedStorage> {
+        StorageMapping::cached_storages(self)
+    }
+
+    fn flush_cache(&self, storage: Option<u32>) {
+        match storage {
+            Some(storage) => self.invalidate(storage),
+            None => self.clear(),
+        }
+    }
+}
+
+/// In memory user resolver, allows testing the event routing without a database
+#[derive(Default)]
+pub struct MockMapping {
+    storages: DashMap<u32, Vec<UserStorageAccess>, RandomState>,
+    groups: DashMap<String, Vec<UserId>, RandomState>,
+}
+
+impl MockMapping {
+    /// Give the user access to everything below the root in the storage, at the same path as in the storage
+    pub fn add_storage_mapping(&self, user: impl Into<UserId>, storage: u32, root: &str) {
+        // the user segment of the mount point isn't used for mapping paths
+        self.add_storage_mount(user, storage, root, &format!("/user/{}", root));
+    }
+
+    /// Give the user access to everything below the root in the storage, mounted at `/<user>/<path>`
+    pub fn add_storage_mount(
+        &self,
+        user: impl Into<UserId>,
+        storage: u32,
+        root: &str,
+        mount_point: &str,
+    ) {
+        self.storages
+            .entry(storage)
+            .or_default()
+            .push(UserStorageAccess {
+                user: user.into(),
+                root: root.into(),
+                mount_point: mount_point.into(),
+            });
+    }
+
+    pub fn add_group_member(&self, group: &str, user: impl Into<UserId>) {
+        self.groups
+            .entry(group.into())
+            .or_default()
+            .push(user.into());
+    }
+}
+
+impl UserResolver for MockMapping {
+    fn get_users_for_storage_path<'a>(
+        &'a self,
+        storage: u32,
+        path: &'a str,
+    ) -> BoxFuture<'a, Result<Vec<UserId>, DatabaseError>> {
+        let users = self
+            .storages
+            .get(&storage)
+            .map(|access| {
+                access
+                    .iter()
+                    .filter(|access| is_path_within(path, &access.root))
+                    .map(|access| access.user.clone())
+                    .collect()
+            })
+            .unwrap_or_default();
+        Box::pin(ready(Ok(users)))
+    }
+
+    fn get_user_paths_for_storage_path<'a>(
+        &'a self,
+        storage: u32,
+        path: &'a str,
+    ) -> BoxFuture<'a, Result<Vec<(UserId, String)>, DatabaseError>> {
+        let users = self
+            .storages
+            .get(&storage)
+            .map(|access| {
+                access
+                    .iter()
+                    .filter_map(|access| Some((access.user.clone(), access.user_path(path)?)))
+                    .collect()
+            })
+            .unwrap_or_default();
+        Box::pin(ready(Ok(users)))
+    }
+
+    fn get_users_for_group<'a>(
+        &'a self,
+        group: &'a str,
+    ) -> BoxFuture<'a, Result<Vec<UserId>, DatabaseError>> {
+        let users = self
+            .groups
+            .get(group)
+            .map(|members| members.clone())
+            .unwrap_or_default();
+        Box::pin(ready(Ok(users)))
+    }
+
+    fn ping(&self) -> BoxFuture<'_, Result<(), DatabaseError>> {
+        Box::pin(ready(Ok(())))
+    }
+}
+
+/// Check if the path is the root or inside of it, ignoring trailing slashes
+pub(crate) fn is_path_within(path: &str, root: &str) -> bool {
+    let path = path.trim_end_matches('/');
+    let root = root.trim_end_matches('/');
+    if root.is_empty() {
+        return true;
+    }
+    match path.strip_prefix(root) {
+        Some(rest) => rest.is_empty() || rest.starts_with('/'),
+        None => false,
+    }
+}
+
+/// Quote a (prefixed) table name using the quoting style of the database backend
+fn quote_table(kind: AnyKind, prefix: &str, table: &str) -> String {
+    match kind {
+        AnyKind::MySql => format!("`{}{}`", prefix.replace('`', "``"), table),
+        _ => format!("\"{}{}\"", prefix.replace('"', "\"\""), table),
+    }
+}
+
+/// Errors that might not happen again when retrying the query
+fn is_transient(e: &sqlx::Error) -> bool {
+    matches!(
+        e,
+        sqlx::Error::Io(_) | sqlx::Error::PoolTimedOut | sqlx::Error::WorkerCrashed
+    )
+}
+
+fn storage_mapping_query(kind: AnyKind, prefix: &str, storage: u32) -> String {
+    format!(
+        "\
+            SELECT DISTINCT user_id, path, mount_point \
+            FROM {mounts} \
+            INNER JOIN {filecache} ON root_id = fileid \
+            WHERE storage_id = {storage}",
+        mounts = quote_table(kind, prefix, "mounts"),
+        filecache = quote_table(kind, prefix, "filecache"),
+        storage = storage
+    )
+}
+
+fn group_members_query(kind: AnyKind, prefix: &str) -> String {
+    let placeholder = match kind {
+        AnyKind::Postgres => "$1",
+        _ => "?",
+    };
+    format!(
+        "SELECT uid FROM {group_user} WHERE gid = {placeholder}",
+        group_user = quote_table(kind, prefix, "group_user"),
+        placeholder = placeholder,
+    )
+}
+
+#[test]
+fn test_user_path() {
+    let access = |root: &str, mount_point: &str| UserStorageAccess {
+        user: "bar".into(),
+        root: root.into(),
+        mount_point: mount_point.into(),
+    };
+
+    let home = access("", "/bar/");
+    assert_eq!(
+        Some("files/foo.txt".into()),
+        home.user_path("files/foo.txt")
+    );
+
+    let share = access("files/Photos/2020", "/bar/files/Shared/Holiday/");
+    assert_eq!(
+        Some("files/Shared/Holiday/beach.jpg".into()),
+        share.user_path("files/Photos/2020/beach.jpg")
+    );
+    assert_eq!(
+        Some("files/Shared/Holiday".into()),
+        share.user_path("files/Photos/2020")
+    );
+    assert_eq!(None, share.user_path("files/Photos/2020-old/beach.jpg"));
+    assert_eq!(None, share.user_path("files/Photos"));
+}
+
+#[test]
+fn test_is_path_within() {
+    assert!(is_path_within("files/foo", "files/foo"));
+    assert!(is_path_within("files/foo/bar.txt", "files/foo"));
+    assert!(!is_path_within("files/foobar", "files/foo"));
+    assert!(!is_path_within("files/foobar/bar.txt", "files/foo"));
+    assert!(!is_path_within("files", "files/foo"));
+    assert!(is_path_within("files/foo/", "files/foo"));
+    assert!(is_path_within("files/foo", "files/foo/"));
+    assert!(is_path_within("files/foo/bar.txt", "files/foo/"));
+    assert!(!is_path_within("files/foobar", "files/foo/"));
+    assert!(is_path_within("files/foo", ""));
+    assert!(is_path_within("", ""));
+}
+
+#[test]
+fn test_storage_mapping_query_quoting() {
+    assert_eq!(
+        "SELECT DISTINCT user_id, path, mount_point FROM `oc_mounts` INNER JOIN `oc_filecache` ON root_id = fileid WHERE storage_id = 10",
+        storage_mapping_query(AnyKind::MySql, "oc_", 10)
+    );
+    assert_eq!(
+        r#"SELECT DISTINCT user_id, path, mount_point FROM "oc_mounts" INNER JOIN "oc_filecache" ON root_id = fileid WHERE storage_id = 10"#,
+        storage_mapping_query(AnyKind::Postgres, "oc_", 10)
+    );
+    assert_eq!(
+        r#"SELECT DISTINCT user_id, path, mount_point FROM "nc_mounts" INNER JOIN "nc_filecache" ON root_id = fileid WHERE storage_id = 10"#,
+        storage_mapping_query(AnyKind::Sqlite, "nc_", 10)
+    );
+}
+
+#[test]
+fn test_group_members_query_quoting() {
+    assert_eq!(
+        "SELECT uid FROM `oc_group_user` WHERE gid = ?",
+        group_members_query(AnyKind::MySql, "oc_")
+    );
+    assert_eq!(
+        r#"SELECT uid FROM "oc_group_user" WHERE gid = $1"#,
+        group_members_query(AnyKind::Postgres, "oc_")
+    );
+}
+
+#[tokio::test]
+async fn test_pool_size() {
+    let mapping = StorageMapping::new(
+        "sqlite::memory:".parse().unwrap(),
+        "oc_".into(),
+        AnyPoolOptions::new()
+            .max_connections(2)
+            .acquire_timeout(Duration::from_millis(100)),
+    )
+    .await
+    .unwrap();
+
+    let _first = mapping.connection.acquire().await.unwrap();
+    let _second = mapping.connection.acquire().await.unwrap();
+    assert!(mapping.connection.acquire().await.is_err());
+}
+
+#[tokio::test]
+async fn test_slow_query() {
+    let mapping = StorageMapping::new(
+        "sqlite::memory:".parse().unwrap(),
+        "oc_".into(),
+        AnyPoolOptions::new(),
+    )
+    .await
+    .unwrap()
+    .with_slow_query_threshold(Duration::from_millis(100));
+
+    assert!(!mapping.record_query_duration(1, Duration::from_millis(50)));
+    assert!(mapping.record_query_duration(1, Duration::from_millis(200)));
+}
+
+#[tokio::test]
+async fn test_mock_mapping() {
+    let mapping = MockMapping::default();
+    mapping.add_storage_mapping("foo", 10, "files");
+    mapping.add_storage_mapping("bar", 10, "files/shared");
+    mapping.add_group_member("group", "foo");
+    let resolver: &dyn UserResolver = &mapping;
+
+    assert_eq!(
+        vec![UserId::from("foo"), UserId::from("bar")],
+        resolver
+            .get_users_for_storage_path(10, "files/shared/foo.txt")
+            .await
+            .unwrap()
+    );
+    assert_eq!(
+        vec![UserId::from("foo")],
+        resolver
+            .get_users_for_storage_path(10, "files/other.txt")
+            .await
+            .unwrap()
+    );
+    assert!(resolver
+        .get_users_for_storage_path(11, "files")
+        .await
+        .unwrap()
+        .is_empty());
+    assert_eq!(
+        vec![UserId::from("foo")],
+        resolver.get_users_for_group("group").await.unwrap()
+    );
+}
+
+#[cfg(test)]
+async fn test_pool(root: &str) -> AnyPool {
+    let pool = AnyPoolOptions::new()
+        .max_connections(1)
+        .connect("sqlite::memory:")
+        .await
+        .unwrap();
+    sqlx::query("CREATE TABLE oc_mounts (user_id TEXT, storage_id INTEGER, root_id INTEGER, mount_point TEXT)")
+        .execute(&pool)
+        .await
+        .unwrap();
+    sqlx::query("CREATE TABLE oc_filecache (fileid INTEGER, path TEXT)")
+        .execute(&pool)
+        .await
+        .unwrap();
+    sqlx::query("INSERT INTO oc_mounts VALUES ('foo', 10, 1, '/foo/')")
+        .execute(&pool)
+        .await
+        .unwrap();
+    sqlx::query("INSERT INTO oc_filecache VALUES (1, ?)")
+        .bind(root)
+        .execute(&pool)
+        .await
+        .unwrap();
+    pool
+}
+
+#[tokio::test]
+async fn test_replica() {
+    let mapping = StorageMapping::from_connection(test_pool("primary").await, "oc_".into())
+        .with_replica(test_pool("replica").await);
+    let access = mapping.load_storage_mapping(10).await.unwrap();
+    assert_eq!("replica", access[0].root);
+
+    // the replica doesn't have the tables, so the query falls back to the primary
+    let broken_replica = AnyPoolOptions::new()
+        .max_connections(1)
+        .connect("sqlite::memory:")
+        .await
+        .unwrap();
+    let mapping = StorageMapping::from_connection(test_pool("primary").await, "oc_".into())
+        .with_replica(broken_replica);
+    let access = mapping.load_storage_mapping(10).await.unwrap();
+    assert_eq!("primary", access[0].root);
+}
+
+#[tokio::test]
+async fn test_duplicate_mounts() {
+    let pool = test_pool("files").await;
+    // the same root mounted twice for the same user
+    sqlx::query("INSERT INTO oc_mounts VALUES ('foo', 10, 1, '/foo/')")
+        .execute(&pool)
+        .await
+        .unwrap();
+    sqlx::query("INSERT INTO oc_mounts VALUES ('bar', 10, 1, '/bar/')")
+        .execute(&pool)
+        .await
+        .unwrap();
+    let mapping = StorageMapping::from_connection(pool, "oc_".into());
+    let access = mapping.load_storage_mapping(10).await.unwrap();
+    assert_eq!(2, access.len());
+    assert!(access
+        .iter()
+        .any(|access| access.user == UserId::from("foo")));
+    assert!(access
+        .iter()
+        .any(|access| access.user == UserId::from("bar")));
+}
+
+#[tokio::test]
+async fn test_retry_transient_error() {
+    use std::sync::atomic::AtomicUsize;
+
+    let mapping = StorageMapping::from_connection(test_pool("files").await, "oc_".into())
+        .with_retries(2, Duration::from_millis(1));
+
+    let attempts = AtomicUsize::new(0);
+    let result = mapping
+        .fetch(|pool| {
+            let attempt = attempts.fetch_add(1, Ordering::SeqCst);
+            async move {
+                if attempt == 0 {
+                    Err(sqlx::Error::Io(std::io::ErrorKind::ConnectionReset.into()))
+                } else {
+                    sqlx::query_as::<Any, UserStorageAccess>(&storage_mapping_query(
+                        AnyKind::Sqlite,
+                        "oc_",
+                        10,
+                    ))
+                    .fetch_all(pool)
+                    .await
+                }
+            }
+        })
+        .await
+        .unwrap();
+    assert_eq!(2, attempts.load(Ordering::SeqCst));
+    assert_eq!(UserId::from("foo"), result[0].user);
+
+    // permanent errors aren't retried
+    let attempts = AtomicUsize::new(0);
+    let result = mapping
+        .fetch(|pool| {
+            attempts.fetch_add(1, Ordering::SeqCst);
+            sqlx::query("SELECT * FROM missing").fetch_all(pool)
+        })
+        .await;
+    assert!(result.is_err());
+    assert_eq!(1, attempts.load(Ordering::SeqCst));
+
+    // transient errors are retried a limited number of times
+    let attempts = AtomicUsize::new(0);
+    let result = mapping
+        .fetch(|_| {
+            attempts.fetch_add(1, Ordering::SeqCst);
+            async { Err::<(), _>(sqlx::Error::PoolTimedOut) }
+        })
+        .await;
+    assert!(result.is_err());
+    assert_eq!(3, attempts.load(Ordering::SeqCst));
+}
+
+#[tokio::test]
+async fn test_cache_jitter() {
+    let mapping = StorageMapping::new(
+        "sqlite::memory:".parse().unwrap(),
+        "oc_".into(),
+        AnyPoolOptions::new(),
+    )
+    .await
+    .unwrap();
+
+    let times: Vec<Duration> = (0..10).map(|_| mapping.cache_time()).collect();
+    assert!(times.iter().any(|time| *time != times[0]));
+    assert!(times
+        .iter()
+        .all(|time| *time > CACHE_TIME.mul_f64(0.8) && *time <= CACHE_TIME));
+
+    let mapping = mapping.with_cache_jitter(0.0);
+    assert_eq!(CACHE_TIME, mapping.cache_time());
+}
+
+#[tokio::test]
+async fn test_stale_refresh() {
+    let mapping = StorageMapping::from_connection(test_pool("files").await, "oc_".into())
+        .with_stale_time(Duration::from_secs(60));
+    let stale = CachedAccess::new(
+        vec![UserStorageAccess {
+            user: "stale".into(),
+            root: "".into(),
+            mount_point: "/stale/".into(),
+        }],
+        Duration::ZERO,
+    );
+    mapping.cache.insert(10, stale);
+
+    let refreshes = METRICS.cache_refreshes();
+    for _ in 0..2 {
+        let users: Vec<UserId> = mapping
+            .get_users_for_storage_path(10, "files/foo.txt")
+            .await
+            .unwrap()
+            .collect();
+        assert_eq!(vec![UserId::from("stale")], users);
+    }
+    assert_eq!(refreshes + 1, METRICS.cache_refreshes());
+
+    for _ in 0..100 {
+        if mapping.cache.get(&10).unwrap().is_valid() {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    let users: Vec<UserId> = mapping
+        .get_users_for_storage_path(10, "files/foo.txt")
+        .await
+        .unwrap()
+        .collect();
+    assert_eq!(vec![UserId::from("foo")], users);
+}
+
+#[tokio::test]
+async fn test_nested_share_mount() {
+    let pool = test_pool("").await;
+    // the owner's "files/Photos/2020" folder is shared with bar and mounted in a subfolder
+    sqlx::query("INSERT INTO oc_mounts VALUES ('bar', 10, 2, '/bar/files/Shared/Holiday/')")
+        .execute(&pool)
+        .await
+        .unwrap();
+    sqlx::query("INSERT INTO oc_filecache VALUES (2, 'files/Photos/2020')")
+        .execute(&pool)
+        .await
+        .unwrap();
+    let mapping = StorageMapping::from_connection(pool, "oc_".into());
+
+    let mut paths = mapping
+        .get_user_paths_for_storage_path(10, "files/Photos/2020/beach.jpg")
+        .await
+        .unwrap();
+    paths.sort_by(|a, b| a.1.cmp(&b.1));
+    assert_eq!(
+        vec![
+            (
+                UserId::from("foo"),
+                "files/Photos/2020/beach.jpg".to_string()
+            ),
+            (
+                UserId::from("bar"),
+                "files/Shared/Holiday/beach.jpg".to_string()
+            ),
+        ],
+        paths
+    );
+
+    let paths = mapping
+        .get_user_paths_for_storage_path(10, "files/Photos/2021/beach.jpg")
+        .await
+        .unwrap();
+    assert_eq!(
+        vec![(
+            UserId::from("foo"),
+            "files/Photos/2021/beach.jpg".to_string()
+        )],
+        paths
+    );
+}
+
+#[tokio::test]
+async fn test_cached_storages() {
+    let mapping = StorageMapping::from_connection(test_pool("files").await, "oc_".into());
+    assert!(mapping.cached_storages().is_empty());
+
+    mapping.get_storage_mapping(10).await.unwrap();
+    let cached = mapping.cached_storages();
+    assert_eq!(1, cached.len());
+    assert_eq!(10, cached[0].storage);
+    assert_eq!(1, cached[0].users);
+    assert!(cached[0].ttl > 0);
+}
+
+#[tokio::test]
+async fn test_flush_cache() {
+    let pool = test_pool("").await;
+    let mapping = StorageMapping::from_connection(pool.clone(), "oc_".into());
+    let users = |mapping: &StorageMapping| {
+        let mapping = mapping.clone();
+        async move {
+            mapping
+                .get_users_for_storage_path(10, "files")
+                .await
+                .unwrap()
+                .count()
+        }
+    };
+    assert_eq!(1, users(&mapping).await);
+
+    sqlx::query("INSERT INTO oc_mounts VALUES ('bar', 10, 1, '/bar/')")
+        .execute(&pool)
+        .await
+        .unwrap();
+    assert_eq!(1, users(&mapping).await);
+
+    // flushing another storage doesn't affect the cached mapping
+    mapping.invalidate(11);
+    assert_eq!(1, users(&mapping).await);
+
+    mapping.invalidate(10);
+    assert_eq!(2, users(&mapping).await);
+
+    sqlx::query("INSERT INTO oc_mounts VALUES ('baz', 10, 1, '/baz/')")
+        .execute(&pool)
+        .await
+        .unwrap();
+    mapping.clear();
+    assert!(mapping.cached_storages().is_empty());
+    assert_eq!(3, users(&mapping).await);
+}
+
+#[tokio::test]
+async fn test_storage_paths() {
+    let pool = test_pool("files/shared").await;
+    sqlx::query("INSERT INTO oc_mounts VALUES ('bar', 10, 2, '/bar/files/other/'), ('bar', 10, 3, '/bar/files/third/')")
+        .execute(&pool)
+        .await
+        .unwrap();
+    sqlx::query("INSERT INTO oc_filecache VALUES (2, 'files/other'), (3, 'files/third')")
+        .execute(&pool)
+        .await
+        .unwrap();
+    let mapping = StorageMapping::from_connection(pool, "oc_".into());
+
+    let paths: Vec<String> = (0..50)
+        .map(|i| match i % 3 {
+            0 => format!("files/shared/{}.txt", i),
+            1 => format!("files/other/{}.txt", i),
+            _ => format!("files/third/{}.txt", i),
+        })
+        .collect();
+    assert_eq!(
+        vec![UserId::from("foo"), UserId::from("bar")],
+        mapping
+            .get_users_for_storage_paths(10, &paths)
+            .await
+            .unwrap()
+    );
+    assert_eq!(
+        vec![UserId::from("bar")],
+        mapping
+            .get_users_for_storage_paths(10, &paths[1..2])
+            .await
+            .unwrap()
+    );
+    assert_eq!(1, mapping.cache.len());
+}
+
+#[tokio::test]
+async fn test_concurrent_loads() {
+    let mapping = StorageMapping::from_connection(test_pool("files").await, "oc_".into());
+
+    let lookups = (0..16).map(|_| {
+        let mapping = mapping.clone();
+        tokio::spawn(async move {
+            mapping
+                .get_users_for_storage_path(10, "files/foo")
+                .await
+                .map(|users| users.count())
+        })
+    });
+    for lookup in futures::future::join_all(lookups).await {
+        assert_eq!(1, lookup.unwrap().unwrap());
+    }
+
+    assert_eq!(1, mapping.query_count());
+    assert!(mapping.loading.is_empty());
 }
@@ -10,32 +10,86 @@ use sqlx::database::HasValueRef;
 use sqlx::error::BoxDynError;
 use sqlx::{Database, Decode, Type};
 use std::fmt;
-use std::hash::{BuildHasher, Hasher};
+use std::hash::{BuildHasher, Hash, Hasher};
+use std::sync::atomic::{AtomicBool, Ordering};
 
 static USER_NAMES: Lazy<DashMap<u64, String, PassthruHasher>> = Lazy::new(DashMap::default);
 
+/// Keep track of user names regardless of the log level
+static RECORD_USER_NAMES: AtomicBool = AtomicBool::new(false);
+
+/// Keep track of user names for all users, for sinks that need to know the user name
+pub fn record_user_names() {
+    RECORD_USER_NAMES.store(true, Ordering::Relaxed);
+}
+
 // Use the same hash state for generating user hash for every instance
 static RANDOM_STATE: OnceBox<RandomState> = OnceBox::new();
 
-#[derive(Clone, Eq, PartialEq, Hash)]
+fn hash_user_id(user_id: &str) -> u64 {
+    let state = RANDOM_STATE.get_or_init(|| Box::new(RandomState::new()));
+    let mut hash = state.build_hasher();
+    hash.write(user_id.as_bytes());
+    hash.finish()
+}
+
+#[derive(Clone)]
 pub struct UserId {
     hash: u64,
+    /// Hash of the lowercase user id, the same as `hash` if the user id has no uppercase characters
+    folded: u64,
 }
 
 impl UserId {
     pub fn new(user_id: &str) -> Self {
-        let state = RANDOM_STATE.get_or_init(|| Box::new(RandomState::new()));
-        let mut hash = state.build_hasher();
-        hash.write(user_id.as_bytes());
-        let hash = hash.finish();
+        let hash = hash_user_id(user_id);
+        let folded = if user_id.chars().any(char::is_uppercase) {
+            hash_user_id(&user_id.to_lowercase())
+        } else {
+            hash
+        };
 
-        if log::max_level() >= LevelFilter::Info {
+        if log::max_level() >= LevelFilter::Info || RECORD_USER_NAMES.load(Ordering::Relaxed) {
             USER_NAMES
                 .entry(hash)
                 .or_insert_with(|| user_id.to_string());
+            if folded != hash {
+                USER_NAMES
+                    .entry(folded)
+                    .or_insert_with(|| user_id.to_string());
+            }
+        }
+
+        UserId { hash, folded }
+    }
+
+    /// The id for the user when ignoring case, for case-insensitive user backends
+    ///
+    /// The user name as first seen is kept for display
+    pub fn normalized(&self) -> UserId {
+        UserId {
+            hash: self.folded,
+            folded: self.folded,
         }
+    }
+
+    /// The name of the user, if user names are being recorded
+    pub fn name(&self) -> Option<String> {
+        USER_NAMES.get(&self.hash).map(|name| name.value().clone())
+    }
+}
+
+impl PartialEq for UserId {
+    fn eq(&self, other: &Self) -> bool {
+        self.hash == other.hash
+    }
+}
+
+impl Eq for UserId {}
 
-        UserId { hash }
+impl Hash for UserId {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.hash.hash(state);
     }
 }
 
@@ -1,34 +1,61 @@
 use dashmap::DashMap;
 use flexi_logger::{Logger, LoggerHandle};
 use futures::future::select;
+use futures::future::BoxFuture;
 use futures::{pin_mut, FutureExt};
 use futures::{SinkExt, StreamExt};
 use http_auth_basic::Credentials;
-use notify_push::config::{Bind, Config};
-use notify_push::message::DEBOUNCE_ENABLE;
-use notify_push::{listen_loop, serve, App};
+use notify_push::config::{Bind, Config, LogFormat, SocketOwner, TlsConfig};
+use notify_push::connection::{Delivery, QueuedMessage};
+use notify_push::error::{AuthenticationError, DatabaseError};
+use notify_push::event::{OverflowPolicy, RedisMode};
+use notify_push::message::{DebounceMode, DEBOUNCE_ENABLE};
+use notify_push::metrics::{serve_metrics, METRICS};
+use notify_push::nc::Authenticator;
+use notify_push::proxy::default_trusted_proxies;
+use notify_push::rate_limit::ThrottlePolicy;
+use notify_push::sink::NotificationSink;
+use notify_push::storage_mapping::{MockMapping, UserResolver};
+use notify_push::UserId;
+use notify_push::{
+    cookie_watchdog, database_keepalive, listen_loop, serve, startup_connect, App, Error,
+};
 use once_cell::sync::Lazy;
+use rand::Rng;
 use redis::AsyncCommands;
 use smallvec::alloc::sync::Arc;
 use sqlx::AnyPool;
-use std::net::SocketAddr;
-use std::sync::atomic::Ordering;
-use std::sync::Mutex;
-use tokio::net::{TcpListener, TcpStream};
-use tokio::sync::oneshot;
+use std::convert::Infallible;
+use std::net::{IpAddr, SocketAddr};
+use std::os::unix::fs::{MetadataExt, PermissionsExt};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU16, AtomicUsize, Ordering};
+use tokio::net::{TcpListener, TcpStream, UnixStream};
+use tokio::sync::{oneshot, Mutex};
 use tokio::task::spawn;
 use tokio::time::timeout;
-use tokio::time::{sleep, Duration};
+use tokio::time::{sleep, Duration, Instant};
 use tokio_stream::wrappers::TcpListenerStream;
+use tokio_tungstenite::tungstenite::client::IntoClientRequest;
+use tokio_tungstenite::tungstenite::protocol::frame::coding::CloseCode;
 use tokio_tungstenite::tungstenite::Message;
 use tokio_tungstenite::{MaybeTlsStream, WebSocketStream};
 use warp::http::StatusCode;
 use warp::{Filter, Reply};
 
 static LAST_PORT: Lazy<Mutex<u16>> = Lazy::new(|| Mutex::new(1024));
+static LAST_SOCKET: AtomicU16 = AtomicU16::new(0);
+
+fn temp_socket_path() -> PathBuf {
+    std::env::temp_dir().join(format!(
+        "notify_push_test_{}_{}.sock",
+        std::process::id(),
+        LAST_SOCKET.fetch_add(1, Ordering::SeqCst)
+    ))
+}
 
 async fn listen_available_port() -> Option<TcpListener> {
-    let mut last_port = LAST_PORT.lock().unwrap();
+    let mut last_port = LAST_PORT.lock().await;
     for port in (*last_port + 1)..65535 {
         if let Ok(tcp) = TcpListener::bind(("127.0.0.1", port)).await {
             *last_port = port;
@@ -80,7 +107,7 @@ impl Services {
             .execute(&db)
             .await
             .unwrap();
-        sqlx::query("CREATE TABLE oc_mounts(storage_id BIGINT, root_id BIGINT, user_id TEXT)")
+        sqlx::query("CREATE TABLE oc_mounts(storage_id BIGINT, root_id BIGINT, user_id TEXT, mount_point TEXT)")
             .execute(&db)
             .await
             .unwrap();
@@ -92,6 +119,10 @@ impl Services {
             .execute(&db)
             .await
             .unwrap();
+        sqlx::query("CREATE TABLE oc_group_user(gid TEXT, uid TEXT)")
+            .execute(&db)
+            .await
+            .unwrap();
 
         let users: Arc<DashMap<String, String>> = Arc::default();
 
@@ -114,11 +145,27 @@ impl Services {
                 }
             });
 
+        // publishes the test cookie to redis like the nextcloud app does
+        let cookie = warp::path!("index.php" / "apps" / "notify_push" / "test" / "cookie")
+            .and_then(move || async move {
+                let cookie: u32 = rand::thread_rng().gen_range(1..u32::MAX);
+                let mut redis = redis::Client::open(format!("redis://{}", redis_addr))
+                    .unwrap()
+                    .get_async_connection()
+                    .await
+                    .unwrap();
+                redis
+                    .publish::<_, _, ()>("notify_test_cookie", cookie)
+                    .await
+                    .unwrap();
+                Result::<_, Infallible>::Ok(cookie.to_string())
+            });
+
         let (redis_shutdown, redis_shutdown_rx) = oneshot::channel();
         let (nextcloud_shutdown, nextcloud_shutdown_rx) = oneshot::channel();
 
         spawn(async move {
-            warp::serve(uid)
+            warp::serve(cookie.or(uid))
                 .serve_incoming_with_graceful_shutdown(
                     TcpListenerStream::new(nextcloud_tcp),
                     nextcloud_shutdown_rx.map(|_| ()),
@@ -145,9 +192,11 @@ impl Services {
         Config {
             database: "sqlite::memory:?cache=shared".parse().unwrap(),
             database_prefix: "oc_".to_string(),
-            redis: vec![format!("redis://{}", self.redis.to_string())
-                .parse()
-                .unwrap()],
+            database_pool_size: 10,
+            database_acquire_timeout: 30,
+            database_idle_timeout: 600,
+            slow_query_ms: 1000,
+            redis: vec![format!("redis://{}", self.redis).parse().unwrap()],
             nextcloud_url: format!("http://{}/", self.nextcloud),
             metrics_bind: None,
             log_level: "".to_string(),
@@ -157,6 +206,49 @@ impl Services {
             tls: None,
             max_debounce_time: 15,
             max_connection_time: 0,
+            admin_secret: Some("secret".to_string()),
+            auth_rate_limit: 3,
+            auth_rate_window: 60,
+            credential_cache_ttl: 0,
+            redis_sharded: false,
+            redis_prefix: String::new(),
+            log_format: LogFormat::Text,
+            stats_interval: 0,
+            dual_stack: false,
+            dry_run: false,
+            send_queue_size: 64,
+            allowed_channels: Vec::new(),
+            database_url_replica: None,
+            cache_jitter: 0.2,
+            cache_stale_time: 60,
+            event_buffer_size: 1024,
+            event_overflow: OverflowPolicy::Block,
+            auth_timeout: 15,
+            trusted_proxies: default_trusted_proxies(),
+            max_connections: None,
+            allowed_origins: Vec::new(),
+            database_retries: 2,
+            database_retry_backoff: 100,
+            ignore_paths: Vec::new(),
+            webhook_url: None,
+            disabled_events: Vec::new(),
+            idle_timeout: 0,
+            instance_id: None,
+            debounce_mode: DebounceMode::Trailing,
+            database_keepalive: 0,
+            metrics_path: None,
+            redis_mode: RedisMode::Channel,
+            cookie_watchdog: 0,
+            listen_only: false,
+            max_auth_message_length: 4096,
+            instance_prefixes: Vec::new(),
+            startup_timeout: 30,
+            heartbeat_interval: 0,
+            replay_buffer_size: 0,
+            replay_retention: 60,
+            max_events_per_second: 0,
+            event_throttle: ThrottlePolicy::Delay,
+            normalize_user_ids: false,
         }
     }
 
@@ -168,7 +260,6 @@ impl Services {
     }
 
     async fn spawn_server(&self) -> ServerHandle {
-        let app = Arc::new(self.app().await);
         let addr = async {
             let tcp = listen_available_port().await.unwrap();
             tcp.local_addr()
@@ -176,27 +267,20 @@ impl Services {
         .await
         .unwrap();
 
-        let (serve_tx, serve_rx) = oneshot::channel();
-        let (listen_tx, listen_rx) = oneshot::channel();
-
-        let bind = Bind::Tcp(addr);
-        spawn(async move {
-            let serve = serve(app.clone(), bind, serve_rx, None, 15, 0).unwrap();
-            let listen = listen_loop(app.clone(), listen_rx);
-
-            pin_mut!(serve);
-            pin_mut!(listen);
-
-            select(serve, listen).await;
-        });
+        self.spawn_server_at(Bind::Tcp(addr), None).await
+    }
 
-        sleep(Duration::from_millis(10)).await;
+    async fn spawn_server_at(&self, bind: Bind, tls: Option<TlsConfig>) -> ServerHandle {
+        spawn_app_at(self.app().await, bind, tls).await
+    }
 
-        ServerHandle {
-            _serve_handle: serve_tx,
-            _listen_handle: listen_tx,
-            port: addr.port(),
-        }
+    async fn spawn_server_with_resolver(
+        &self,
+        resolver: impl UserResolver + 'static,
+    ) -> ServerHandle {
+        let addr = listen_available_port().await.unwrap().local_addr().unwrap();
+        let app = self.app().await.with_user_resolver(resolver);
+        spawn_app_at(app, Bind::Tcp(addr), None).await
     }
 
     async fn redis_client(&self) -> redis::aio::Connection {
@@ -209,9 +293,21 @@ impl Services {
     }
 
     async fn add_storage_mapping(&self, username: &str, storage: u32, root: u32) {
-        sqlx::query("INSERT INTO oc_mounts(storage_id, root_id, user_id) VALUES(?, ?, ?)")
-            .bind(storage as i64)
-            .bind(root as i64)
+        sqlx::query(
+            "INSERT INTO oc_mounts(storage_id, root_id, user_id, mount_point) VALUES(?, ?, ?, ?)",
+        )
+        .bind(storage as i64)
+        .bind(root as i64)
+        .bind(username)
+        .bind(format!("/{}/", username))
+        .execute(&self.db)
+        .await
+        .unwrap();
+    }
+
+    async fn add_group_member(&self, group: &str, username: &str) {
+        sqlx::query("INSERT INTO oc_group_user(gid, uid) VALUES(?, ?)")
+            .bind(group)
             .bind(username)
             .execute(&self.db)
             .await
@@ -228,6 +324,66 @@ impl Services {
     }
 }
 
+async fn spawn_app_at(app: App, bind: Bind, tls: Option<TlsConfig>) -> ServerHandle {
+    let app = Arc::new(app);
+    let port = match &bind {
+        Bind::Tcp(addr) => addr.port(),
+        Bind::DualStack(port) => *port,
+        Bind::Unix(..) => 0,
+    };
+
+    let (serve_tx, serve_rx) = oneshot::channel();
+    let (listen_tx, listen_rx) = oneshot::channel();
+
+    spawn(async move {
+        let serve = serve(app.clone(), bind, serve_rx, tls.as_ref(), 15, 0).unwrap();
+        let listen = listen_loop(app.clone(), listen_rx);
+
+        pin_mut!(serve);
+        pin_mut!(listen);
+
+        select(serve, listen).await;
+    });
+
+    sleep(Duration::from_millis(10)).await;
+
+    ServerHandle {
+        _serve_handle: serve_tx,
+        _listen_handle: listen_tx,
+        port,
+    }
+}
+
+struct MetricsHandle {
+    _serve_handle: oneshot::Sender<()>,
+    port: u16,
+}
+
+async fn spawn_metrics_server() -> MetricsHandle {
+    let addr = listen_available_port().await.unwrap().local_addr().unwrap();
+
+    let (serve_tx, serve_rx) = oneshot::channel();
+    spawn(serve_metrics(Bind::Tcp(addr), serve_rx, None).unwrap());
+
+    sleep(Duration::from_millis(10)).await;
+
+    MetricsHandle {
+        _serve_handle: serve_tx,
+        port: addr.port(),
+    }
+}
+
+impl MetricsHandle {
+    async fn get(&self) -> String {
+        reqwest::get(format!("http://127.0.0.1:{}/metrics", self.port))
+            .await
+            .unwrap()
+            .text()
+            .await
+            .unwrap()
+    }
+}
+
 struct ServerHandle {
     _serve_handle: oneshot::Sender<()>,
     _listen_handle: oneshot::Sender<()>,
@@ -242,6 +398,25 @@ impl ServerHandle {
             .0
     }
 
+    async fn connect_with_protocol(
+        &self,
+        protocols: &str,
+    ) -> Result<
+        (
+            WebSocketStream<MaybeTlsStream<TcpStream>>,
+            tokio_tungstenite::tungstenite::handshake::client::Response,
+        ),
+        tokio_tungstenite::tungstenite::Error,
+    > {
+        let mut request = format!("ws://127.0.0.1:{}/ws", self.port)
+            .into_client_request()
+            .unwrap();
+        request
+            .headers_mut()
+            .insert("sec-websocket-protocol", protocols.parse().unwrap());
+        tokio_tungstenite::connect_async(request).await
+    }
+
     async fn connect_auth(
         &self,
         username: &str,
@@ -280,12 +455,279 @@ async fn test_auth_failure() {
     let services = Services::new().await;
     services.add_user("foo", "bar");
 
+    let server_handle = services.spawn_server().await;
+    let invalid = auth_failures("invalid_credentials");
+    let mut client = server_handle.connect().await;
+    client.send(Message::Text("foo".into())).await.unwrap();
+    client.send(Message::Text("not_bar".into())).await.unwrap();
+
+    assert_next_message(&mut client, "err: Invalid credentials").await;
+    assert!(auth_failures("invalid_credentials") > invalid);
+}
+
+/// Accepts a single static token for any username
+struct StaticTokenAuthenticator {
+    token: &'static str,
+}
+
+impl Authenticator for StaticTokenAuthenticator {
+    fn verify_credentials<'a>(
+        &'a self,
+        username: &'a str,
+        password: &'a str,
+        _forwarded_for: Vec<IpAddr>,
+    ) -> BoxFuture<'a, Result<String, AuthenticationError>> {
+        Box::pin(async move {
+            if password == self.token {
+                Ok(username.to_string())
+            } else {
+                Err(AuthenticationError::Invalid)
+            }
+        })
+    }
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+async fn test_custom_authenticator() {
+    let services = Services::new().await;
+    // the nextcloud credentials aren't accepted by the custom authenticator
+    services.add_user("foo", "bar");
+
+    let addr = listen_available_port().await.unwrap().local_addr().unwrap();
+    let app = services
+        .app()
+        .await
+        .with_authenticator(StaticTokenAuthenticator { token: "secret" });
+    let server_handle = spawn_app_at(app, Bind::Tcp(addr), None).await;
+
+    server_handle.connect_auth("foo", "secret").await;
+
+    let mut client = server_handle.connect().await;
+    client.send(Message::Text("foo".into())).await.unwrap();
+    client.send(Message::Text("bar".into())).await.unwrap();
+    assert_next_message(&mut client, "err: Invalid credentials").await;
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+async fn test_nextcloud_unavailable() {
+    let services = Services::new().await;
+    services.add_user("foo", "bar");
+
+    let mut config = services.config();
+    let unused = listen_available_port().await.unwrap().local_addr().unwrap();
+    config.nextcloud_url = format!("http://{}/", unused);
+    let app = App::with_connection(services.db.clone(), config, LOG_HANDLE.clone(), false)
+        .await
+        .unwrap();
+    let addr = listen_available_port().await.unwrap().local_addr().unwrap();
+    let server_handle = spawn_app_at(app, Bind::Tcp(addr), None).await;
+
+    let response = reqwest::get(format!(
+        "http://127.0.0.1:{}/test/reverse_cookie",
+        server_handle.port
+    ))
+    .await
+    .unwrap();
+    assert_eq!(StatusCode::OK, response.status());
+    assert!(response.text().await.unwrap().contains("connect"));
+
+    let mut client =
+        tokio_tungstenite::connect_async(format!("ws://127.0.0.1:{}/ws", server_handle.port))
+            .await
+            .unwrap()
+            .0;
+    client.send(Message::Text("foo".into())).await.unwrap();
+    client.send(Message::Text("bar".into())).await.unwrap();
+    let message = timeout(Duration::from_secs(5), client.next())
+        .await
+        .unwrap()
+        .unwrap()
+        .unwrap();
+    assert!(message.to_text().unwrap().starts_with("err: "));
+    match client.next().await {
+        Some(Ok(Message::Close(Some(frame)))) => assert_eq!(CloseCode::Error, frame.code),
+        message => panic!("expected close frame, got {:?}", message),
+    }
+
+    // the server keeps running
+    assert_eq!(
+        StatusCode::OK,
+        reqwest::get(format!("http://127.0.0.1:{}/health", server_handle.port))
+            .await
+            .unwrap()
+            .status()
+    );
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+async fn test_auth_timeout() {
+    let services = Services::new().await;
+    services.add_user("foo", "bar");
+
+    let mut config = services.config();
+    config.auth_timeout = 1;
+    let app = App::with_connection(services.db.clone(), config, LOG_HANDLE.clone(), false)
+        .await
+        .unwrap();
+    let addr = listen_available_port().await.unwrap().local_addr().unwrap();
+    let server_handle = spawn_app_at(app, Bind::Tcp(addr), None).await;
+
+    let connect = || async {
+        tokio_tungstenite::connect_async(format!("ws://127.0.0.1:{}/ws", server_handle.port))
+            .await
+            .unwrap()
+            .0
+    };
+
+    // slow, but within the timeout
+    let mut client = connect().await;
+    client.send(Message::Text("foo".into())).await.unwrap();
+    sleep(Duration::from_millis(500)).await;
+    client.send(Message::Text("bar".into())).await.unwrap();
+    assert_next_message(&mut client, "authenticated").await;
+
+    // too slow
+    let timeouts = auth_failures("timeout");
+    let mut client = connect().await;
+    client.send(Message::Text("foo".into())).await.unwrap();
+    sleep(Duration::from_millis(1200)).await;
+    client.send(Message::Text("bar".into())).await.ok();
+    assert_next_message(&mut client, "Authentication timeout").await;
+    assert!(auth_failures("timeout") > timeouts);
+}
+
+fn auth_failures(reason: &str) -> usize {
+    METRICS
+        .authentication_failures_by_reason()
+        .find(|(known, _)| *known == reason)
+        .map(|(_, count)| count)
+        .unwrap()
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+async fn test_idle_timeout() {
+    let services = Services::new().await;
+    services.add_user("foo", "bar");
+
+    let mut config = services.config();
+    config.idle_timeout = 1;
+    let app = App::with_connection(services.db.clone(), config, LOG_HANDLE.clone(), false)
+        .await
+        .unwrap();
+    let addr = listen_available_port().await.unwrap().local_addr().unwrap();
+    let server_handle = spawn_app_at(app, Bind::Tcp(addr), None).await;
+
+    let mut idle = server_handle.connect_auth("foo", "bar").await;
+    let mut active = server_handle.connect_auth("foo", "bar").await;
+
+    for _ in 0..10 {
+        sleep(Duration::from_millis(250)).await;
+        active
+            .send(Message::Text("client test".into()))
+            .await
+            .unwrap();
+    }
+
+    loop {
+        match timeout(Duration::from_secs(1), idle.next()).await {
+            Ok(Some(Ok(Message::Ping(_)))) => {}
+            Ok(Some(Ok(Message::Close(Some(frame))))) => {
+                assert_eq!("Idle timeout", frame.reason);
+                break;
+            }
+            msg => panic!("expected idle connection to be closed, got {:?}", msg),
+        }
+    }
+    // the active connection only got the initial ping
+    assert!(matches!(active.next().await, Some(Ok(Message::Ping(_)))));
+    assert_no_message(&mut active).await;
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+async fn test_max_connections() {
+    let services = Services::new().await;
+    services.add_user("foo", "bar");
+
+    let mut config = services.config();
+    config.max_connections = Some(2);
+    let app = App::with_connection(services.db.clone(), config, LOG_HANDLE.clone(), false)
+        .await
+        .unwrap();
+    let addr = listen_available_port().await.unwrap().local_addr().unwrap();
+    let server_handle = spawn_app_at(app, Bind::Tcp(addr), None).await;
+
+    let mut first = server_handle.connect_auth("foo", "bar").await;
+    let _second = server_handle.connect_auth("foo", "bar").await;
+
+    let rejected = METRICS.rejected_connections();
+    match tokio_tungstenite::connect_async(format!("ws://127.0.0.1:{}/ws", server_handle.port))
+        .await
+    {
+        Err(tokio_tungstenite::tungstenite::Error::Http(response)) => {
+            assert_eq!(StatusCode::SERVICE_UNAVAILABLE, response.status());
+        }
+        Err(e) => panic!("unexpected error {}", e),
+        Ok(_) => panic!("connection over the limit was accepted"),
+    }
+    assert!(METRICS.rejected_connections() > rejected);
+
+    // closing a connection frees up a slot
+    first.close(None).await.unwrap();
+    sleep(Duration::from_millis(100)).await;
+    let _third = server_handle.connect_auth("foo", "bar").await;
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+async fn test_auth_failure_close_frame() {
+    let services = Services::new().await;
+    services.add_user("foo", "bar");
+
     let server_handle = services.spawn_server().await;
     let mut client = server_handle.connect().await;
     client.send(Message::Text("foo".into())).await.unwrap();
     client.send(Message::Text("not_bar".into())).await.unwrap();
 
     assert_next_message(&mut client, "err: Invalid credentials").await;
+    match timeout(Duration::from_millis(500), client.next())
+        .await
+        .unwrap()
+        .unwrap()
+        .unwrap()
+    {
+        Message::Close(Some(frame)) => {
+            assert_eq!(CloseCode::Policy, frame.code);
+            assert_eq!("Invalid credentials", frame.reason);
+        }
+        msg => panic!("expected close frame, got {:?}", msg),
+    }
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+async fn test_auth_message_too_large() {
+    let services = Services::new().await;
+    let mut config = services.config();
+    config.max_auth_message_length = 16;
+    let app = App::with_connection(services.db.clone(), config, LOG_HANDLE.clone(), false)
+        .await
+        .unwrap();
+    let addr = listen_available_port().await.unwrap().local_addr().unwrap();
+    let server_handle = spawn_app_at(app, Bind::Tcp(addr), None).await;
+    let too_large = auth_failures("message_too_large");
+
+    // the username is rejected without waiting for the password
+    let mut client = server_handle.connect().await;
+    client.send(Message::Text("a".repeat(17))).await.unwrap();
+    assert_next_message(&mut client, "err: message too large").await;
+    match timeout(Duration::from_millis(500), client.next())
+        .await
+        .unwrap()
+        .unwrap()
+        .unwrap()
+    {
+        Message::Close(Some(frame)) => assert_eq!(CloseCode::Size, frame.code),
+        msg => panic!("expected close frame, got {:?}", msg),
+    }
+    assert_eq!(too_large + 1, auth_failures("message_too_large"));
 }
 
 async fn assert_next_message(
@@ -303,6 +745,40 @@ async fn assert_next_message(
     );
 }
 
+async fn next_event(response: &mut reqwest::Response) -> String {
+    let chunk = timeout(Duration::from_secs(2), response.chunk())
+        .await
+        .unwrap()
+        .unwrap()
+        .unwrap();
+    String::from_utf8(chunk.to_vec()).unwrap()
+}
+
+/// Check the json authentication acknowledgement and return the reconnect token
+async fn assert_authenticated_v2(
+    client: &mut WebSocketStream<MaybeTlsStream<TcpStream>>,
+    user: &str,
+) -> String {
+    let message = timeout(Duration::from_millis(300), client.next())
+        .await
+        .unwrap()
+        .unwrap()
+        .unwrap();
+    let message: serde_json::Value = serde_json::from_str(message.to_text().unwrap()).unwrap();
+    assert_eq!("authenticated", message["type"]);
+    assert_eq!(user, message["user"]);
+    message["reconnect_token"].as_str().unwrap().to_string()
+}
+
+async fn assert_closed(client: &mut WebSocketStream<MaybeTlsStream<TcpStream>>) {
+    let msg = timeout(Duration::from_millis(500), client.next())
+        .await
+        .unwrap()
+        .unwrap()
+        .unwrap();
+    assert!(msg.is_close(), "expected close frame, got {:?}", msg);
+}
+
 async fn assert_no_message(client: &mut WebSocketStream<MaybeTlsStream<TcpStream>>) {
     sleep(Duration::from_millis(5)).await;
     assert!(timeout(Duration::from_millis(10), client.next())
@@ -391,6 +867,29 @@ async fn test_notify_file_different_storage() {
     assert_no_message(&mut client).await;
 }
 
+#[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+async fn test_notify_file_sibling_path() {
+    let services = Services::new().await;
+    services.add_user("foo", "bar");
+    services.add_filecache_item(10, "foo").await;
+    services.add_filecache_item(11, "foo/bar").await;
+    services.add_storage_mapping("foo", 10, 11).await;
+
+    let server_handle = services.spawn_server().await;
+    let mut client = server_handle.connect_auth("foo", "bar").await;
+
+    let mut redis = services.redis_client().await;
+    redis
+        .publish::<_, _, ()>(
+            "notify_storage_update",
+            r#"{"storage":10, "path":"foo/barbaz", "file_id":5}"#,
+        )
+        .await
+        .unwrap();
+
+    assert_no_message(&mut client).await;
+}
+
 #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
 async fn test_notify_file_multiple() {
     let services = Services::new().await;
@@ -472,6 +971,26 @@ async fn test_notify_notification() {
     assert_no_message(&mut client2).await;
 }
 
+#[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+async fn test_notify_notification_extra_fields() {
+    let services = Services::new().await;
+    services.add_user("foo", "bar");
+
+    let server_handle = services.spawn_server().await;
+    let mut client = server_handle.connect_auth("foo", "bar").await;
+
+    let mut redis = services.redis_client().await;
+    redis
+        .publish::<_, _, ()>(
+            "notify_notification",
+            r#"{"user":"foo","object":{"type":"chat","id":"12"},"app":"spreed","timestamp":1700000000}"#,
+        )
+        .await
+        .unwrap();
+
+    assert_next_message(&mut client, "notify_notification").await;
+}
+
 #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
 async fn test_notify_share() {
     let services = Services::new().await;
@@ -560,3 +1079,2109 @@ async fn test_notify_custom_body() {
     assert_next_message(&mut client1, "my_custom_message [1,2,3]").await;
     assert_no_message(&mut client2).await;
 }
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+async fn test_notify_custom_allowed_channels() {
+    let services = Services::new().await;
+    services.add_user("foo", "bar");
+
+    let mut config = services.config();
+    config.allowed_channels = vec!["calendar_update".into()];
+    let app = App::with_connection(services.db.clone(), config, LOG_HANDLE.clone(), false)
+        .await
+        .unwrap();
+    let addr = listen_available_port().await.unwrap().local_addr().unwrap();
+    let server_handle = spawn_app_at(app, Bind::Tcp(addr), None).await;
+    let mut client = server_handle.connect_auth("foo", "bar").await;
+
+    let mut redis = services.redis_client().await;
+    redis
+        .publish::<_, _, ()>(
+            "notify_custom",
+            r#"{"user":"foo", "message":"other_message"}"#,
+        )
+        .await
+        .unwrap();
+    redis
+        .publish::<_, _, ()>(
+            "notify_custom",
+            r#"{"user":"foo", "message":"calendar_update", "body": {"id": 1}}"#,
+        )
+        .await
+        .unwrap();
+
+    assert_next_message(&mut client, r#"calendar_update {"id":1}"#).await;
+    assert_no_message(&mut client).await;
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+async fn test_metrics() {
+    let services = Services::new().await;
+    services.add_user("foo", "bar");
+
+    let server_handle = services.spawn_server().await;
+    let metrics_handle = spawn_metrics_server().await;
+    let _client = server_handle.connect_auth("foo", "bar").await;
+
+    let mut redis = services.redis_client().await;
+    redis
+        .publish::<_, _, ()>("notify_activity", r#"{"user":"foo"}"#)
+        .await
+        .unwrap();
+    sleep(Duration::from_millis(100)).await;
+
+    let metrics = metrics_handle.get().await;
+    for name in [
+        "active_connection_count ",
+        "mapping_query_count ",
+        "event_count_total ",
+        "event_type_count_total{type=\"notify_activity\"} ",
+        "authentication_success_count_total ",
+        "authentication_failure_count_total ",
+    ] {
+        assert!(
+            metrics.contains(name),
+            "{} not found in metrics:\n{}",
+            name,
+            metrics
+        );
+    }
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+async fn test_stale_unix_socket() {
+    let socket_path = temp_socket_path();
+
+    // a socket left behind by a previous run
+    drop(std::os::unix::net::UnixListener::bind(&socket_path).unwrap());
+    assert!(socket_path.exists());
+
+    let (cancel, cancel_rx) = oneshot::channel();
+    let server = serve_metrics(
+        Bind::Unix(socket_path.clone(), 0o660, SocketOwner::default()),
+        cancel_rx,
+        None,
+    )
+    .unwrap();
+    let server = tokio::spawn(server);
+    sleep(Duration::from_millis(100)).await;
+    UnixStream::connect(&socket_path).await.unwrap();
+
+    // a socket that is still in use isn't removed
+    let (_, in_use_rx) = oneshot::channel::<()>();
+    assert!(serve_metrics(
+        Bind::Unix(socket_path.clone(), 0o660, SocketOwner::default()),
+        in_use_rx,
+        None
+    )
+    .is_err());
+    UnixStream::connect(&socket_path).await.unwrap();
+
+    cancel.send(()).unwrap();
+    server.await.unwrap();
+    assert!(!socket_path.exists());
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+async fn test_unix_socket() {
+    let services = Services::new().await;
+    services.add_user("foo", "bar");
+
+    let socket_path = temp_socket_path();
+    let _server_handle = services
+        .spawn_server_at(
+            Bind::Unix(socket_path.clone(), 0o660, SocketOwner::default()),
+            None,
+        )
+        .await;
+
+    let permissions = std::fs::metadata(&socket_path).unwrap().permissions();
+    assert_eq!(0o660, permissions.mode() & 0o777);
+
+    let stream = UnixStream::connect(&socket_path).await.unwrap();
+    let (mut client, _) = tokio_tungstenite::client_async("ws://localhost/ws", stream)
+        .await
+        .unwrap();
+    client.send(Message::Text("foo".into())).await.unwrap();
+    client.send(Message::Text("bar".into())).await.unwrap();
+
+    sleep(Duration::from_millis(100)).await;
+    assert_eq!(
+        timeout(Duration::from_millis(200), client.next())
+            .await
+            .unwrap()
+            .unwrap()
+            .unwrap(),
+        Message::Text("authenticated".to_string())
+    );
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+async fn test_unix_socket_owner() {
+    let services = Services::new().await;
+
+    // changing the ownership to our own user and group is allowed without privileges
+    let socket_path = temp_socket_path();
+    std::fs::write(&socket_path, "").unwrap();
+    let metadata = std::fs::metadata(&socket_path).unwrap();
+    std::fs::remove_file(&socket_path).unwrap();
+    let owner = SocketOwner {
+        uid: Some(metadata.uid()),
+        gid: Some(metadata.gid()),
+    };
+
+    let _server_handle = services
+        .spawn_server_at(Bind::Unix(socket_path.clone(), 0o660, owner), None)
+        .await;
+
+    let metadata = std::fs::metadata(&socket_path).unwrap();
+    assert_eq!(owner.uid, Some(metadata.uid()));
+    assert_eq!(owner.gid, Some(metadata.gid()));
+}
+
+fn test_tls_config() -> TlsConfig {
+    TlsConfig {
+        cert: concat!(env!("CARGO_MANIFEST_DIR"), "/tests/tls/cert.pem").into(),
+        key: concat!(env!("CARGO_MANIFEST_DIR"), "/tests/tls/key.pem").into(),
+    }
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+async fn test_tls() {
+    let services = Services::new().await;
+
+    let addr = listen_available_port().await.unwrap().local_addr().unwrap();
+    let tls = test_tls_config();
+    tls.validate().unwrap();
+    let _server_handle = services.spawn_server_at(Bind::Tcp(addr), Some(tls)).await;
+
+    let client = reqwest::Client::builder()
+        .danger_accept_invalid_certs(true)
+        .build()
+        .unwrap();
+    let response = client
+        .get(format!("https://127.0.0.1:{}/test/mapping/1", addr.port()))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(StatusCode::OK, response.status());
+    assert_eq!("0", response.text().await.unwrap());
+
+    assert!(
+        reqwest::get(format!("http://127.0.0.1:{}/test/mapping/1", addr.port()))
+            .await
+            .is_err()
+    );
+}
+
+#[test]
+fn test_tls_missing_files() {
+    let tls = TlsConfig {
+        key: test_tls_config().key,
+        cert: "/non/existing/cert.pem".into(),
+    };
+    assert!(tls.validate().is_err());
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+async fn test_nextcloud_self_signed() {
+    let addr = listen_available_port().await.unwrap().local_addr().unwrap();
+    let tls = test_tls_config();
+    let (_shutdown, shutdown_rx) = oneshot::channel::<()>();
+    let (_, server) = warp::serve(
+        warp::path!("index.php" / "apps" / "notify_push" / "test" / "cookie").map(|| "1"),
+    )
+    .tls()
+    .cert_path(tls.cert)
+    .key_path(tls.key)
+    .bind_with_graceful_shutdown(addr, shutdown_rx.map(|_| ()));
+    spawn(server);
+    sleep(Duration::from_millis(10)).await;
+
+    let url = format!("https://127.0.0.1:{}/", addr.port());
+    let strict = notify_push::nc::Client::new(&url, false).unwrap();
+    assert!(strict.get_test_cookie().await.is_err());
+
+    let relaxed = notify_push::nc::Client::new(&url, true).unwrap();
+    assert_eq!(1, relaxed.get_test_cookie().await.unwrap());
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+async fn test_health() {
+    let services = Services::new().await;
+    let server_handle = services.spawn_server().await;
+    sleep(Duration::from_millis(100)).await;
+
+    let response = reqwest::get(format!("http://127.0.0.1:{}/health", server_handle.port))
+        .await
+        .unwrap();
+    assert_eq!(StatusCode::OK, response.status());
+    assert_eq!(r#"{"failing":[]}"#, response.text().await.unwrap());
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+async fn test_version() {
+    let services = Services::new().await;
+    let server_handle = services.spawn_server().await;
+    sleep(Duration::from_millis(100)).await;
+
+    let response = reqwest::get(format!("http://127.0.0.1:{}/version", server_handle.port))
+        .await
+        .unwrap();
+    assert_eq!(StatusCode::OK, response.status());
+    let info: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(env!("NOTIFY_PUSH_VERSION"), info["version"]);
+    assert!(!info["commit"].as_str().unwrap().is_empty());
+    assert!(info["build_time"].as_u64().unwrap() > 0);
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+async fn test_cors_origins() {
+    let services = Services::new().await;
+
+    let mut config = services.config();
+    config.allowed_origins = vec!["https://cloud.example.com".into()];
+    let app = App::with_connection(services.db.clone(), config, LOG_HANDLE.clone(), false)
+        .await
+        .unwrap();
+    let addr = listen_available_port().await.unwrap().local_addr().unwrap();
+    let _server_handle = spawn_app_at(app, Bind::Tcp(addr), None).await;
+    sleep(Duration::from_millis(100)).await;
+
+    let preflight = |origin: &'static str| async move {
+        reqwest::Client::new()
+            .request(
+                reqwest::Method::OPTIONS,
+                format!("http://127.0.0.1:{}/health", addr.port()),
+            )
+            .header("origin", origin)
+            .header("access-control-request-method", "GET")
+            .send()
+            .await
+            .unwrap()
+    };
+
+    let response = preflight("https://cloud.example.com").await;
+    assert_eq!(StatusCode::OK, response.status());
+    assert_eq!(
+        "https://cloud.example.com",
+        response.headers()["access-control-allow-origin"]
+    );
+
+    let response = preflight("https://evil.example.com").await;
+    assert_eq!(StatusCode::FORBIDDEN, response.status());
+    assert!(response
+        .headers()
+        .get("access-control-allow-origin")
+        .is_none());
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+async fn test_health_database_down() {
+    let services = Services::new().await;
+
+    let db = AnyPool::connect("sqlite::memory:").await.unwrap();
+    db.close().await;
+    let app = App::with_connection(db, services.config(), LOG_HANDLE.clone(), false)
+        .await
+        .unwrap();
+    let addr = listen_available_port().await.unwrap().local_addr().unwrap();
+    let _server_handle = spawn_app_at(app, Bind::Tcp(addr), None).await;
+    sleep(Duration::from_millis(100)).await;
+
+    let response = reqwest::get(format!("http://127.0.0.1:{}/health", addr.port()))
+        .await
+        .unwrap();
+    assert_eq!(StatusCode::SERVICE_UNAVAILABLE, response.status());
+    assert_eq!(
+        r#"{"failing":["database"]}"#,
+        response.text().await.unwrap()
+    );
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+async fn test_admin_broadcast() {
+    let services = Services::new().await;
+    services.add_user("foo", "bar");
+    services.add_user("foo2", "bar");
+
+    let server_handle = services.spawn_server().await;
+    let mut client1 = server_handle.connect_auth("foo", "bar").await;
+    let mut client2 = server_handle.connect_auth("foo2", "bar").await;
+
+    let url = format!("http://127.0.0.1:{}/admin/broadcast", server_handle.port);
+    let client = reqwest::Client::new();
+
+    let response = client
+        .post(&url)
+        .json(&serde_json::json!({"message": "maintenance"}))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(StatusCode::FORBIDDEN, response.status());
+
+    let response = client
+        .post(&url)
+        .bearer_auth("wrong")
+        .json(&serde_json::json!({"message": "maintenance"}))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(StatusCode::FORBIDDEN, response.status());
+    assert_no_message(&mut client1).await;
+
+    let response = client
+        .post(&url)
+        .bearer_auth("secret")
+        .json(&serde_json::json!({"message": "maintenance"}))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(StatusCode::OK, response.status());
+
+    assert_next_message(&mut client1, "maintenance").await;
+    assert_next_message(&mut client2, "maintenance").await;
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+async fn test_notify_group_members() {
+    let services = Services::new().await;
+    services.add_user("foo", "bar");
+    services.add_user("foo2", "bar");
+    services.add_user("foo3", "bar");
+
+    services.add_group_member("group1", "foo").await;
+    services.add_group_member("group1", "foo2").await;
+    services.add_group_member("group2", "foo3").await;
+
+    let server_handle = services.spawn_server().await;
+    let mut client1 = server_handle.connect_auth("foo", "bar").await;
+    let mut client2 = server_handle.connect_auth("foo2", "bar").await;
+    let mut client3 = server_handle.connect_auth("foo3", "bar").await;
+
+    let mut redis = services.redis_client().await;
+    redis
+        .publish::<_, _, ()>(
+            "notify_group_membership_update",
+            r#"{"user":"foo", "group":"group1"}"#,
+        )
+        .await
+        .unwrap();
+
+    assert_next_message(&mut client1, "notify_file").await;
+    assert_next_message(&mut client2, "notify_file").await;
+    assert_no_message(&mut client3).await;
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+async fn test_auth_rate_limit() {
+    let services = Services::new().await;
+    services.add_user("foo", "bar");
+
+    let server_handle = services.spawn_server().await;
+    for _ in 0..3 {
+        let mut client = server_handle.connect().await;
+        client.send(Message::Text("foo".into())).await.unwrap();
+        client.send(Message::Text("not_bar".into())).await.unwrap();
+        assert_next_message(&mut client, "err: Invalid credentials").await;
+    }
+
+    // even the correct credentials are rejected without being verified once the limit is reached
+    let mut client = server_handle.connect().await;
+    client.send(Message::Text("foo".into())).await.unwrap();
+    client.send(Message::Text("bar".into())).await.unwrap();
+    assert_next_message(&mut client, "err: rate limited").await;
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+async fn test_credential_cache() {
+    let services = Services::new().await;
+    services.add_user("foo", "bar");
+
+    let client = notify_push::nc::Client::new(&services.config().nextcloud_url, false)
+        .unwrap()
+        .with_credential_cache(Duration::from_millis(200));
+    let verify = || client.verify_credentials("foo", "bar", vec![]);
+
+    assert_eq!("foo", verify().await.unwrap());
+
+    // the cached verification is used without asking nextcloud
+    services.add_user("foo", "changed");
+    assert_eq!("foo", verify().await.unwrap());
+
+    // once expired, the credentials are verified again
+    sleep(Duration::from_millis(250)).await;
+    assert!(verify().await.is_err());
+}
+
+#[test]
+fn test_binary_version() {
+    let output = std::process::Command::new(env!("CARGO_BIN_EXE_notify_push"))
+        .arg("--version")
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    assert_eq!(
+        format!("notify_push {}\n", env!("NOTIFY_PUSH_VERSION")),
+        String::from_utf8(output.stdout).unwrap()
+    );
+}
+
+#[test]
+fn test_binary_dump_config() {
+    let output = std::process::Command::new(env!("CARGO_BIN_EXE_notify_push"))
+        .args([
+            "--database-url",
+            "sqlite::memory:",
+            "--nextcloud-url",
+            "http://cloud.example.com",
+            "--port",
+            "1234",
+            "--admin-secret",
+            "hunter2",
+            "--dump-config",
+        ])
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    assert!(stdout.contains("nextcloud_url: \"http://cloud.example.com/\""));
+    assert!(stdout.contains(":1234"));
+    assert!(!stdout.contains("hunter2"));
+    assert!(stdout.contains("admin_secret: Some(\"<redacted>\")"));
+}
+
+#[test]
+fn test_binary_database_url_file() {
+    let path = std::env::temp_dir().join(format!(
+        "notify_push_test_database_url_{}",
+        std::process::id()
+    ));
+    std::fs::write(&path, "sqlite:///tmp/from_secret_file.sqlite\n").unwrap();
+
+    let output = std::process::Command::new(env!("CARGO_BIN_EXE_notify_push"))
+        .args([
+            "--nextcloud-url",
+            "http://cloud.example.com",
+            "--dump-config",
+        ])
+        .env_remove("DATABASE_URL")
+        .env("DATABASE_URL_FILE", &path)
+        .output()
+        .unwrap();
+    std::fs::remove_file(path).ok();
+    assert!(output.status.success());
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    assert!(stdout.contains("from_secret_file.sqlite"));
+}
+
+#[test]
+fn test_binary_check_invalid_database() {
+    let output = std::process::Command::new(env!("CARGO_BIN_EXE_notify_push"))
+        .args([
+            "--database-url",
+            "sqlite:///non/existing/database.sqlite",
+            "--nextcloud-url",
+            "http://cloud.example.com",
+            "--redis-url",
+            "redis://127.0.0.1:1",
+            "--check",
+        ])
+        .output()
+        .unwrap();
+    assert!(!output.status.success());
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    assert!(stdout.contains("database: failed: Failed to connect to database"));
+    assert!(stdout.contains("redis: failed"));
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+async fn test_binary_print_metrics_once() {
+    let services = Services::new().await;
+    let database = std::env::temp_dir().join(format!(
+        "notify_push_test_metrics_{}.sqlite",
+        std::process::id()
+    ));
+    let database_url = format!("sqlite://{}?mode=rwc", database.display());
+    let db = AnyPool::connect(&database_url).await.unwrap();
+    sqlx::query("CREATE TABLE oc_filecache(fileid BIGINT, path TEXT)")
+        .execute(&db)
+        .await
+        .unwrap();
+    sqlx::query(
+        "CREATE TABLE oc_mounts(storage_id BIGINT, root_id BIGINT, user_id TEXT, mount_point TEXT)",
+    )
+    .execute(&db)
+    .await
+    .unwrap();
+    db.close().await;
+
+    let redis_url = format!("redis://{}", services.redis);
+    let output = tokio::task::spawn_blocking(move || {
+        std::process::Command::new(env!("CARGO_BIN_EXE_notify_push"))
+            .args([
+                "--database-url",
+                &database_url,
+                "--nextcloud-url",
+                "http://cloud.example.com",
+                "--redis-url",
+                &redis_url,
+                "--print-metrics-once",
+            ])
+            .output()
+            .unwrap()
+    })
+    .await
+    .unwrap();
+    std::fs::remove_file(database).ok();
+
+    assert!(output.status.success());
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    assert!(stdout.contains("active_connection_count 0\n"));
+    assert!(stdout.contains("mapping_query_count 1\n"));
+}
+
+/// Run the binary with json logging and collect the first log lines
+fn binary_log_lines(log_level: &str, count: usize) -> Vec<serde_json::Value> {
+    use std::io::{BufRead, BufReader};
+
+    let socket = temp_socket_path();
+    let mut child = std::process::Command::new(env!("CARGO_BIN_EXE_notify_push"))
+        .args([
+            "--database-url",
+            "sqlite::memory:",
+            "--nextcloud-url",
+            "http://127.0.0.1:1",
+            "--redis-url",
+            "redis://127.0.0.1:1",
+            "--socket-path",
+            socket.to_str().unwrap(),
+            "--log-format",
+            "json",
+            "--log-level",
+            log_level,
+        ])
+        .env_remove("RUST_LOG")
+        .stdout(std::process::Stdio::piped())
+        .spawn()
+        .unwrap();
+    let lines = BufReader::new(child.stdout.take().unwrap())
+        .lines()
+        .take(count)
+        .map(|line| serde_json::from_str(&line.unwrap()).unwrap())
+        .collect();
+    child.kill().ok();
+    child.wait().ok();
+    std::fs::remove_file(socket).ok();
+    lines
+}
+
+#[test]
+fn test_binary_shutdown_summary() {
+    use std::io::{BufRead, BufReader};
+
+    let socket = temp_socket_path();
+    let mut child = std::process::Command::new(env!("CARGO_BIN_EXE_notify_push"))
+        .args([
+            "--database-url",
+            "sqlite::memory:",
+            "--nextcloud-url",
+            "http://127.0.0.1:1",
+            "--redis-url",
+            "redis://127.0.0.1:1",
+            "--socket-path",
+            socket.to_str().unwrap(),
+            "--log-format",
+            "json",
+            "--log-level",
+            "info",
+        ])
+        .env_remove("RUST_LOG")
+        .stdout(std::process::Stdio::piped())
+        .spawn()
+        .unwrap();
+
+    for _ in 0..50 {
+        if socket.exists() {
+            break;
+        }
+        std::thread::sleep(Duration::from_millis(100));
+    }
+    assert!(socket.exists());
+    std::process::Command::new("kill")
+        .args(["-TERM", &child.id().to_string()])
+        .status()
+        .unwrap();
+
+    let lines: Vec<serde_json::Value> = BufReader::new(child.stdout.take().unwrap())
+        .lines()
+        .map(|line| serde_json::from_str(&line.unwrap()).unwrap())
+        .collect();
+    assert!(child.wait().unwrap().success());
+    std::fs::remove_file(socket).ok();
+
+    let summary = lines
+        .iter()
+        .filter_map(|line| line["message"].as_str())
+        .find(|message| message.starts_with("shutdown complete"))
+        .expect("no shutdown summary logged");
+    assert_eq!(
+        "shutdown complete, processed 0 events, peak of 0 connections (0 total), 0 mapping queries, 0 authentication failures",
+        summary
+    );
+}
+
+fn is_crate_debug_line(line: &serde_json::Value) -> bool {
+    line["level"] == "DEBUG" && line["target"].as_str().unwrap().starts_with("notify_push")
+}
+
+#[test]
+fn test_binary_log_level() {
+    let lines = binary_log_lines("debug", 20);
+    assert!(lines.iter().any(is_crate_debug_line));
+
+    let lines = binary_log_lines("warn", 2);
+    assert!(!lines.is_empty());
+    assert!(!lines.iter().any(is_crate_debug_line));
+    assert!(lines.iter().all(|line| line["level"] != "INFO"));
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+async fn test_sharded_redis() {
+    let services = Services::new().await;
+    services.add_user("foo", "bar");
+
+    let second_redis_tcp = listen_available_port().await.unwrap();
+    let second_redis = second_redis_tcp.local_addr().unwrap();
+    let (_second_redis_shutdown, second_redis_shutdown_rx) = oneshot::channel::<()>();
+    spawn(async move {
+        mini_redis::server::run(second_redis_tcp, second_redis_shutdown_rx)
+            .await
+            .ok();
+    });
+
+    let mut config = services.config();
+    config
+        .redis
+        .push(format!("redis://{}", second_redis).parse().unwrap());
+    config.redis_sharded = true;
+    let app = App::with_connection(services.db.clone(), config, LOG_HANDLE.clone(), false)
+        .await
+        .unwrap();
+    let addr = listen_available_port().await.unwrap().local_addr().unwrap();
+    let server_handle = spawn_app_at(app, Bind::Tcp(addr), None).await;
+    let mut client = server_handle.connect_auth("foo", "bar").await;
+
+    let mut redis = services.redis_client().await;
+    redis
+        .publish::<_, _, ()>("notify_activity", r#"{"user":"foo"}"#)
+        .await
+        .unwrap();
+    assert_next_message(&mut client, "notify_activity").await;
+
+    let mut second = redis::Client::open(format!("redis://{}", second_redis))
+        .unwrap()
+        .get_async_connection()
+        .await
+        .unwrap();
+    second
+        .publish::<_, _, ()>("notify_notification", r#"{"user":"foo"}"#)
+        .await
+        .unwrap();
+    assert_next_message(&mut client, "notify_notification").await;
+
+    let response = reqwest::get(format!("http://127.0.0.1:{}/health", addr.port()))
+        .await
+        .unwrap();
+    assert_eq!(StatusCode::OK, response.status());
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+async fn test_malformed_event() {
+    let services = Services::new().await;
+    services.add_user("foo", "bar");
+
+    let server_handle = services.spawn_server().await;
+    let mut client = server_handle.connect_auth("foo", "bar").await;
+
+    let mut redis = services.redis_client().await;
+    redis
+        .publish::<_, _, ()>("notify_activity", "garbage")
+        .await
+        .unwrap();
+    redis
+        .publish::<_, _, ()>("notify_activity", r#"{"user":"foo"}"#)
+        .await
+        .unwrap();
+
+    assert_next_message(&mut client, "notify_activity").await;
+    assert!(METRICS.malformed_events() > 0);
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+async fn test_redis_prefix() {
+    let services = Services::new().await;
+    services.add_user("foo", "bar");
+
+    let mut config = services.config();
+    config.redis_prefix = "instance1_".to_string();
+    let app = App::with_connection(services.db.clone(), config, LOG_HANDLE.clone(), false)
+        .await
+        .unwrap();
+    let addr = listen_available_port().await.unwrap().local_addr().unwrap();
+    let server_handle = spawn_app_at(app, Bind::Tcp(addr), None).await;
+    let mut client = server_handle.connect_auth("foo", "bar").await;
+
+    let mut redis = services.redis_client().await;
+    redis
+        .publish::<_, _, ()>("notify_activity", r#"{"user":"foo"}"#)
+        .await
+        .unwrap();
+    assert_no_message(&mut client).await;
+
+    redis
+        .publish::<_, _, ()>("instance1_notify_activity", r#"{"user":"foo"}"#)
+        .await
+        .unwrap();
+    assert_next_message(&mut client, "notify_activity").await;
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+async fn test_admin_cached_storages() {
+    let services = Services::new().await;
+    services.add_filecache_item(10, "").await;
+    services.add_storage_mapping("foo", 10, 10).await;
+
+    let server_handle = services.spawn_server().await;
+    let url = format!("http://127.0.0.1:{}/cache/storages", server_handle.port);
+    let client = reqwest::Client::new();
+
+    let response = client.get(&url).send().await.unwrap();
+    assert_eq!(StatusCode::FORBIDDEN, response.status());
+
+    let mapping = reqwest::get(format!(
+        "http://127.0.0.1:{}/test/mapping/10",
+        server_handle.port
+    ))
+    .await
+    .unwrap()
+    .text()
+    .await
+    .unwrap();
+    assert_eq!("1", mapping);
+
+    let cached: serde_json::Value = client
+        .get(&url)
+        .bearer_auth("secret")
+        .send()
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+    let cached = cached.as_array().unwrap();
+    assert_eq!(1, cached.len());
+    assert_eq!(10, cached[0]["storage"]);
+    assert_eq!(1, cached[0]["users"]);
+    assert!(cached[0]["ttl"].as_u64().unwrap() > 0);
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+async fn test_metrics_path() {
+    let services = Services::new().await;
+
+    let server_handle = services.spawn_server().await;
+    let response = reqwest::get(format!("http://127.0.0.1:{}/metrics", server_handle.port))
+        .await
+        .unwrap();
+    assert_eq!(StatusCode::NOT_FOUND, response.status());
+
+    let mut config = services.config();
+    config.metrics_path = Some("metrics".into());
+    let app = App::with_connection(services.db.clone(), config, LOG_HANDLE.clone(), false)
+        .await
+        .unwrap();
+    let addr = listen_available_port().await.unwrap().local_addr().unwrap();
+    let server_handle = spawn_app_at(app, Bind::Tcp(addr), None).await;
+    for path in ["metrics", "push/metrics"] {
+        let response = reqwest::get(format!("http://127.0.0.1:{}/{}", server_handle.port, path))
+            .await
+            .unwrap();
+        assert_eq!(StatusCode::OK, response.status());
+        assert!(response
+            .text()
+            .await
+            .unwrap()
+            .contains("active_connection_count "));
+    }
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+async fn test_listen_only() {
+    let services = Services::new().await;
+    services.add_user("foo", "bar");
+    let mut config = services.config();
+    config.listen_only = true;
+    config.metrics_path = Some("metrics".into());
+    let app = App::with_connection(services.db.clone(), config, LOG_HANDLE.clone(), false)
+        .await
+        .unwrap();
+    let addr = listen_available_port().await.unwrap().local_addr().unwrap();
+    let server_handle = spawn_app_at(app, Bind::Tcp(addr), None).await;
+
+    assert!(
+        tokio_tungstenite::connect_async(format!("ws://127.0.0.1:{}/ws", server_handle.port))
+            .await
+            .is_err()
+    );
+    let response = reqwest::get(format!("http://127.0.0.1:{}/sse", server_handle.port))
+        .await
+        .unwrap();
+    assert_eq!(StatusCode::NOT_FOUND, response.status());
+
+    let events = METRICS.events_received();
+    let mut redis = services.redis_client().await;
+    redis
+        .publish::<_, _, ()>("notify_activity", r#"{"user":"foo"}"#)
+        .await
+        .unwrap();
+    sleep(Duration::from_millis(100)).await;
+    assert!(METRICS.events_received() > events);
+
+    let response = reqwest::get(format!("http://127.0.0.1:{}/metrics", server_handle.port))
+        .await
+        .unwrap();
+    assert_eq!(StatusCode::OK, response.status());
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+async fn test_admin_cache_flush() {
+    let services = Services::new().await;
+    services.add_filecache_item(10, "").await;
+    services.add_storage_mapping("foo", 10, 10).await;
+
+    let server_handle = services.spawn_server().await;
+    let client = reqwest::Client::new();
+    let mapping_count = || async {
+        reqwest::get(format!(
+            "http://127.0.0.1:{}/test/mapping/10",
+            server_handle.port
+        ))
+        .await
+        .unwrap()
+        .text()
+        .await
+        .unwrap()
+    };
+    let flush = |query: &'static str| {
+        client
+            .post(format!(
+                "http://127.0.0.1:{}/cache/flush{}",
+                server_handle.port, query
+            ))
+            .bearer_auth("secret")
+            .send()
+    };
+
+    assert_eq!("1", mapping_count().await);
+    services.add_storage_mapping("foo2", 10, 10).await;
+    assert_eq!("1", mapping_count().await);
+
+    let response = client
+        .post(format!(
+            "http://127.0.0.1:{}/cache/flush",
+            server_handle.port
+        ))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(StatusCode::FORBIDDEN, response.status());
+    assert_eq!("1", mapping_count().await);
+
+    assert_eq!(StatusCode::OK, flush("?storage=11").await.unwrap().status());
+    assert_eq!("1", mapping_count().await);
+
+    assert_eq!(StatusCode::OK, flush("?storage=10").await.unwrap().status());
+    assert_eq!("2", mapping_count().await);
+
+    services.add_storage_mapping("foo3", 10, 10).await;
+    assert_eq!(StatusCode::OK, flush("").await.unwrap().status());
+    assert_eq!("3", mapping_count().await);
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+async fn test_admin_user_connections() {
+    let services = Services::new().await;
+    services.add_user("foo", "bar");
+
+    let server_handle = services.spawn_server().await;
+    let _client1 = server_handle.connect_auth("foo", "bar").await;
+    let (mut client2, _) = server_handle
+        .connect_with_protocol("notify_push.v2")
+        .await
+        .unwrap();
+    client2.send(Message::Text("foo".into())).await.unwrap();
+    client2.send(Message::Text("bar".into())).await.unwrap();
+    assert_authenticated_v2(&mut client2, "foo").await;
+    client2
+        .send(Message::Text("client desktop".into()))
+        .await
+        .unwrap();
+    sleep(Duration::from_millis(100)).await;
+
+    let url = format!("http://127.0.0.1:{}/connections/foo", server_handle.port);
+    let client = reqwest::Client::new();
+
+    let response = client.get(&url).send().await.unwrap();
+    assert_eq!(StatusCode::FORBIDDEN, response.status());
+
+    let response = client.get(&url).bearer_auth("secret").send().await.unwrap();
+    assert_eq!(StatusCode::OK, response.status());
+    let body: serde_json::Value = response.json().await.unwrap();
+    assert_eq!("foo", body["user"]);
+    assert_eq!(2, body["count"]);
+    let connections = body["connections"].as_array().unwrap();
+    assert_eq!("notify_push.v1", connections[0]["protocol"]);
+    assert_eq!("notify_push.v2", connections[1]["protocol"]);
+    assert_eq!("desktop", connections[1]["client"]);
+    assert!(connections[0]["id"].as_u64() < connections[1]["id"].as_u64());
+    assert!(connections[0]["connected"].as_u64().unwrap() > 0);
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+async fn test_admin_disconnect() {
+    let services = Services::new().await;
+    services.add_user("foo", "bar");
+
+    let server_handle = services.spawn_server().await;
+    let mut client1 = server_handle.connect_auth("foo", "bar").await;
+    let mut client2 = server_handle.connect_auth("foo", "bar").await;
+
+    let url = format!("http://127.0.0.1:{}/admin/disconnect", server_handle.port);
+    let client = reqwest::Client::new();
+
+    let response = client
+        .post(&url)
+        .json(&serde_json::json!({"user": "foo", "connection": 0}))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(StatusCode::FORBIDDEN, response.status());
+
+    let response = client
+        .post(&url)
+        .bearer_auth("secret")
+        .json(&serde_json::json!({"user": "other", "connection": 0}))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(StatusCode::NOT_FOUND, response.status());
+
+    let response = client
+        .post(&url)
+        .bearer_auth("secret")
+        .json(&serde_json::json!({"user": "foo", "connection": 0}))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(StatusCode::OK, response.status());
+    assert_closed(&mut client1).await;
+
+    let mut redis = services.redis_client().await;
+    redis
+        .publish::<_, _, ()>("notify_activity", r#"{"user":"foo"}"#)
+        .await
+        .unwrap();
+    assert_next_message(&mut client2, "notify_activity").await;
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+async fn test_credential_invalidation() {
+    let services = Services::new().await;
+    services.add_user("foo", "bar");
+    services.add_user("foo2", "bar");
+
+    let server_handle = services.spawn_server().await;
+    let mut client1 = server_handle.connect_auth("foo", "bar").await;
+    let mut client2 = server_handle.connect_auth("foo", "bar").await;
+    let mut other = server_handle.connect_auth("foo2", "bar").await;
+
+    let mut redis = services.redis_client().await;
+    redis
+        .publish::<_, _, ()>("notify_credential_invalidation", r#"{"user":"foo"}"#)
+        .await
+        .unwrap();
+    assert_closed(&mut client1).await;
+    assert_closed(&mut client2).await;
+
+    redis
+        .publish::<_, _, ()>("notify_activity", r#"{"user":"foo2"}"#)
+        .await
+        .unwrap();
+    assert_next_message(&mut other, "notify_activity").await;
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+async fn test_cookie_round_trip() {
+    let services = Services::new().await;
+    let app = Arc::new(services.app().await);
+    let (_listen_cancel, listen_cancel_handle) = oneshot::channel();
+    spawn(listen_loop(app.clone(), listen_cancel_handle));
+
+    let time = app
+        .test_cookie_round_trip(Duration::from_secs(5))
+        .await
+        .unwrap();
+    assert!(time < Duration::from_secs(5));
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+async fn test_cookie_round_trip_no_redis() {
+    let services = Services::new().await;
+    let app = services.app().await;
+
+    assert!(app
+        .test_cookie_round_trip(Duration::from_millis(100))
+        .await
+        .is_err());
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+async fn test_cookie_watchdog() {
+    let services = Services::new().await;
+    // the test cookies are published without the prefix, so they never arrive
+    let config = Config {
+        redis_prefix: "other_".into(),
+        ..services.config()
+    };
+    let app = App::with_connection(services.db.clone(), config, LOG_HANDLE.clone(), false)
+        .await
+        .unwrap();
+    let app = Arc::new(app);
+    let (_listen_cancel, listen_cancel_handle) = oneshot::channel();
+    spawn(listen_loop(app.clone(), listen_cancel_handle));
+
+    let watchdog = spawn(cookie_watchdog(app.clone(), Duration::from_millis(100)));
+    sleep(Duration::from_millis(150)).await;
+    assert!(app.health().await.is_healthy());
+
+    sleep(Duration::from_millis(300)).await;
+    watchdog.abort();
+    assert_eq!(vec!["cookie"], app.health().await.failing);
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+async fn test_dual_stack() {
+    let services = Services::new().await;
+    let port = listen_available_port()
+        .await
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port();
+    let _server_handle = services.spawn_server_at(Bind::DualStack(port), None).await;
+    sleep(Duration::from_millis(100)).await;
+
+    for host in ["127.0.0.1", "[::1]"] {
+        let response = reqwest::get(format!("http://{}:{}/health", host, port))
+            .await
+            .unwrap();
+        assert_eq!(StatusCode::OK, response.status());
+    }
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+async fn test_dry_run() {
+    let services = Services::new().await;
+    services.add_user("foo", "bar");
+    services.add_filecache_item(10, "foo").await;
+    services.add_storage_mapping("foo", 20, 10).await;
+
+    let mut config = services.config();
+    config.dry_run = true;
+    let app = App::with_connection(services.db.clone(), config, LOG_HANDLE.clone(), false)
+        .await
+        .unwrap();
+    let addr = listen_available_port().await.unwrap().local_addr().unwrap();
+    let server_handle = spawn_app_at(app, Bind::Tcp(addr), None).await;
+    let mut client = server_handle.connect_auth("foo", "bar").await;
+
+    let queries = METRICS.mapping_query_count();
+    let mut redis = services.redis_client().await;
+    redis
+        .publish::<_, _, ()>(
+            "notify_storage_update",
+            r#"{"storage":20, "path":"foo/bar", "file_id":5}"#,
+        )
+        .await
+        .unwrap();
+    redis
+        .publish::<_, _, ()>("notify_activity", r#"{"user":"foo"}"#)
+        .await
+        .unwrap();
+
+    sleep(Duration::from_millis(100)).await;
+    assert_no_message(&mut client).await;
+    assert!(METRICS.mapping_query_count() > queries);
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+async fn test_protocol_negotiation() {
+    let services = Services::new().await;
+    services.add_user("foo", "bar");
+
+    let server_handle = services.spawn_server().await;
+    let mut redis = services.redis_client().await;
+
+    let (mut v2, response) = server_handle
+        .connect_with_protocol("notify_push.v1, notify_push.v2")
+        .await
+        .unwrap();
+    assert_eq!(
+        "notify_push.v2",
+        response.headers()["sec-websocket-protocol"]
+    );
+    let (mut v1, response) = server_handle
+        .connect_with_protocol("other, notify_push.v1")
+        .await
+        .unwrap();
+    assert_eq!(
+        "notify_push.v1",
+        response.headers()["sec-websocket-protocol"]
+    );
+
+    for client in [&mut v1, &mut v2] {
+        client.send(Message::Text("foo".into())).await.unwrap();
+        client.send(Message::Text("bar".into())).await.unwrap();
+    }
+    assert_next_message(&mut v1, "authenticated").await;
+    assert_authenticated_v2(&mut v2, "foo").await;
+
+    redis
+        .publish::<_, _, ()>("notify_activity", r#"{"user":"foo"}"#)
+        .await
+        .unwrap();
+    assert_next_message(&mut v1, "notify_activity").await;
+    assert_next_message(&mut v2, r#"{"type":"notify_activity"}"#).await;
+
+    match server_handle.connect_with_protocol("other").await {
+        Err(tokio_tungstenite::tungstenite::Error::Http(response)) => {
+            assert_eq!(StatusCode::BAD_REQUEST, response.status())
+        }
+        result => panic!("expected bad request, got {:?}", result.map(|r| r.1)),
+    }
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+async fn test_reconnect_token() {
+    let services = Services::new().await;
+    services.add_user("foo", "bar");
+
+    let server_handle = services.spawn_server().await;
+    let (mut client, _) = server_handle
+        .connect_with_protocol("notify_push.v2")
+        .await
+        .unwrap();
+    client.send(Message::Text("foo".into())).await.unwrap();
+    client.send(Message::Text("bar".into())).await.unwrap();
+    let token = assert_authenticated_v2(&mut client, "foo").await;
+    drop(client);
+
+    // nextcloud isn't asked to verify the credentials when resuming
+    services.add_user("foo", "changed");
+    let (mut resumed, _) = server_handle
+        .connect_with_protocol("notify_push.v2")
+        .await
+        .unwrap();
+    resumed
+        .send(Message::Text(format!("resume:{}", token)))
+        .await
+        .unwrap();
+    assert_authenticated_v2(&mut resumed, "foo").await;
+
+    let mut redis = services.redis_client().await;
+    redis
+        .publish::<_, _, ()>("notify_activity", r#"{"user":"foo"}"#)
+        .await
+        .unwrap();
+    assert_next_message(&mut resumed, r#"{"type":"notify_activity"}"#).await;
+
+    // invalid tokens fall back to the credentials
+    let (mut tampered, _) = server_handle
+        .connect_with_protocol("notify_push.v2")
+        .await
+        .unwrap();
+    tampered
+        .send(Message::Text(format!("resume:{}x", token)))
+        .await
+        .unwrap();
+    assert_next_message(&mut tampered, r#"{"type":"resume_failed"}"#).await;
+    tampered.send(Message::Text("foo".into())).await.unwrap();
+    tampered
+        .send(Message::Text("changed".into()))
+        .await
+        .unwrap();
+    assert_authenticated_v2(&mut tampered, "foo").await;
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+async fn test_startup_timeout() {
+    let services = Services::new().await;
+    // accepts connections but never completes the handshake
+    let unresponsive = listen_available_port().await.unwrap();
+    let addr = unresponsive.local_addr().unwrap();
+
+    let mut config = services.config();
+    config.database = format!("mysql://nextcloud@127.0.0.1:{}/nextcloud", addr.port())
+        .parse()
+        .unwrap();
+    let started = Instant::now();
+    let err = startup_connect(
+        "database",
+        Duration::from_millis(200),
+        App::new(config, LOG_HANDLE.clone()),
+    )
+    .await
+    .err()
+    .unwrap();
+    assert!(started.elapsed() < Duration::from_secs(5));
+    assert!(matches!(err, Error::StartupTimeout("database", _)));
+    assert!(err.to_string().contains("database"));
+    drop(unresponsive);
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+async fn test_instance_prefixes() {
+    let services = Services::new().await;
+    services.add_user("foo", "bar");
+    services.add_user("foo2", "bar");
+    services.add_filecache_item(10, "").await;
+    services.add_storage_mapping("foo", 10, 10).await;
+
+    // a second instance using the same database with the nc2_ table prefix
+    for query in [
+        "CREATE TABLE nc2_filecache(fileid BIGINT, path TEXT)",
+        "CREATE TABLE nc2_mounts(storage_id BIGINT, root_id BIGINT, user_id TEXT, mount_point TEXT)",
+        "INSERT INTO nc2_filecache(fileid, path) VALUES(10, '')",
+        "INSERT INTO nc2_mounts(storage_id, root_id, user_id, mount_point) VALUES(10, 10, 'foo2', '/foo2/')",
+    ] {
+        sqlx::query(query).execute(&services.db).await.unwrap();
+    }
+
+    let mut config = services.config();
+    config.instance_prefixes = vec![("two".into(), "nc2_".into())];
+    let app = App::with_connection(services.db.clone(), config, LOG_HANDLE.clone(), false)
+        .await
+        .unwrap();
+    let addr = listen_available_port().await.unwrap().local_addr().unwrap();
+    let server_handle = spawn_app_at(app, Bind::Tcp(addr), None).await;
+    let mut client1 = server_handle.connect_auth("foo", "bar").await;
+    let mut client2 = server_handle.connect_auth("foo2", "bar").await;
+
+    let mut redis = services.redis_client().await;
+    redis
+        .publish::<_, _, ()>(
+            "notify_storage_update",
+            r#"{"storage":10, "path":"foo.txt", "file_id":5, "instance":"two"}"#,
+        )
+        .await
+        .unwrap();
+    assert_next_message(&mut client2, "notify_file").await;
+    assert_no_message(&mut client1).await;
+
+    // events without a known instance use the default prefix
+    redis
+        .publish::<_, _, ()>(
+            "notify_storage_update",
+            r#"{"storage":10, "path":"foo.txt", "file_id":5}"#,
+        )
+        .await
+        .unwrap();
+    assert_next_message(&mut client1, "notify_file").await;
+    assert_no_message(&mut client2).await;
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+async fn test_storage_update_move() {
+    let services = Services::new().await;
+    services.add_user("foo", "bar");
+    services.add_user("foo2", "bar");
+    services.add_user("foo3", "bar");
+
+    let mapping = MockMapping::default();
+    mapping.add_storage_mapping("foo", 10, "files/source");
+    mapping.add_storage_mapping("foo2", 10, "files/target");
+    mapping.add_storage_mapping("foo3", 10, "files/other");
+
+    let server_handle = services.spawn_server_with_resolver(mapping).await;
+    let (mut source, _) = server_handle
+        .connect_with_protocol("notify_push.v2")
+        .await
+        .unwrap();
+    source.send(Message::Text("foo".into())).await.unwrap();
+    source.send(Message::Text("bar".into())).await.unwrap();
+    assert_authenticated_v2(&mut source, "foo").await;
+    let mut target = server_handle.connect_auth("foo2", "bar").await;
+    let mut other = server_handle.connect_auth("foo3", "bar").await;
+
+    let mut redis = services.redis_client().await;
+    redis
+        .publish::<_, _, ()>(
+            "notify_storage_update_move",
+            r#"{"storage":10, "from":"files/source/foo.txt", "to":"files/target/foo.txt"}"#,
+        )
+        .await
+        .unwrap();
+
+    assert_next_message(
+        &mut source,
+        r#"{"from":"files/source/foo.txt","to":"files/target/foo.txt","type":"file_move"}"#,
+    )
+    .await;
+    assert_next_message(&mut target, "notify_file").await;
+    assert_no_message(&mut other).await;
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+async fn test_mock_user_resolver() {
+    let services = Services::new().await;
+    services.add_user("foo", "bar");
+    services.add_user("foo2", "bar");
+
+    let mapping = MockMapping::default();
+    mapping.add_storage_mapping("foo", 10, "foo");
+    mapping.add_storage_mapping("foo2", 10, "foo/shared");
+    mapping.add_group_member("group1", "foo2");
+
+    let server_handle = services.spawn_server_with_resolver(mapping).await;
+    let mut client1 = server_handle.connect_auth("foo", "bar").await;
+    let mut client2 = server_handle.connect_auth("foo2", "bar").await;
+
+    let mut redis = services.redis_client().await;
+    redis
+        .publish::<_, _, ()>(
+            "notify_storage_update",
+            r#"{"storage":10, "path":"foo/bar", "file_id":5}"#,
+        )
+        .await
+        .unwrap();
+    assert_next_message(&mut client1, "notify_file").await;
+    assert_no_message(&mut client2).await;
+
+    redis
+        .publish::<_, _, ()>(
+            "notify_group_membership_update",
+            r#"{"user":"foo", "group":"group1"}"#,
+        )
+        .await
+        .unwrap();
+    assert_next_message(&mut client2, "notify_file").await;
+}
+
+/// Sink that takes a while for every message, making large fan-outs expensive
+#[derive(Default)]
+struct SlowSink {
+    sent: Arc<AtomicUsize>,
+}
+
+impl NotificationSink for SlowSink {
+    fn send(&self, _user: &UserId, _msg: &QueuedMessage) -> Delivery {
+        std::thread::sleep(Duration::from_micros(50));
+        self.sent.fetch_add(1, Ordering::SeqCst);
+        Delivery::default()
+    }
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+async fn test_large_fan_out() {
+    const USERS: usize = 20_000;
+    let services = Services::new().await;
+    services.add_user("foo", "bar");
+
+    let mapping = MockMapping::default();
+    for i in 0..USERS {
+        mapping.add_storage_mapping(format!("user{}", i), 10, "");
+    }
+    let sink = SlowSink::default();
+    let sent = sink.sent.clone();
+
+    let addr = listen_available_port().await.unwrap().local_addr().unwrap();
+    let app = services
+        .app()
+        .await
+        .with_user_resolver(mapping)
+        .with_sink(sink);
+    let server_handle = spawn_app_at(app, Bind::Tcp(addr), None).await;
+    let mut client = server_handle.connect_auth("foo", "bar").await;
+
+    let fan_outs = METRICS.fan_out_duration.count();
+    let mut redis = services.redis_client().await;
+    redis
+        .publish::<_, _, ()>(
+            "notify_storage_update",
+            r#"{"storage":10, "path":"foo/bar", "file_id":5}"#,
+        )
+        .await
+        .unwrap();
+    let start = Instant::now();
+    redis
+        .publish::<_, _, ()>("notify_activity", r#"{"user":"foo"}"#)
+        .await
+        .unwrap();
+
+    // the small event isn't held up until the large one is sent, which takes at least a second
+    assert_next_message(&mut client, "notify_activity").await;
+    assert!(start.elapsed() < Duration::from_millis(300));
+    assert!(sent.load(Ordering::SeqCst) < USERS);
+
+    // every mapped user and the activity for foo
+    while sent.load(Ordering::SeqCst) < USERS + 1 {
+        sleep(Duration::from_millis(50)).await;
+    }
+    assert!(METRICS.fan_out_duration.count() > fan_outs);
+}
+
+/// Counts the storage lookups made through the wrapped resolver
+#[derive(Default)]
+struct CountingResolver {
+    inner: MockMapping,
+    lookups: Arc<AtomicUsize>,
+    pings: Arc<AtomicUsize>,
+}
+
+impl UserResolver for CountingResolver {
+    fn get_users_for_storage_path<'a>(
+        &'a self,
+        storage: u32,
+        path: &'a str,
+    ) -> BoxFuture<'a, Result<Vec<UserId>, DatabaseError>> {
+        self.lookups.fetch_add(1, Ordering::SeqCst);
+        self.inner.get_users_for_storage_path(storage, path)
+    }
+
+    fn get_users_for_storage_paths<'a>(
+        &'a self,
+        storage: u32,
+        paths: &'a [String],
+    ) -> BoxFuture<'a, Result<Vec<UserId>, DatabaseError>> {
+        self.lookups.fetch_add(1, Ordering::SeqCst);
+        self.inner.get_users_for_storage_paths(storage, paths)
+    }
+
+    fn get_users_for_group<'a>(
+        &'a self,
+        group: &'a str,
+    ) -> BoxFuture<'a, Result<Vec<UserId>, DatabaseError>> {
+        self.inner.get_users_for_group(group)
+    }
+
+    fn ping(&self) -> BoxFuture<'_, Result<(), DatabaseError>> {
+        self.pings.fetch_add(1, Ordering::SeqCst);
+        self.inner.ping()
+    }
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+async fn test_database_keepalive() {
+    let services = Services::new().await;
+    let resolver = CountingResolver::default();
+    let pings = resolver.pings.clone();
+    let app = App::with_connection(
+        services.db.clone(),
+        services.config(),
+        LOG_HANDLE.clone(),
+        false,
+    )
+    .await
+    .unwrap()
+    .with_user_resolver(resolver);
+    let app = Arc::new(app);
+
+    let keepalive = spawn(database_keepalive(app.clone(), Duration::from_millis(100)));
+    sleep(Duration::from_millis(450)).await;
+    keepalive.abort();
+    // the first check runs immediately
+    let count = pings.load(Ordering::SeqCst);
+    assert!((4..=6).contains(&count), "{} pings", count);
+
+    // the health check uses the result of the keepalive instead of querying the database
+    assert!(!app.health().await.failing.contains(&"database"));
+    assert_eq!(count, pings.load(Ordering::SeqCst));
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+async fn test_storage_update_batch() {
+    let services = Services::new().await;
+    services.add_user("foo", "bar");
+    services.add_user("foo2", "bar");
+
+    let resolver = CountingResolver::default();
+    resolver.inner.add_storage_mapping("foo", 10, "files");
+    resolver
+        .inner
+        .add_storage_mapping("foo2", 10, "files/shared");
+    let lookups = resolver.lookups.clone();
+
+    let server_handle = services.spawn_server_with_resolver(resolver).await;
+    let mut client1 = server_handle.connect_auth("foo", "bar").await;
+    let mut client2 = server_handle.connect_auth("foo2", "bar").await;
+
+    let paths: Vec<String> = (0..50).map(|i| format!("files/shared/{}.txt", i)).collect();
+    let mut redis = services.redis_client().await;
+    redis
+        .publish::<_, _, ()>(
+            "notify_storage_update_batch",
+            serde_json::json!({"storage": 10, "paths": paths}).to_string(),
+        )
+        .await
+        .unwrap();
+
+    assert_next_message(&mut client1, "notify_file").await;
+    assert_next_message(&mut client2, "notify_file").await;
+    assert_no_message(&mut client1).await;
+    assert_no_message(&mut client2).await;
+    assert_eq!(1, lookups.load(Ordering::SeqCst));
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+async fn test_ignore_paths() {
+    let services = Services::new().await;
+    services.add_user("foo", "bar");
+
+    let mapping = MockMapping::default();
+    mapping.add_storage_mapping("foo", 10, "");
+
+    let mut config = services.config();
+    config.ignore_paths = vec!["files_trashbin/".into(), "files_versions".into()];
+    let app = App::with_connection(services.db.clone(), config, LOG_HANDLE.clone(), false)
+        .await
+        .unwrap()
+        .with_user_resolver(mapping);
+    let addr = listen_available_port().await.unwrap().local_addr().unwrap();
+    let server_handle = spawn_app_at(app, Bind::Tcp(addr), None).await;
+    let mut client = server_handle.connect_auth("foo", "bar").await;
+
+    let mut redis = services.redis_client().await;
+    redis
+        .publish::<_, _, ()>(
+            "notify_storage_update",
+            r#"{"storage":10, "path":"files_trashbin/files/foo.txt.d1234", "file_id":5}"#,
+        )
+        .await
+        .unwrap();
+    assert_no_message(&mut client).await;
+
+    redis
+        .publish::<_, _, ()>(
+            "notify_storage_update",
+            r#"{"storage":10, "path":"files_versions/foo.txt.v1234", "file_id":6}"#,
+        )
+        .await
+        .unwrap();
+    assert_no_message(&mut client).await;
+
+    // only whole path segments are matched
+    redis
+        .publish::<_, _, ()>(
+            "notify_storage_update",
+            r#"{"storage":10, "path":"files_versions2/foo.txt", "file_id":7}"#,
+        )
+        .await
+        .unwrap();
+    assert_next_message(&mut client, "notify_file").await;
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+async fn test_disabled_events() {
+    let services = Services::new().await;
+    services.add_user("foo", "bar");
+
+    let mut config = services.config();
+    config.disabled_events = vec!["share_create".into(), "group_update".into()];
+    let app = App::with_connection(services.db.clone(), config, LOG_HANDLE.clone(), false)
+        .await
+        .unwrap();
+    let addr = listen_available_port().await.unwrap().local_addr().unwrap();
+    let server_handle = spawn_app_at(app, Bind::Tcp(addr), None).await;
+    let mut client = server_handle.connect_auth("foo", "bar").await;
+
+    let skipped = METRICS.skipped_events();
+    let mut redis = services.redis_client().await;
+    redis
+        .publish::<_, _, ()>("notify_user_share_created", r#"{"user":"foo"}"#)
+        .await
+        .unwrap();
+    assert_no_message(&mut client).await;
+    assert!(METRICS.skipped_events() > skipped);
+
+    redis
+        .publish::<_, _, ()>("notify_activity", r#"{"user":"foo"}"#)
+        .await
+        .unwrap();
+    assert_next_message(&mut client, "notify_activity").await;
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+async fn test_foreign_instance() {
+    let services = Services::new().await;
+    services.add_user("foo", "bar");
+
+    let mut config = services.config();
+    config.instance_id = Some("oc123".into());
+    let app = App::with_connection(services.db.clone(), config, LOG_HANDLE.clone(), false)
+        .await
+        .unwrap();
+    let addr = listen_available_port().await.unwrap().local_addr().unwrap();
+    let server_handle = spawn_app_at(app, Bind::Tcp(addr), None).await;
+    let mut client = server_handle.connect_auth("foo", "bar").await;
+
+    let foreign = METRICS.foreign_events();
+    let mut redis = services.redis_client().await;
+    redis
+        .publish::<_, _, ()>("notify_activity", r#"{"user":"foo","instance":"oc456"}"#)
+        .await
+        .unwrap();
+    assert_no_message(&mut client).await;
+    assert!(METRICS.foreign_events() > foreign);
+
+    redis
+        .publish::<_, _, ()>("notify_activity", r#"{"user":"foo","instance":"oc123"}"#)
+        .await
+        .unwrap();
+    assert_next_message(&mut client, "notify_activity").await;
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+async fn test_webhook_sink() {
+    let services = Services::new().await;
+    services.add_user("foo", "bar");
+
+    let (webhook_tx, mut webhook_rx) = tokio::sync::mpsc::unbounded_channel();
+    let webhook = warp::path!("hook")
+        .and(warp::post())
+        .and(warp::body::json())
+        .map(move |body: serde_json::Value| {
+            webhook_tx.send(body).ok();
+            StatusCode::OK
+        });
+    let webhook_listener = listen_available_port().await.unwrap();
+    let webhook_addr = webhook_listener.local_addr().unwrap();
+    spawn(warp::serve(webhook).run_incoming(TcpListenerStream::new(webhook_listener)));
+
+    let mut config = services.config();
+    config.webhook_url = Some(format!("http://{}/hook", webhook_addr).parse().unwrap());
+    let app = App::with_connection(services.db.clone(), config, LOG_HANDLE.clone(), false)
+        .await
+        .unwrap();
+    let addr = listen_available_port().await.unwrap().local_addr().unwrap();
+    let server_handle = spawn_app_at(app, Bind::Tcp(addr), None).await;
+    let mut client = server_handle.connect_auth("foo", "bar").await;
+
+    let mut redis = services.redis_client().await;
+    redis
+        .publish::<_, _, ()>("notify_activity", r#"{"user":"foo"}"#)
+        .await
+        .unwrap();
+
+    assert_next_message(&mut client, "notify_activity").await;
+    let payload = timeout(Duration::from_secs(1), webhook_rx.recv())
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!("foo", payload["user"]);
+    assert_eq!(
+        serde_json::json!({"type": "notify_activity"}),
+        payload["message"]
+    );
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+async fn test_sse() {
+    let services = Services::new().await;
+    services.add_user("foo", "bar");
+
+    let mapping = MockMapping::default();
+    mapping.add_storage_mapping("foo", 10, "");
+    let server_handle = services.spawn_server_with_resolver(mapping).await;
+    let url = format!("http://127.0.0.1:{}/sse", server_handle.port);
+
+    let response = reqwest::Client::new()
+        .get(&url)
+        .basic_auth("foo", Some("wrong"))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(StatusCode::UNAUTHORIZED, response.status());
+    let response = reqwest::get(&url).await.unwrap();
+    assert_eq!(StatusCode::UNAUTHORIZED, response.status());
+
+    let mut response = reqwest::Client::new()
+        .get(&url)
+        .basic_auth("foo", Some("bar"))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(StatusCode::OK, response.status());
+    assert_eq!("text/event-stream", response.headers()["content-type"]);
+
+    assert_eq!(
+        "data:{\"type\":\"authenticated\",\"user\":\"foo\"}\n\n",
+        next_event(&mut response).await
+    );
+
+    let mut redis = services.redis_client().await;
+    redis
+        .publish::<_, _, ()>(
+            "notify_storage_update",
+            r#"{"storage":10, "path":"files/foo.txt", "file_id":5}"#,
+        )
+        .await
+        .unwrap();
+    assert_eq!(
+        "data:{\"type\":\"notify_file\"}\n\n",
+        next_event(&mut response).await
+    );
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+async fn test_path_subscription() {
+    let services = Services::new().await;
+    services.add_user("foo", "bar");
+
+    let mapping = MockMapping::default();
+    mapping.add_storage_mapping("foo", 10, "");
+
+    let server_handle = services.spawn_server_with_resolver(mapping).await;
+    let mut client = server_handle.connect_auth("foo", "bar").await;
+    client
+        .send(Message::Text("subscribe files/Photos".into()))
+        .await
+        .unwrap();
+    client
+        .send(Message::Text("listen notify_file_id".into()))
+        .await
+        .unwrap();
+    // unknown listen options don't install a path subscription
+    client
+        .send(Message::Text("listen files/Documents".into()))
+        .await
+        .unwrap();
+    sleep(Duration::from_millis(50)).await;
+
+    let mut redis = services.redis_client().await;
+    redis
+        .publish::<_, _, ()>(
+            "notify_storage_update",
+            r#"{"storage":10, "path":"files/Documents/foo.txt", "file_id":5}"#,
+        )
+        .await
+        .unwrap();
+    assert_no_message(&mut client).await;
+
+    redis
+        .publish::<_, _, ()>(
+            "notify_storage_update",
+            r#"{"storage":10, "path":"files/Photos/foo.jpg", "file_id":6}"#,
+        )
+        .await
+        .unwrap();
+    assert_next_message(&mut client, "notify_file_id [6]").await;
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+async fn test_path_subscription_shared_mount() {
+    let services = Services::new().await;
+    services.add_user("foo", "bar");
+    services.add_user("foo2", "bar");
+
+    // foo2 received a share of foo's "files/Photos/2020" folder, mounted at "files/Shared/Holiday"
+    let mapping = MockMapping::default();
+    mapping.add_storage_mount("foo", 10, "", "/foo/");
+    mapping.add_storage_mount(
+        "foo2",
+        10,
+        "files/Photos/2020",
+        "/foo2/files/Shared/Holiday/",
+    );
+
+    let server_handle = services.spawn_server_with_resolver(mapping).await;
+    let mut owner = server_handle.connect_auth("foo", "bar").await;
+    let mut recipient = server_handle.connect_auth("foo2", "bar").await;
+    for (client, path) in [
+        (&mut owner, "files/Photos"),
+        (&mut recipient, "files/Shared/Holiday"),
+    ] {
+        client
+            .send(Message::Text(format!("subscribe {}", path)))
+            .await
+            .unwrap();
+        client
+            .send(Message::Text("listen notify_file_id".into()))
+            .await
+            .unwrap();
+    }
+    sleep(Duration::from_millis(50)).await;
+
+    let mut redis = services.redis_client().await;
+    redis
+        .publish::<_, _, ()>(
+            "notify_storage_update",
+            r#"{"storage":10, "path":"files/Photos/2020/beach.jpg", "file_id":7}"#,
+        )
+        .await
+        .unwrap();
+    assert_next_message(&mut owner, "notify_file_id [7]").await;
+    assert_next_message(&mut recipient, "notify_file_id [7]").await;
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+async fn test_heartbeat() {
+    let services = Services::new().await;
+    services.add_user("foo", "bar");
+
+    let mut config = services.config();
+    config.heartbeat_interval = 1;
+    let app = App::with_connection(services.db.clone(), config, LOG_HANDLE.clone(), false)
+        .await
+        .unwrap();
+    let addr = listen_available_port().await.unwrap().local_addr().unwrap();
+    let server_handle = spawn_app_at(app, Bind::Tcp(addr), None).await;
+    let disabled_handle = services.spawn_server().await;
+
+    let mut client = server_handle.connect_auth("foo", "bar").await;
+    let mut disabled = disabled_handle.connect_auth("foo", "bar").await;
+
+    let start = Instant::now();
+    let mut heartbeats = Vec::new();
+    while let Ok(Some(Ok(msg))) = timeout(
+        Duration::from_millis(3200).saturating_sub(start.elapsed()),
+        client.next(),
+    )
+    .await
+    {
+        match msg {
+            Message::Ping(_) => {}
+            Message::Text(text) if text == "heartbeat" => heartbeats.push(start.elapsed()),
+            msg => panic!("unexpected message {:?}", msg),
+        }
+        if start.elapsed() >= Duration::from_millis(3200) {
+            break;
+        }
+    }
+    assert!(
+        (2..=3).contains(&heartbeats.len()),
+        "got heartbeats at {:?}",
+        heartbeats
+    );
+    for pair in heartbeats.windows(2) {
+        assert!(pair[1] - pair[0] >= Duration::from_secs(1));
+    }
+
+    assert!(matches!(disabled.next().await, Some(Ok(Message::Ping(_)))));
+    assert_no_message(&mut disabled).await;
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+async fn test_replay_since() {
+    let services = Services::new().await;
+    services.add_user("foo", "bar");
+
+    let mut config = services.config();
+    config.replay_buffer_size = 16;
+    let app = App::with_connection(services.db.clone(), config, LOG_HANDLE.clone(), false)
+        .await
+        .unwrap();
+    let addr = listen_available_port().await.unwrap().local_addr().unwrap();
+    let server_handle = spawn_app_at(app, Bind::Tcp(addr), None).await;
+
+    let mut client = server_handle.connect_auth("foo", "bar").await;
+    assert_next_message(&mut client, "seq 0").await;
+    client.close(None).await.ok();
+    drop(client);
+
+    let mut redis = services.redis_client().await;
+    redis
+        .publish::<_, _, ()>("notify_activity", r#"{"user":"foo"}"#)
+        .await
+        .unwrap();
+    sleep(Duration::from_millis(100)).await;
+
+    let mut client = server_handle.connect_auth("foo", "bar").await;
+    assert_next_message(&mut client, "seq 1").await;
+    client.send(Message::Text("since:0".into())).await.unwrap();
+    assert_next_message(&mut client, "notify_activity").await;
+    assert_next_message(&mut client, "seq 1").await;
+
+    // nothing is replayed once caught up
+    client.send(Message::Text("since:1".into())).await.unwrap();
+    assert_next_message(&mut client, "seq 1").await;
+}
+
+async fn spawn_throttled_server(services: &Services, policy: ThrottlePolicy) -> ServerHandle {
+    let mut config = services.config();
+    config.max_events_per_second = 2;
+    config.event_throttle = policy;
+    let app = App::with_connection(services.db.clone(), config, LOG_HANDLE.clone(), false)
+        .await
+        .unwrap();
+    let addr = listen_available_port().await.unwrap().local_addr().unwrap();
+    spawn_app_at(app, Bind::Tcp(addr), None).await
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+async fn test_event_throttle_delay() {
+    let services = Services::new().await;
+    services.add_user("foo", "bar");
+    let server_handle = spawn_throttled_server(&services, ThrottlePolicy::Delay).await;
+    let mut client = server_handle.connect_auth("foo", "bar").await;
+
+    let throttled = METRICS.throttled_events();
+    let mut redis = services.redis_client().await;
+    let start = Instant::now();
+    for i in 0..3 {
+        redis
+            .publish::<_, _, ()>(
+                "notify_custom",
+                format!(r#"{{"user":"foo", "message":"message_{}"}}"#, i),
+            )
+            .await
+            .unwrap();
+    }
+
+    for i in 0..3 {
+        assert_eq!(
+            Message::Text(format!("message_{}", i)),
+            timeout(Duration::from_secs(2), client.next())
+                .await
+                .unwrap()
+                .unwrap()
+                .unwrap()
+        );
+    }
+    // the third event has to wait for the bucket to refill
+    assert!(start.elapsed() >= Duration::from_millis(400));
+    assert!(METRICS.throttled_events() > throttled);
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+async fn test_event_throttle_drop() {
+    let services = Services::new().await;
+    services.add_user("foo", "bar");
+    let server_handle = spawn_throttled_server(&services, ThrottlePolicy::Drop).await;
+    let mut client = server_handle.connect_auth("foo", "bar").await;
+
+    let throttled = METRICS.throttled_events();
+    let mut redis = services.redis_client().await;
+    for i in 0..5 {
+        redis
+            .publish::<_, _, ()>(
+                "notify_custom",
+                format!(r#"{{"user":"foo", "message":"message_{}"}}"#, i),
+            )
+            .await
+            .unwrap();
+    }
+
+    assert_next_message(&mut client, "message_0").await;
+    assert_next_message(&mut client, "message_1").await;
+    assert_no_message(&mut client).await;
+    assert!(METRICS.throttled_events() >= throttled + 3);
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+async fn test_normalize_user_ids() {
+    let services = Services::new().await;
+    services.add_user("alice", "bar");
+
+    let mut config = services.config();
+    config.normalize_user_ids = true;
+    let app = App::with_connection(services.db.clone(), config, LOG_HANDLE.clone(), false)
+        .await
+        .unwrap();
+    let addr = listen_available_port().await.unwrap().local_addr().unwrap();
+    let server_handle = spawn_app_at(app, Bind::Tcp(addr), None).await;
+
+    let mut client = server_handle.connect_auth("alice", "bar").await;
+
+    let mut redis = services.redis_client().await;
+    redis
+        .publish::<_, _, ()>("notify_activity", r#"{"user":"Alice"}"#)
+        .await
+        .unwrap();
+
+    assert_next_message(&mut client, "notify_activity").await;
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+async fn test_root_and_not_found() {
+    let services = Services::new().await;
+    let server_handle = services.spawn_server().await;
+
+    for path in ["", "push/"] {
+        let response = reqwest::get(format!("http://127.0.0.1:{}/{}", server_handle.port, path))
+            .await
+            .unwrap();
+        assert_eq!(StatusCode::OK, response.status());
+        let info: serde_json::Value = response.json().await.unwrap();
+        assert_eq!("notify_push", info["name"]);
+        assert_eq!(env!("NOTIFY_PUSH_VERSION"), info["version"]);
+        assert_eq!(2, info.as_object().unwrap().len());
+    }
+
+    let response = reqwest::get(format!("http://127.0.0.1:{}/unknown", server_handle.port))
+        .await
+        .unwrap();
+    assert_eq!(StatusCode::NOT_FOUND, response.status());
+    let error: serde_json::Value = response.json().await.unwrap();
+    assert_eq!("not_found", error["error"]);
+    assert!(error["message"].is_string());
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+async fn test_error_framing() {
+    let services = Services::new().await;
+    services.add_user("foo", "bar");
+
+    let server_handle = services.spawn_server().await;
+    let (mut client, _) = server_handle
+        .connect_with_protocol("notify_push.v2")
+        .await
+        .unwrap();
+    client.send(Message::Text("foo".into())).await.unwrap();
+    client.send(Message::Text("wrong".into())).await.unwrap();
+    let message = timeout(Duration::from_millis(300), client.next())
+        .await
+        .unwrap()
+        .unwrap()
+        .unwrap();
+    let message: serde_json::Value = serde_json::from_str(message.to_text().unwrap()).unwrap();
+    assert_eq!("error", message["type"]);
+    assert_eq!("invalid_credentials", message["reason"]);
+    assert_eq!("Invalid credentials", message["message"]);
+
+    let (mut client, _) = server_handle
+        .connect_with_protocol("notify_push.v2")
+        .await
+        .unwrap();
+    client.send(Message::Text("foo".into())).await.unwrap();
+    client.send(Message::Text("bar".into())).await.unwrap();
+    assert_authenticated_v2(&mut client, "foo").await;
+    let mut v1_client = server_handle.connect_auth("foo", "bar").await;
+
+    // custom messages can't pose as errors for v2 clients, v1 clients still receive them
+    let mut redis = services.redis_client().await;
+    for message in ["error", "err: fake", "calendar_update"] {
+        redis
+            .publish::<_, _, ()>(
+                "notify_custom",
+                serde_json::json!({"user": "foo", "message": message}).to_string(),
+            )
+            .await
+            .unwrap();
+    }
+    assert_next_message(&mut client, r#"{"type":"calendar_update"}"#).await;
+    assert_no_message(&mut client).await;
+    assert_next_message(&mut v1_client, "error").await;
+    assert_next_message(&mut v1_client, "err: fake").await;
+    assert_next_message(&mut v1_client, "calendar_update").await;
+}